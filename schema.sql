@@ -1,54 +1,147 @@
-create table media(
+create table if not exists media(
     chat_id sqlite3_int64,
     msg_id sqlite3_int32,
     file_type varchar(9) not null,
-    unique_id varchar(16) not null,
+    unique_id varchar(250) not null,
     file_id varchar(90) null,
-    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+    timestamp sqlite3_int64 not null,
     PRIMARY KEY (chat_id, unique_id)
 );
 
-create table urls(
-    chat_id sqlite3_int64,
-    msg_id sqlite3_int32,
-    unique_id varchar(250) not null,
-    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-    PRIMARY KEY (chat_id, unique_id)
-);
+create index if not exists media_unique_id on media(unique_id);
 
-create table users(
+create table if not exists users(
     user_id sqlite3_int64,
     chat_id sqlite3_int64,
     user_name varchar(250),
     chat_name varchar(250),
-    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+    timestamp sqlite3_int64 not null,
     PRIMARY KEY (user_id, chat_id)
 );
 
-create table duplicates(
+create table if not exists duplicates(
     chat_id sqlite3_int64,
-    unique_id varchar(16) not null,
+    msg_id sqlite3_int32,
     file_type varchar(9) not null,
+    unique_id varchar(250) not null,
     file_id varchar(90) null,
-    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-    msg_id sqlite3_int32,
+    timestamp sqlite3_int64 not null,
     PRIMARY KEY (chat_id, unique_id)
 );
 
-create table mappings(
+create table if not exists mappings(
     api_id sqlite3_int64,
     chat_id sqlite3_int64,
-    unique_id varchar(16) not null,
-    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+    unique_id varchar(250) not null,
+    timestamp sqlite3_int64 not null,
     PRIMARY KEY (api_id, chat_id)
 );
 
-select * from media where timestamp <= date('now', '-4 day');
-select * from urls where timestamp <= date('now', '-4 day');
+create table if not exists chat_groups(
+    supergroup_id sqlite3_int64,
+    chat_id sqlite3_int64,
+    member_offset sqlite3_int64,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (supergroup_id)
+);
 
-SELECT * FROM media WHERE chat_id = -1001592783264 GROUP BY msg_id ORDER BY timestamp DESC limit 5;
-SELECT * FROM urls WHERE chat_id = -1001592783264 ORDER BY timestamp DESC limit 5;
+create table if not exists phashes(
+    chat_id sqlite3_int64,
+    msg_id sqlite3_int32,
+    hash sqlite3_int64 not null,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (chat_id, msg_id)
+);
 
-SELECT * FROM users WHERE chat_id = -1001592783264 AND timestamp <= date('now', '-4 day')
+create table if not exists fingerprints(
+    chat_id sqlite3_int64,
+    msg_id sqlite3_int32,
+    hash sqlite3_int64 not null,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (chat_id, msg_id)
+);
+
+create table if not exists configs(
+    chat_id sqlite3_int64,
+    config text not null,
+    PRIMARY KEY (chat_id)
+);
+
+create table if not exists strikes(
+    chat_id sqlite3_int64,
+    user_id sqlite3_int64,
+    count sqlite3_int32 not null,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+create table if not exists offenders(
+    chat_id sqlite3_int64,
+    user_id sqlite3_int64,
+    user_name varchar(250),
+    count sqlite3_int32 not null,
+    last_chat_id sqlite3_int64 not null,
+    last_msg_id sqlite3_int32 not null,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+-- Users never flagged nor banned in a chat
+create table if not exists allowlist(
+    chat_id sqlite3_int64,
+    user_id sqlite3_int64,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+create table if not exists ban_plans(
+    token varchar(16),
+    plan text not null,
+    timestamp sqlite3_int64 not null,
+    PRIMARY KEY (token)
+);
+
+create table if not exists bans(
+    user_id sqlite3_int64,
+    chat_id sqlite3_int64,
+    user_name varchar(250),
+    reason varchar(250) not null,
+    admin_id sqlite3_int64,
+    error text null,
+    timestamp sqlite3_int64 not null
+);
+
+create index if not exists bans_chat_id on bans(chat_id, timestamp);
+
+-- Append only, rows are never updated nor deleted
+create table if not exists audit_log(
+    chat_id sqlite3_int64,
+    user_id sqlite3_int64,
+    entry text not null,
+    timestamp sqlite3_int64 not null
+);
+
+create index if not exists audit_log_chat_id on audit_log(chat_id, timestamp);
+
+-- Media groups, the message ids are stored as a json array
+create table if not exists albums(
+    chat_id sqlite3_int64,
+    media_group_id varchar(64) not null,
+    album text not null,
+    timestamp sqlite3_int64 not null,
+    primary key (chat_id, media_group_id)
+);
+
+-- Bot messages waiting to be deleted, kept so a restart does not forget them
+create table if not exists deletions(
+    chat_id sqlite3_int64,
+    msg_id integer,
+    due sqlite3_int64 not null,
+    primary key (chat_id, msg_id)
+);
 
-ALTER TABLE duplicates ADD msg_id sqlite3_int32;
+-- Urls are stored on media with file_type 'url', same as the rocksdb backend
+-- select * from media where timestamp <= strftime('%s', 'now', '-4 day');
+-- SELECT * FROM media WHERE chat_id = -1001592783264 GROUP BY msg_id ORDER BY timestamp DESC limit 5;
+-- SELECT * FROM media WHERE chat_id = -1001592783264 AND file_type = 'url' ORDER BY timestamp DESC limit 5;
+-- SELECT * FROM users WHERE chat_id = -1001592783264 AND timestamp <= strftime('%s', 'now', '-4 day');
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::offset::Utc;
+use teloxide::prelude::*;
+use teloxide::types::User;
+
+use crate::duplicates::{check_message, settle};
+use crate::i18n;
+use crate::models::{Album, AlbumMode, ChatConfig, Status};
+use crate::repository::Repo;
+
+/// Telegram delivers the messages of an album one by one, they are collected this long
+pub const ALBUM_WAIT_MS: u64 = 1500;
+
+/// Messages of albums still being received
+#[derive(Default)]
+pub struct AlbumBuffer {
+    pending: Mutex<HashMap<(i64, String), Vec<Message>>>,
+}
+
+impl AlbumBuffer {
+    /// Adds a message of an album, true when it is the first one and its handler
+    /// must check the album once the rest arrived
+    pub fn push(&self, message: Message, media_group_id: &str) -> bool {
+        let mut pending = ok!(self.pending.lock());
+        let messages = pending
+            .entry((message.chat.id, media_group_id.to_string()))
+            .or_insert_with(Vec::new);
+        messages.push(message);
+        messages.len() == 1
+    }
+
+    /// Messages received for the album, in order
+    pub fn take(&self, chat_id: i64, media_group_id: &str) -> Vec<Message> {
+        let mut pending = ok!(self.pending.lock());
+        let mut messages = pending
+            .remove(&(chat_id, media_group_id.to_string()))
+            .unwrap_or_default();
+        messages.sort_by_key(|message| message.id);
+        messages
+    }
+}
+
+/// Outcome of checking every message of an album
+#[derive(Debug, Clone)]
+pub struct AlbumStatus {
+    /// A single notice for the whole album
+    pub status: Status,
+    pub msg_ids: Vec<i32>,
+    /// Messages repeating media already shared
+    pub duplicates: Vec<i32>,
+}
+
+impl AlbumStatus {
+    pub fn is_partial(&self) -> bool {
+        !self.duplicates.is_empty() && self.duplicates.len() < self.msg_ids.len()
+    }
+
+    /// Messages a deleting sanction removes
+    pub fn to_delete(&self, config: &ChatConfig) -> Vec<i32> {
+        match config.album_mode {
+            AlbumMode::Whole if !self.duplicates.is_empty() => self.msg_ids.clone(),
+            _ => self.duplicates.clone(),
+        }
+    }
+}
+
+/// Checks every message of an album by unique id, storing the new media
+pub fn check_album(db: Repo, messages: &[Message], user: &User) -> Vec<(i32, Status)> {
+    messages
+        .iter()
+        .map(|message| (message.id, check_message(db.clone(), message, user)))
+        .collect()
+}
+
+/// Settles the checked messages of an album as one post: it is counted once against the user
+/// and gets one notice. `near` are the items flagged by their photo hash. Parts arriving after
+/// the album was settled are only added to it
+pub fn settle_album(
+    db: Repo,
+    chat_id: i64,
+    media_group_id: &str,
+    statuses: Vec<(i32, Status)>,
+    near: &[i32],
+    user: &User,
+    is_admin: bool,
+) -> Option<AlbumStatus> {
+    let first = statuses.first()?;
+    let msg_ids = statuses.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let duplicates = statuses
+        .iter()
+        .filter(|(_, status)| status.action)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+
+    let settled = db.get_album(chat_id, media_group_id);
+    let mut album_ids = settled.as_ref().map_or_else(Vec::new, |album| album.msg_ids.clone());
+    album_ids.extend(&msg_ids);
+    album_ids.sort_unstable();
+    album_ids.dedup();
+    db.insert_album(Album {
+        chat_id,
+        media_group_id: media_group_id.into(),
+        msg_ids: album_ids,
+        timestamp: Utc::now().timestamp(),
+    });
+    if settled.is_some() {
+        log::info!("settle_album: late parts {:?} of {} already settled", msg_ids, media_group_id);
+        return None;
+    }
+
+    let status = match statuses.iter().find(|(_, status)| status.action) {
+        None => first.1.clone(),
+        Some((id, status)) => {
+            let mut status = status.clone();
+            if duplicates.len() < msg_ids.len() {
+                let locale = db.get_config(chat_id).locale;
+                let partial = t!(locale, "album.partial", duplicates = duplicates.len(), total = msg_ids.len());
+                status.text = format!("{}{}", partial, status.text);
+            }
+            let rule = if near.contains(id) { "phash" } else { "unique_id" };
+            settle(db, chat_id, user, is_admin, *id, status, rule)
+        }
+    };
+    Some(AlbumStatus {
+        status,
+        msg_ids,
+        duplicates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_album, settle_album, AlbumBuffer, AlbumStatus};
+    use crate::phash::check_hash;
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::{AlbumMode, ChatConfig, Locale};
+    use crate::repository::{conformance, Repo};
+    use serde_json::json;
+    use std::sync::Arc;
+    use teloxide::types::{Message, User};
+
+    const CHAT_ID: i64 = -1001592783264;
+    const USER_ID: i64 = 1072037897;
+    const GROUP: &str = "13054627816521573";
+
+    fn photo(msg_id: i32, unique_id: &str, media_group_id: Option<&str>) -> Message {
+        let mut msg = json!({
+            "message_id": msg_id,
+            "date": 1633072800,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Highlander" },
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Connor" },
+            "photo": [{
+                "file_id": format!("file_{}", unique_id),
+                "file_unique_id": unique_id,
+                "width": 90,
+                "height": 90
+            }]
+        });
+        if let Some(id) = media_group_id {
+            msg["media_group_id"] = json!(id);
+        }
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn detect_album(db: Repo, group: &str, messages: &[Message], user: &User) -> Option<AlbumStatus> {
+        let statuses = check_album(db.clone(), messages, user);
+        settle_album(db, CHAT_ID, group, statuses, &[], user, false)
+    }
+
+    fn english(mode: AlbumMode) -> Repo {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.locale = Locale::En;
+        config.album_mode = mode;
+        db.insert_config(config);
+        db
+    }
+
+    #[test]
+    fn buffers_albums() {
+        let buffer = AlbumBuffer::default();
+        assert!(buffer.push(photo(11, "b", Some(GROUP)), GROUP));
+        assert!(!buffer.push(photo(10, "a", Some(GROUP)), GROUP));
+        assert!(buffer.push(photo(20, "c", Some("other")), "other"));
+        let ids = buffer.take(CHAT_ID, GROUP).iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![10, 11]);
+        assert!(buffer.take(CHAT_ID, GROUP).is_empty());
+        assert!(buffer.push(photo(12, "d", Some(GROUP)), GROUP));
+    }
+
+    #[test]
+    fn whole_duplicate_album() {
+        let db = english(AlbumMode::Items);
+        let user = conformance::user(USER_ID);
+        let first = vec![photo(10, "a", Some(GROUP)), photo(11, "b", Some(GROUP))];
+        let album = detect_album(db.clone(), GROUP, &first, &user).unwrap();
+        assert!(!album.status.action);
+        assert_eq!(db.find_album(CHAT_ID, 11).unwrap().msg_ids, vec![10, 11]);
+
+        let again = vec![photo(20, "a", Some("again")), photo(21, "b", Some("again"))];
+        let album = detect_album(db.clone(), "again", &again, &user).unwrap();
+        assert!(album.status.action);
+        assert!(!album.is_partial());
+        assert!(album.status.text.starts_with("Duplicate message"));
+        assert_eq!(album.to_delete(&db.get_config(CHAT_ID)), vec![20, 21]);
+        // one album, one duplicate
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().count, 1);
+    }
+
+    #[test]
+    fn partial_album() {
+        let db = english(AlbumMode::Items);
+        let user = conformance::user(USER_ID);
+        detect_album(db.clone(), GROUP, &[photo(10, "a", Some(GROUP)), photo(11, "b", Some(GROUP))], &user);
+
+        let mixed = vec![photo(20, "c", Some("mixed")), photo(21, "a", Some("mixed")), photo(22, "d", Some("mixed"))];
+        let album = detect_album(db.clone(), "mixed", &mixed, &user).unwrap();
+        assert!(album.status.action);
+        assert!(album.is_partial());
+        assert!(album.status.text.starts_with("1 of 3 album items were already shared"));
+        assert_eq!(album.to_delete(&db.get_config(CHAT_ID)), vec![21]);
+
+        let mut config = db.get_config(CHAT_ID);
+        config.album_mode = AlbumMode::Whole;
+        assert_eq!(album.to_delete(&config), vec![20, 21, 22]);
+    }
+
+    #[test]
+    fn late_album_part() {
+        let db = english(AlbumMode::Items);
+        let user = conformance::user(USER_ID);
+        detect_album(db.clone(), GROUP, &[photo(10, "a", Some(GROUP))], &user);
+
+        let again = vec![photo(20, "a", Some("again")), photo(21, "b", Some("again"))];
+        assert!(detect_album(db.clone(), "again", &again, &user).unwrap().status.action);
+        assert!(detect_album(db.clone(), "again", &[photo(22, "a", Some("again"))], &user).is_none());
+        assert_eq!(db.find_album(CHAT_ID, 20).unwrap().msg_ids, vec![20, 21, 22]);
+        assert_eq!(db.find_album(CHAT_ID, 22).unwrap().media_group_id, "again");
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().count, 1);
+    }
+
+    #[test]
+    fn near_duplicate_album_item() {
+        let db = english(AlbumMode::Items);
+        let mut config = db.get_config(CHAT_ID);
+        config.phash_distance = 4;
+        db.insert_config(config);
+        let user = conformance::user(USER_ID);
+        let first = photo(10, "a", Some(GROUP));
+        detect_album(db.clone(), GROUP, &[first.clone()], &user);
+        assert!(check_hash(db.clone(), &first, &first.photo().unwrap()[0], 0xf0f0).is_none());
+
+        let near = vec![photo(20, "c", Some("near")), photo(21, "d", Some("near"))];
+        let mut statuses = check_album(db.clone(), &near, &user);
+        assert!(check_hash(db.clone(), &near[0], &near[0].photo().unwrap()[0], 0x0f0f).is_none());
+        statuses[1].1 = check_hash(db.clone(), &near[1], &near[1].photo().unwrap()[0], 0xf0f1).unwrap();
+        assert!(db.message_media(CHAT_ID, 21, false).is_empty());
+
+        let album = settle_album(db.clone(), CHAT_ID, "near", statuses, &[21], &user, false).unwrap();
+        assert!(album.status.action);
+        assert!(album.is_partial());
+        assert_eq!(album.duplicates, vec![21]);
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().last_msg_id, 10);
+        assert_eq!(db.list_audit(CHAT_ID, None, 0)[0].rule, "phash");
+    }
+}
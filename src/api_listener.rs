@@ -1,26 +1,31 @@
 use rtdlib::types::{
-    Chat, ChatMembers, ChatType, MessageContent, MessageSender, TextEntityType,
+    Chat, ChatMembers, ChatType, FormattedText, MessageContent, MessageSender,
     UpdateDeleteMessages, UpdateNewMessage,
 };
 use rtdlib::Tdlib;
 
 use chrono::offset::Utc;
-use lazy_static::lazy_static;
-use regex::Regex;
 
 use std::collections::VecDeque;
 use std::sync::Arc;
 
 use tokio::time::{sleep, Duration};
 
-use super::duplicates::extract_last250;
-use super::models::{Group, User};
-use super::repository::Repository;
-use super::rocksdb::RocksDBRepo;
+use super::kinds;
+use super::models::{Group, Payload, User};
+use super::repository::Repo;
+use super::urls::{td_entity_urls, url_id};
 
 const LIMIT: i64 = 200;
 
-pub async fn tgram_listener(tdlib: Arc<Tdlib>, db: RocksDBRepo) -> () {
+/// Maps the urls of a text or caption to the message, with the same unique ids the bot stores
+fn insert_url_mappings(db: &Repo, id: i64, chat_id: i64, text: &FormattedText) {
+    for url in td_entity_urls(text.text(), text.entities()) {
+        db.insert_mapping(id, chat_id, &url_id(&url));
+    }
+}
+
+pub async fn tgram_listener(tdlib: Arc<Tdlib>, db: Repo) -> () {
     let mut channel: VecDeque<Group> = VecDeque::new();
     loop {
         match tdlib.receive(5.0) {
@@ -42,42 +47,38 @@ pub async fn tgram_listener(tdlib: Arc<Tdlib>, db: RocksDBRepo) -> () {
 
                             match message.content() {
                                 MessageContent::MessageText(message_text) => {
-                                    lazy_static! {
-                                        static ref RE: Regex = ok!(Regex::new("(http|ftp|https)://([\\w_-]+(?:(?:\\.[\\w_-]+)+))([\\w.,@?^=%&:/~+#-]*[\\w@?^=%&/~+#-])?"));
-                                    }
-
-                                    let txt = message_text.text().text();
-                                    message_text.text().entities().iter().for_each(|entity| {
-                                        log::info!("Entity: {:?}", entity);
-                                        match entity.type_() {
-                                            TextEntityType::Url(_) => {
-                                                RE.captures_iter(txt).for_each(|cap| {
-                                                    let url = cap.get(0).unwrap().as_str();
-                                                    let unique_id = extract_last250(url);
-                                                    db.insert_mapping(id, chat_id, unique_id);
-                                                });
-                                            }
-                                            _ => (),
-                                        }
-                                    })
+                                    insert_url_mappings(&db, id, chat_id, message_text.text());
+                                }
+                                MessageContent::MessageAnimation(message_animation) => {
+                                    insert_url_mappings(&db, id, chat_id, message_animation.caption());
+                                    let unique_id = message_animation
+                                        .animation()
+                                        .animation()
+                                        .remote()
+                                        .unique_id();
+                                    db.insert_mapping(id, chat_id, unique_id);
                                 }
                                 MessageContent::MessageAudio(message_audio) => {
+                                    insert_url_mappings(&db, id, chat_id, message_audio.caption());
                                     let unique_id =
                                         message_audio.audio().audio().remote().unique_id();
                                     db.insert_mapping(id, chat_id, unique_id);
                                 }
                                 MessageContent::MessageDocument(message_document) => {
+                                    insert_url_mappings(&db, id, chat_id, message_document.caption());
                                     let unique_id =
                                         message_document.document().document().remote().unique_id();
                                     db.insert_mapping(id, chat_id, unique_id);
                                 }
                                 MessageContent::MessagePhoto(message_photo) => {
+                                    insert_url_mappings(&db, id, chat_id, message_photo.caption());
                                     message_photo.photo().sizes().iter().for_each(|size| {
                                         let unique_id = size.photo().remote().unique_id().as_str();
                                         db.insert_mapping(id, chat_id, unique_id);
                                     })
                                 }
                                 MessageContent::MessageVideo(message_video) => {
+                                    insert_url_mappings(&db, id, chat_id, message_video.caption());
                                     let unique_id =
                                         message_video.video().video().remote().unique_id();
                                     db.insert_mapping(id, chat_id, unique_id);
@@ -90,7 +91,38 @@ pub async fn tgram_listener(tdlib: Arc<Tdlib>, db: RocksDBRepo) -> () {
                                         .unique_id();
                                     db.insert_mapping(id, chat_id, unique_id);
                                 }
+                                MessageContent::MessageSticker(message_sticker) => {
+                                    let unique_id =
+                                        message_sticker.sticker().sticker().remote().unique_id();
+                                    db.insert_mapping(id, chat_id, unique_id);
+                                }
+                                MessageContent::MessageContact(message_contact) => {
+                                    let contact = message_contact.contact();
+                                    let payload = Payload::Contact {
+                                        phone_number: contact.phone_number().clone(),
+                                        first_name: contact.first_name().clone(),
+                                        last_name: Some(contact.last_name().clone()),
+                                    };
+                                    db.insert_mapping(id, chat_id, &kinds::unique_id(&payload));
+                                }
+                                MessageContent::MessageLocation(message_location) => {
+                                    let location = message_location.location();
+                                    let payload = Payload::Location {
+                                        latitude: location.latitude(),
+                                        longitude: location.longitude(),
+                                    };
+                                    db.insert_mapping(id, chat_id, &kinds::unique_id(&payload));
+                                }
+                                MessageContent::MessagePoll(message_poll) => {
+                                    let poll = message_poll.poll();
+                                    let payload = Payload::Poll {
+                                        question: poll.question().clone(),
+                                        options: poll.options().iter().map(|o| o.text().clone()).collect(),
+                                    };
+                                    db.insert_mapping(id, chat_id, &kinds::unique_id(&payload));
+                                }
                                 MessageContent::MessageVoiceNote(message_voice_note) => {
+                                    insert_url_mappings(&db, id, chat_id, message_voice_note.caption());
                                     let unique_id = message_voice_note
                                         .voice_note()
                                         .voice()
@@ -0,0 +1,159 @@
+use chrono::offset::{TimeZone, Utc};
+
+use crate::i18n;
+use crate::links::describe;
+use crate::models::{AuditAction, AuditEntry, Locale};
+use crate::repository::Repo;
+
+/// Entries shown by a bare /auditlog
+const DEFAULT_ENTRIES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditQuery {
+    Recent(usize),
+    User(i64),
+    Export,
+}
+
+/// Parses the arguments of /auditlog: `[n]`, `user <id>` or `export`
+pub fn parse_query(args: &str) -> Option<AuditQuery> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    match parts.as_slice() {
+        [] => Some(AuditQuery::Recent(DEFAULT_ENTRIES)),
+        ["export"] => Some(AuditQuery::Export),
+        ["user", id] => id.parse().map(AuditQuery::User).ok(),
+        [num] => num.parse().map(AuditQuery::Recent).ok(),
+        _ => None,
+    }
+}
+
+/// Appends an entry to the audit log, actor_id is 0 when a rule triggered the action
+pub fn record(
+    db: Repo,
+    action: AuditAction,
+    chat_id: i64,
+    user_id: i64,
+    msg_id: i32,
+    actor_id: i64,
+    rule: &str,
+) -> bool {
+    let entry = AuditEntry {
+        action,
+        chat_id,
+        user_id,
+        msg_id,
+        actor_id,
+        rule: rule.into(),
+        timestamp: Utc::now().timestamp(),
+    };
+    let stored = db.insert_audit(entry);
+    if !stored {
+        log::error!("record: could not audit {} of {} on {}", action, user_id, chat_id);
+    }
+    stored
+}
+
+pub fn format_entry(locale: Locale, entry: &AuditEntry) -> String {
+    let actor = match entry.actor_id {
+        0 => t!(locale, "audit.rule"),
+        id => t!(locale, "audit.admin", id = id),
+    };
+    let message = match entry.msg_id {
+        0 => String::new(),
+        id => t!(locale, "audit.message", link = describe(locale, entry.chat_id, id)),
+    };
+    t!(
+        locale,
+        "audit.line",
+        date = Utc.timestamp(entry.timestamp, 0),
+        action = entry.action,
+        user_id = entry.user_id,
+        actor = actor,
+        rule = entry.rule,
+        message = message
+    )
+}
+
+/// The chat's whole audit log as JSON lines, oldest first, with the name to attach it as.
+/// None when nothing was recorded
+pub fn export(db: Repo, chat_id: i64) -> Option<(String, Vec<u8>)> {
+    let mut entries = db.list_audit(chat_id, None, 0);
+    if entries.is_empty() {
+        return None;
+    }
+    entries.reverse();
+    let mut data = Vec::new();
+    for entry in &entries {
+        data.extend(ok!(serde_json::to_vec(entry)));
+        data.push(b'\n');
+    }
+    log::info!("export: {} entries of {}", entries.len(), chat_id);
+    Some((format!("audit_{}.jsonl", chat_id), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, format_entry, parse_query, record, AuditQuery, DEFAULT_ENTRIES};
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::{AuditAction, AuditEntry, Locale};
+    use crate::repository::Repo;
+    use std::sync::Arc;
+
+    const CHAT_ID: i64 = -1001592783264;
+
+    #[test]
+    fn queries() {
+        assert_eq!(parse_query(""), Some(AuditQuery::Recent(DEFAULT_ENTRIES)));
+        assert_eq!(parse_query("5"), Some(AuditQuery::Recent(5)));
+        assert_eq!(parse_query("user 1072037897"), Some(AuditQuery::User(1072037897)));
+        assert_eq!(parse_query(" export "), Some(AuditQuery::Export));
+        assert_eq!(parse_query("user"), None);
+        assert_eq!(parse_query("user me"), None);
+        assert_eq!(parse_query("-3"), None);
+    }
+
+    #[test]
+    fn records_and_formats() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        assert!(record(db.clone(), AuditAction::Mute, CHAT_ID, 1072037897, 1234, 0, "strike 2"));
+        let entries = db.list_audit(CHAT_ID, None, 0);
+        assert_eq!(entries.len(), 1);
+        let line = format_entry(Locale::En, &entries[0]);
+        assert!(line.contains("mute UserId: 1072037897, by rule (strike 2)"));
+        assert!(line.ends_with("message: https://t.me/c/1592783264/1234"));
+
+        let entry = AuditEntry {
+            action: AuditAction::Ban,
+            chat_id: CHAT_ID,
+            user_id: 208056682,
+            msg_id: 0,
+            actor_id: 1072037897,
+            rule: String::from("inactive for 30 days"),
+            timestamp: 1633072800,
+        };
+        assert_eq!(
+            format_entry(Locale::En, &entry),
+            "2021-10-01 07:20:00 UTC ban UserId: 208056682, by admin 1072037897 (inactive for 30 days)"
+        );
+        assert!(format_entry(Locale::Es, &entry).contains("por admin 1072037897"));
+    }
+
+    #[test]
+    fn exports_json_lines() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        assert!(export(db.clone(), CHAT_ID).is_none());
+        record(db.clone(), AuditAction::Duplicate, CHAT_ID, 42, 10, 0, "unique_id");
+        record(db.clone(), AuditAction::Delete, CHAT_ID, 42, 10, 0, "duplicate, strike 1");
+        record(db.clone(), AuditAction::Delete, -4242, 42, 10, 0, "duplicate, strike 1");
+
+        let (name, data) = export(db.clone(), CHAT_ID).unwrap();
+        assert_eq!(name, "audit_-1001592783264.jsonl");
+        let lines = String::from_utf8(data).unwrap();
+        let entries = lines
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::Duplicate);
+    }
+}
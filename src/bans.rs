@@ -0,0 +1,298 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use chrono::offset::{TimeZone, Utc};
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use teloxide::RequestError;
+use tokio::time::sleep;
+
+use crate::audit;
+use crate::i18n;
+use crate::models::User as DBUser;
+use crate::models::{AuditAction, Ban, BanPlan, Locale, BAN_PLAN_TTL_SECS};
+use crate::repository::Repo;
+
+const CALLBACK_PREFIX: &str = "ban";
+
+/// Bans sent back to back before the bucket starts throttling
+const BAN_BURST: f64 = 5.0;
+/// Sustained bans per second, well under Telegram's per bot limits
+const BAN_RATE: f64 = 1.0;
+/// RetryAfter answers honoured for a single user before giving up
+const MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlanAction {
+    Confirm,
+    Cancel,
+}
+
+/// Short token identifying a plan in callback data, which Telegram caps at 64 bytes
+fn new_token(chat_id: i64, admin_id: i64, users: &[DBUser]) -> String {
+    let mut hasher = DefaultHasher::new();
+    (chat_id, admin_id, Utc::now().timestamp_nanos(), users.len()).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+pub fn new_plan(chat_id: i64, admin_id: i64, users: Vec<DBUser>, reason: &str) -> BanPlan {
+    BanPlan {
+        token: new_token(chat_id, admin_id, &users),
+        chat_id,
+        admin_id,
+        users,
+        reason: reason.into(),
+        timestamp: Utc::now().timestamp(),
+    }
+}
+
+pub fn summary(locale: Locale, plan: &BanPlan) -> String {
+    let mut lines = vec![t!(
+        locale,
+        "plan.summary",
+        count = plan.users.len(),
+        reason = plan.reason,
+        minutes = BAN_PLAN_TTL_SECS / 60
+    )];
+    lines.extend(plan.users.iter().enumerate().map(|(i, user)| {
+        t!(
+            locale,
+            "plan.line",
+            n = i + 1,
+            user_id = user.user_id,
+            name = user.user_name,
+            chat_id = user.chat_id,
+            date = Utc.timestamp(user.timestamp, 0)
+        )
+    }));
+    lines.join("\n")
+}
+
+fn callback_data(action: PlanAction, token: &str) -> String {
+    let action = match action {
+        PlanAction::Confirm => "confirm",
+        PlanAction::Cancel => "cancel",
+    };
+    format!("{}:{}:{}", CALLBACK_PREFIX, action, token)
+}
+
+pub fn parse_callback(data: &str) -> Option<(PlanAction, String)> {
+    let parts: Vec<&str> = data.split(':').collect();
+    match parts.as_slice() {
+        [CALLBACK_PREFIX, "confirm", token] => Some((PlanAction::Confirm, token.to_string())),
+        [CALLBACK_PREFIX, "cancel", token] => Some((PlanAction::Cancel, token.to_string())),
+        _ => None,
+    }
+}
+
+pub fn keyboard(locale: Locale, plan: &BanPlan) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback(
+            t!(locale, "plan.confirm"),
+            callback_data(PlanAction::Confirm, &plan.token),
+        ),
+        InlineKeyboardButton::callback(
+            t!(locale, "plan.cancel"),
+            callback_data(PlanAction::Cancel, &plan.token),
+        ),
+    ]])
+}
+
+/// Final report once every ban of the plan has been attempted
+pub fn report(locale: Locale, bans: &[Ban]) -> String {
+    let failed = bans.iter().filter(|ban| ban.error.is_some()).collect::<Vec<_>>();
+    let mut lines = vec![t!(
+        locale,
+        "plan.report",
+        banned = bans.len() - failed.len(),
+        failed = failed.len()
+    )];
+    lines.extend(failed.iter().map(|ban| {
+        t!(
+            locale,
+            "plan.failed",
+            user_id = ban.user_id,
+            chat_id = ban.chat_id,
+            error = ban.error.as_deref().unwrap_or("")
+        )
+    }));
+    lines.join("\n")
+}
+
+/// Spaces out requests: up to `capacity` at once, then `rate` per second
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Takes a token and returns how long to wait before using it
+    pub fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - 1.0;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+async fn ban_with_retry(bot: &AutoSend<Bot>, bucket: &mut TokenBucket, user: &DBUser) -> Result<(), RequestError> {
+    let mut retries = 0;
+    loop {
+        let wait = bucket.take(Instant::now());
+        if wait > Duration::from_secs(0) {
+            sleep(wait).await;
+        }
+        match bot.ban_chat_member(user.chat_id, user.user_id).until_date(0).await {
+            Ok(_) => return Ok(()),
+            Err(RequestError::RetryAfter(secs)) if retries < MAX_RETRIES => {
+                log::info!("ban_with_retry: flood limit, retrying {} in {}s", user.user_id, secs);
+                retries += 1;
+                sleep(Duration::from_secs(secs.max(1) as u64)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn ban_record(user: &DBUser, reason: &str, admin_id: i64, error: Option<String>) -> Ban {
+    Ban {
+        user_id: user.user_id,
+        chat_id: user.chat_id,
+        user_name: user.user_name.clone(),
+        reason: reason.into(),
+        admin_id,
+        error,
+        timestamp: Utc::now().timestamp(),
+    }
+}
+
+/// Bans every user of the plan one at a time, storing each outcome
+pub async fn execute_plan(bot: &AutoSend<Bot>, db: Repo, plan: &BanPlan) -> String {
+    let mut bucket = TokenBucket::new(BAN_BURST, BAN_RATE, Instant::now());
+    let mut bans = Vec::new();
+    for user in &plan.users {
+        let error = match ban_with_retry(bot, &mut bucket, user).await {
+            Ok(_) => {
+                audit::record(
+                    db.clone(),
+                    AuditAction::Ban,
+                    user.chat_id,
+                    user.user_id,
+                    0,
+                    plan.admin_id,
+                    &plan.reason,
+                );
+                None
+            }
+            Err(e) => {
+                log::error!("execute_plan: {} on {}: {}", user.user_id, user.chat_id, e);
+                Some(e.to_string())
+            }
+        };
+        let ban = ban_record(user, &plan.reason, plan.admin_id, error);
+        db.insert_ban(ban.clone());
+        bans.push(ban);
+    }
+    let summary = report(db.get_config(plan.chat_id).locale, &bans);
+    log::info!("execute_plan: {}", summary.lines().next().unwrap_or(""));
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{callback_data, new_plan, parse_callback, report, summary, PlanAction, TokenBucket};
+    use crate::models::User as DBUser;
+    use crate::models::{Ban, Locale, BAN_PLAN_TTL_SECS};
+    use std::time::{Duration, Instant};
+
+    fn user(user_id: i64) -> DBUser {
+        DBUser {
+            user_id,
+            chat_id: -1001592783264,
+            user_name: format!("User {}", user_id),
+            chat_name: String::from("Highlander"),
+            timestamp: 1633072800,
+        }
+    }
+
+    #[test]
+    fn callback_roundtrip() {
+        let plan = new_plan(-1001592783264, 1072037897, vec![user(42)], "inactive for 30 days");
+        assert_eq!(plan.token.len(), 16);
+        let data = callback_data(PlanAction::Confirm, &plan.token);
+        assert!(data.len() <= 64);
+        assert_eq!(parse_callback(&data), Some((PlanAction::Confirm, plan.token.clone())));
+        let data = callback_data(PlanAction::Cancel, &plan.token);
+        assert_eq!(parse_callback(&data), Some((PlanAction::Cancel, plan.token)));
+        assert_eq!(parse_callback("ban:maybe:5f1c2e3a9b7d4c60"), None);
+        assert_eq!(parse_callback("page:2"), None);
+    }
+
+    #[test]
+    fn numbered_summary() {
+        let plan = new_plan(-1001592783264, 1072037897, vec![user(42), user(43)], "inactive for 30 days");
+        let text = summary(Locale::En, &plan);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Ban plan: 2 users inactive for 30 days"));
+        assert!(lines[1].starts_with("1. UserId: 42"));
+        assert!(lines[2].starts_with("2. UserId: 43"));
+        assert!(summary(Locale::Es, &plan).starts_with("Plan de expulsion: 2 usuarios inactive for 30 days"));
+    }
+
+    #[test]
+    fn plans_expire() {
+        let plan = new_plan(-1001592783264, 1072037897, vec![user(42)], "inactive for 30 days");
+        assert!(!plan.is_expired(plan.timestamp + BAN_PLAN_TTL_SECS));
+        assert!(plan.is_expired(plan.timestamp + BAN_PLAN_TTL_SECS + 1));
+    }
+
+    #[test]
+    fn report_lists_errors() {
+        let ban = |user_id: i64, error: Option<&str>| Ban {
+            user_id,
+            chat_id: -1001592783264,
+            user_name: format!("User {}", user_id),
+            reason: String::from("inactive for 30 days"),
+            admin_id: 1072037897,
+            error: error.map(String::from),
+            timestamp: 1633072800,
+        };
+        let bans = vec![ban(42, None), ban(43, Some("Bad Request: user is an administrator"))];
+        assert_eq!(
+            report(Locale::En, &bans),
+            "Banned 1 users, 1 failed\nUserId: 43, GroupId: -1001592783264: Bad Request: user is an administrator"
+        );
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_throttles() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 1.0, start);
+        assert_eq!(bucket.take(start), Duration::from_secs(0));
+        assert_eq!(bucket.take(start), Duration::from_secs(0));
+        assert_eq!(bucket.take(start), Duration::from_secs(1));
+        assert_eq!(bucket.take(start), Duration::from_secs(2));
+        // the debt is paid back over time, and the bucket never refills past its capacity
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(later), Duration::from_secs(0));
+        assert_eq!(bucket.take(later), Duration::from_secs(0));
+        assert_eq!(bucket.take(later), Duration::from_secs(1));
+    }
+}
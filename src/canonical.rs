@@ -0,0 +1,147 @@
+use url::{form_urlencoded, Url};
+
+/// Query params that only track where a link was shared from
+const TRACKING_PARAMS: [&str; 14] = [
+    "fbclid", "gclid", "dclid", "igshid", "mc_cid", "mc_eid", "ref", "ref_src", "ref_url", "si",
+    "feature", "spm", "_ga", "yclid",
+];
+
+/// Leading labels serving the same content as the bare domain
+const MOBILE_PREFIXES: [&str; 3] = ["www.", "m.", "mobile."];
+
+const TWITTER_HOSTS: [&str; 4] = ["twitter.com", "x.com", "fxtwitter.com", "vxtwitter.com"];
+
+fn is_tracking(param: &str) -> bool {
+    param.starts_with("utm_") || TRACKING_PARAMS.contains(&param)
+}
+
+fn strip_mobile(host: &str) -> String {
+    let mut host = host.trim_end_matches('.').to_lowercase();
+    while let Some(prefix) = MOBILE_PREFIXES.iter().find(|p| host.starts_with(*p)) {
+        host = host[prefix.len()..].to_string();
+    }
+    host
+}
+
+/// Rewrites short links and aliases to a single host and path, returns the remaining params
+fn resolve_known(host: &str, path: &str, params: Vec<(String, String)>) -> (String, String, Vec<(String, String)>) {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match host {
+        "youtu.be" if !segments.is_empty() => {
+            let mut params = params;
+            params.push((String::from("v"), segments[0].to_string()));
+            (String::from("youtube.com"), String::from("/watch"), params)
+        }
+        "youtube.com" if segments.len() > 1 && (segments[0] == "shorts" || segments[0] == "embed") => {
+            let mut params = params;
+            params.push((String::from("v"), segments[1].to_string()));
+            (String::from("youtube.com"), String::from("/watch"), params)
+        }
+        "youtube-nocookie.com" if segments.len() > 1 && segments[0] == "embed" => {
+            let mut params = params;
+            params.push((String::from("v"), segments[1].to_string()));
+            (String::from("youtube.com"), String::from("/watch"), params)
+        }
+        h if TWITTER_HOSTS.contains(&h) => {
+            // share params (s, t) are the only thing twitter puts on the query
+            (String::from("twitter.com"), path.to_string(), Vec::new())
+        }
+        _ => (host.to_string(), path.to_string(), params),
+    }
+}
+
+/// Normalises a url so the same resource always produces the same unique id.
+/// Unparseable input is returned untouched.
+pub fn canonicalize(raw: &str) -> String {
+    let url = match Url::parse(raw) {
+        Ok(url) => url,
+        Err(e) => {
+            log::info!("canonicalize: {} {}", raw, e);
+            return raw.to_string();
+        }
+    };
+    let host = match url.host_str() {
+        Some(host) => strip_mobile(host),
+        None => return raw.to_string(),
+    };
+    let params = url
+        .query_pairs()
+        .filter(|(k, _)| !is_tracking(k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect::<Vec<_>>();
+
+    let (host, path, mut params) = resolve_known(&host, url.path(), params);
+    params.sort();
+    params.dedup();
+
+    let scheme = match url.scheme() {
+        "http" | "https" => "https",
+        other => other,
+    };
+    let port = match url.port() {
+        Some(port) => format!(":{}", port),
+        None => String::new(),
+    };
+    let path = if path.len() > 1 {
+        path.trim_end_matches('/')
+    } else {
+        ""
+    };
+    let query = if params.is_empty() {
+        String::new()
+    } else {
+        let encoded = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter())
+            .finish();
+        format!("?{}", encoded)
+    };
+
+    format!("{}://{}{}{}{}", scheme, host, port, path, query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonicalize;
+
+    const CASES: [(&str, &str); 18] = [
+        ("https://youtu.be/GCI0NMgVfPk", "https://youtube.com/watch?v=GCI0NMgVfPk"),
+        ("https://www.youtube.com/watch?v=GCI0NMgVfPk&feature=share", "https://youtube.com/watch?v=GCI0NMgVfPk"),
+        ("https://m.youtube.com/watch?v=GCI0NMgVfPk&si=abc123", "https://youtube.com/watch?v=GCI0NMgVfPk"),
+        ("https://youtube.com/shorts/GCI0NMgVfPk", "https://youtube.com/watch?v=GCI0NMgVfPk"),
+        ("https://youtu.be/GCI0NMgVfPk?t=42", "https://youtube.com/watch?t=42&v=GCI0NMgVfPk"),
+        ("https://twitter.com/plaforscience/status/1379526168513277960", "https://twitter.com/plaforscience/status/1379526168513277960"),
+        ("https://x.com/plaforscience/status/1379526168513277960?s=20&t=abc", "https://twitter.com/plaforscience/status/1379526168513277960"),
+        ("https://mobile.twitter.com/plaforscience/status/1379526168513277960", "https://twitter.com/plaforscience/status/1379526168513277960"),
+        ("HTTPS://WWW.Example.COM/Path/", "https://example.com/Path"),
+        ("http://example.com/a?utm_source=tg&utm_medium=social&id=3", "https://example.com/a?id=3"),
+        ("https://example.com/a?b=2&a=1#comments", "https://example.com/a?a=1&b=2"),
+        ("https://m.wikipedia.org/wiki/Highlander_(film)", "https://wikipedia.org/wiki/Highlander_(film)"),
+        ("https://en.m.wikipedia.org/wiki/Highlander_(film)", "https://en.m.wikipedia.org/wiki/Highlander_(film)"),
+        ("https://m.facebook.com/story.php?id=1&fbclid=IwAR0", "https://facebook.com/story.php?id=1"),
+        ("https://example.com:8080/", "https://example.com:8080"),
+        ("https://drive.google.com/file/d/1t3_HeKZDIMEJl5_Y_l7uuIt4IeebCN7e/view?usp=sharing", "https://drive.google.com/file/d/1t3_HeKZDIMEJl5_Y_l7uuIt4IeebCN7e/view?usp=sharing"),
+        ("ftp://files.example.com/pub/", "ftp://files.example.com/pub"),
+        ("https://example.com/search?q=fish%20%26%20chips&page=2", "https://example.com/search?page=2&q=fish+%26+chips"),
+    ];
+
+    #[test]
+    fn canonicalizes_table() {
+        for (raw, expected) in CASES.iter() {
+            assert_eq!(canonicalize(raw), *expected, "canonicalize({})", raw);
+        }
+    }
+
+    #[test]
+    fn same_video_same_id() {
+        let a = canonicalize("https://youtu.be/GCI0NMgVfPk");
+        let b = canonicalize("https://www.youtube.com/watch?v=GCI0NMgVfPk&feature=share");
+        let c = canonicalize("https://www.youtube.com/watch?v=GCI0NMgVfPk&utm_source=telegram");
+        assert_eq!(a, b);
+        assert_eq!(b, c);
+    }
+
+    #[test]
+    fn unparseable_is_untouched() {
+        assert_eq!(canonicalize("not a url"), "not a url");
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use teloxide::prelude::*;
+use tokio::spawn;
+use tokio::time::sleep;
+
+use chrono::offset::Utc;
+
+use crate::models::Deletion;
+use crate::repository::Repo;
+
+/// Bots can only delete messages younger than 48 hours
+pub const MAX_WARNING_TTL_SECS: i64 = 172800;
+
+/// Stores the deletion of `msg_id` in `ttl` seconds, None when the chat keeps its warnings
+pub fn schedule(db: Repo, chat_id: i64, msg_id: i32, ttl: i64, now: i64) -> Option<Deletion> {
+    if ttl <= 0 {
+        return None;
+    }
+    let deletion = Deletion {
+        chat_id,
+        msg_id,
+        due: now + ttl.min(MAX_WARNING_TTL_SECS),
+    };
+    if db.insert_deletion(deletion.clone()) {
+        Some(deletion)
+    } else {
+        log::error!("schedule: could not store deletion of {} on {}", msg_id, chat_id);
+        None
+    }
+}
+
+/// How long to sleep until `due`, overdue deletions run right away
+pub fn wait_for(due: i64, now: i64) -> Duration {
+    Duration::from_secs((due - now).max(0) as u64)
+}
+
+/// Waits until the deletion is due, deletes the message and forgets the deletion.
+/// It is forgotten also when Telegram refuses, e.g. an admin already removed the message
+pub async fn delete_after(bot: AutoSend<Bot>, db: Repo, deletion: Deletion) {
+    sleep(wait_for(deletion.due, Utc::now().timestamp())).await;
+    match bot.delete_message(deletion.chat_id, deletion.msg_id).await {
+        Ok(_) => log::info!("delete_after: deleted {} on {}", deletion.msg_id, deletion.chat_id),
+        Err(e) => log::error!("delete_after: {} on {}: {}", deletion.msg_id, deletion.chat_id, e),
+    }
+    db.delete_deletion(deletion.chat_id, deletion.msg_id);
+}
+
+/// Spawns the deletions left pending by a previous run, returns how many
+pub fn resume(bot: AutoSend<Bot>, db: Repo) -> usize {
+    let deletions = db.list_deletions();
+    for deletion in &deletions {
+        spawn(delete_after(bot.clone(), db.clone(), deletion.clone()));
+    }
+    deletions.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schedule, wait_for, MAX_WARNING_TTL_SECS};
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::Deletion;
+    use crate::repository::Repo;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const CHAT_ID: i64 = -1001592783264;
+
+    #[test]
+    fn schedules_deletions() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let now = 1633072800;
+        assert_eq!(schedule(db.clone(), CHAT_ID, 1234, 0, now), None);
+        assert!(db.list_deletions().is_empty());
+
+        let deletion = schedule(db.clone(), CHAT_ID, 1234, 60, now);
+        assert_eq!(deletion, Some(Deletion { chat_id: CHAT_ID, msg_id: 1234, due: now + 60 }));
+        let deletion = schedule(db.clone(), CHAT_ID, 1235, 3 * 86400, now);
+        assert_eq!(deletion.map(|d| d.due), Some(now + MAX_WARNING_TTL_SECS));
+        assert_eq!(db.list_deletions().len(), 2);
+    }
+
+    #[test]
+    fn waits_until_due() {
+        assert_eq!(wait_for(1633072860, 1633072800), Duration::from_secs(60));
+        assert_eq!(wait_for(1633072800, 1633072860), Duration::from_secs(0));
+    }
+}
@@ -10,9 +10,21 @@ use chrono::offset::{TimeZone, Utc};
 
 use std::sync::Arc;
 
-use super::models::HResponse;
-use super::repository::Repository;
-use super::rocksdb::RocksDBRepo;
+use super::audit::{export, format_entry, parse_query, AuditQuery};
+use super::cleanup::MAX_WARNING_TTL_SECS;
+use super::i18n;
+use super::links::describe;
+use super::models::{
+    AlbumMode, AuditEntry, ChatConfig, FileType, HResponse, Locale, Media, Outgoing, Payload, Policy,
+    ReplyMode, MAX_MUTE_MINUTES, MAX_WINDOW_DAYS,
+};
+use super::notice::{duplicate_text, sample, validate, TemplateError, MAX_NOTICE_LEN, PLACEHOLDERS};
+use super::repository::Repo;
+
+/// Telegram sends at most this many media in a group
+const MEDIA_GROUP_MAX: usize = 10;
+/// File types Telegram sends in media groups
+const GROUPABLE: [&str; 4] = ["photo", "video", "audio", "document"];
 
 #[derive(BotCommand)]
 #[command(rename = "lowercase", description = "These commands are supported:")]
@@ -45,19 +57,73 @@ pub enum Command {
     ListDuplicates(u8),
     #[command(description = "Get the Ids of all chats managed by highlander")]
     GetChatIds,
+    #[command(description = "set for how many days media remains unique in this chat")]
+    SetWindow(i64),
+    #[command(description = "show for how many days media remains unique in this chat")]
+    GetWindow,
+    #[command(description = "flag photos differing in at most n bits of their perceptual hash, 0 disables it")]
+    SetPhotoDistance(u32),
+    #[command(description = "also flag media already shared on the given chat, needs admin rights there too")]
+    Subscribe(i64),
+    #[command(description = "stop flagging media shared on the given chat")]
+    Unsubscribe(i64),
+    #[command(description = "list the chats this chat is subscribed to")]
+    Network,
+    #[command(description = "what to do on duplicates: warn, delete, mute [minutes] or escalate [mute_after] [ban_after]")]
+    SetPolicy(String),
+    #[command(description = "show what happens on duplicates in this chat")]
+    GetPolicy,
+    #[command(description = "list the n users who posted the most duplicates")]
+    Offenders(u8),
+    #[command(description = "never flag nor ban the given user id in this chat")]
+    AllowUser(i64),
+    #[command(description = "remove the given user id from this chat's allowlist")]
+    DisallowUser(i64),
+    #[command(description = "list the user ids never flagged nor banned in this chat")]
+    ListAllowed,
+    #[command(description = "moderation history: /auditlog [n], /auditlog user <id> or /auditlog export")]
+    AuditLog(String),
+    #[command(description = "language of the replies in this chat: es or en")]
+    SetLanguage(String),
+    #[command(description = "show the language of the replies in this chat")]
+    GetLanguage,
+    #[command(description = "custom duplicate notice with placeholders, empty for the default")]
+    SetNotice(String),
+    #[command(description = "show the duplicate notice on a sample")]
+    PreviewNotice,
+    #[command(description = "delete duplicate warnings after n seconds, 0 keeps them")]
+    SetWarningTtl(i64),
+    #[command(description = "how notices point at the original: link, reply or quote")]
+    SetReplyMode(String),
+    #[command(description = "show how notices point at the original message")]
+    GetReplyMode,
+    #[command(description = "what is deleted from an album with repeated items: items or whole")]
+    SetAlbumMode(String),
+    #[command(description = "check duplicates of the given kind, without one lists the checked kinds")]
+    Track(String),
+    #[command(description = "stop checking duplicates of the given kind")]
+    Untrack(String),
+    #[command(description = "flag texts of at least n characters [similar in p percent], 0 disables it")]
+    SetTextCheck(String),
 }
 
-fn prepare_input_media(ftype: &str, file_id: Option<&str>, unique_id: Option<&str>) -> InputMedia {
+fn prepare_input_media(
+    locale: Locale,
+    ftype: &str,
+    file_id: Option<&str>,
+    unique_id: Option<&str>,
+) -> InputMedia {
+    let caption = t!(locale, "media.caption", id = ok!(unique_id));
     match ftype {
         "photo" => InputMedia::Photo(InputMediaPhoto {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
         }),
         "video" => InputMedia::Video(InputMediaVideo {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
             thumb: None,
@@ -68,7 +134,7 @@ fn prepare_input_media(ftype: &str, file_id: Option<&str>, unique_id: Option<&st
         }),
         "audio" => InputMedia::Audio(InputMediaAudio {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
             thumb: None,
@@ -78,7 +144,7 @@ fn prepare_input_media(ftype: &str, file_id: Option<&str>, unique_id: Option<&st
         }),
         "animation" => InputMedia::Animation(InputMediaAnimation {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
             width: None,
@@ -88,7 +154,7 @@ fn prepare_input_media(ftype: &str, file_id: Option<&str>, unique_id: Option<&st
         }),
         "document" => InputMedia::Document(InputMediaDocument {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
             thumb: None,
@@ -96,13 +162,145 @@ fn prepare_input_media(ftype: &str, file_id: Option<&str>, unique_id: Option<&st
         }),
         _ => InputMedia::Photo(InputMediaPhoto {
             media: InputFile::FileId(ok!(file_id).into()),
-            caption: Some(format!("Part of media {}", ok!(unique_id))),
+            caption: Some(caption),
             caption_entities: None,
             parse_mode: None,
         }),
     }
 }
 
+fn describe_policy(config: &ChatConfig) -> String {
+    let locale = config.locale;
+    match config.policy {
+        Policy::Warn => t!(locale, "policy.warn"),
+        Policy::Delete => t!(locale, "policy.delete"),
+        Policy::Mute => t!(locale, "policy.mute", minutes = config.mute_minutes),
+        Policy::Escalate => t!(
+            locale,
+            "policy.escalate",
+            minutes = config.mute_minutes,
+            mute_after = config.mute_after,
+            ban_after = config.ban_after
+        ),
+    }
+}
+
+/// Applies `policy [args]` to the config, args are minutes for mute and thresholds for escalate
+fn parse_policy(config: &mut ChatConfig, args: &str) -> Result<(), String> {
+    let locale = config.locale;
+    let mut parts = args.split_whitespace();
+    let name = parts.next().unwrap_or("");
+    let policy = name
+        .parse::<Policy>()
+        .map_err(|_| t!(locale, "policy.unknown", policy = name))?;
+    let numbers = parts
+        .map(|n| n.parse::<i64>().map_err(|_| t!(locale, "policy.nan", value = n)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.iter().any(|n| *n < 1) {
+        return Err(t!(locale, "policy.min"));
+    }
+    match (policy, numbers.as_slice()) {
+        (_, []) => (),
+        (Policy::Mute, [minutes]) if *minutes <= MAX_MUTE_MINUTES => config.mute_minutes = *minutes,
+        (Policy::Mute, [_]) => return Err(t!(locale, "policy.max_mute", max = MAX_MUTE_MINUTES)),
+        (Policy::Escalate, [mute_after, ban_after]) if mute_after < ban_after => {
+            config.mute_after = *mute_after as u32;
+            config.ban_after = *ban_after as u32;
+        }
+        (Policy::Escalate, [_, _]) => return Err(t!(locale, "policy.order")),
+        _ => return Err(t!(locale, "policy.args", policy = policy)),
+    }
+    config.policy = policy;
+    Ok(())
+}
+
+/// Applies `min_length [similarity]` to the config
+fn parse_text_check(config: &mut ChatConfig, args: &str) -> Result<(), String> {
+    let locale = config.locale;
+    let numbers = args
+        .split_whitespace()
+        .map(|n| n.parse::<u32>().map_err(|_| t!(locale, "text_check.usage")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match numbers.as_slice() {
+        [min_length] => config.text_min_length = *min_length as usize,
+        [min_length, similarity] if *similarity >= 1 && *similarity <= 100 => {
+            config.text_min_length = *min_length as usize;
+            config.text_similarity = *similarity;
+        }
+        _ => return Err(t!(locale, "text_check.usage")),
+    }
+    Ok(())
+}
+
+fn describe_template_error(locale: Locale, e: TemplateError) -> String {
+    match e {
+        TemplateError::Empty => t!(locale, "notice.reset"),
+        TemplateError::TooLong => t!(locale, "notice.too_long", max = MAX_NOTICE_LEN),
+        TemplateError::Unbalanced => t!(locale, "notice.unbalanced"),
+        TemplateError::Unknown(name) => {
+            let available = PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>();
+            t!(locale, "notice.unknown", name = name, available = available.join(", "))
+        }
+    }
+}
+
+fn describe_file_types(config: &ChatConfig) -> String {
+    let names = config.file_types.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    if names.is_empty() {
+        t!(config.locale, "file_types.none")
+    } else {
+        t!(config.locale, "file_types.list", file_types = names.join(", "))
+    }
+}
+
+/// Turns checking of the `name` kind on or off
+fn toggle_file_type(db: &Repo, chat_id: i64, name: &str, on: bool) -> HResponse {
+    let mut config = db.get_config(chat_id);
+    let locale = config.locale;
+    if on && name.trim().is_empty() {
+        return HResponse::Text(describe_file_types(&config));
+    }
+    match name.parse::<FileType>() {
+        Err(_) => {
+            let available = FileType::ALL.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            HResponse::Text(t!(
+                locale,
+                "file_types.unknown",
+                file_type = name.trim(),
+                available = available.join(", ")
+            ))
+        }
+        Ok(file_type) => {
+            config.file_types.retain(|t| *t != file_type);
+            if on {
+                config.file_types.push(file_type);
+            }
+            let reply = describe_file_types(&config);
+            if db.insert_config(config) {
+                HResponse::Text(reply)
+            } else {
+                HResponse::Text(t!(locale, "error.store"))
+            }
+        }
+    }
+}
+
+fn describe_reply_mode(locale: Locale, mode: ReplyMode) -> String {
+    match mode {
+        ReplyMode::Link => t!(locale, "reply_mode.link"),
+        ReplyMode::Reply => t!(locale, "reply_mode.reply"),
+        ReplyMode::Quote => t!(locale, "reply_mode.quote"),
+    }
+}
+
+fn audit_reply(locale: Locale, entries: Vec<AuditEntry>) -> HResponse {
+    if entries.is_empty() {
+        HResponse::Text(t!(locale, "audit.empty"))
+    } else {
+        HResponse::URL(entries.iter().map(|entry| format_entry(locale, entry)).collect())
+    }
+}
+
 fn str_to_option(str: &String) -> Option<&String> {
     if str == "" {
         None
@@ -111,29 +309,111 @@ fn str_to_option(str: &String) -> Option<&String> {
     }
 }
 
+fn input_media(locale: Locale, media: &Media) -> InputMedia {
+    let file_id = str_to_option(&media.file_id).map(|s| s.as_str());
+    let unique_id = str_to_option(&media.unique_id).map(|s| s.as_str());
+    prepare_input_media(locale, media.file_type.as_str(), file_id, unique_id)
+}
+
+/// Media that can't go in a media group
+fn ungrouped(media: &Media) -> Option<Outgoing> {
+    let file_type = media.file_type.parse::<FileType>().ok()?;
+    match file_type {
+        FileType::Animation | FileType::Voice | FileType::VideoNote | FileType::Sticker => {
+            Some(Outgoing::File(file_type, media.file_id.clone()))
+        }
+        FileType::Contact | FileType::Location | FileType::Poll => {
+            match serde_json::from_str::<Payload>(&media.file_id) {
+                Ok(payload) => Some(Outgoing::Payload(payload)),
+                Err(e) => {
+                    log::error!("ungrouped: {} of {}: {}", media.unique_id, media.msg_id, e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// One group per album with the album's items, consecutive standalone media share a group
+/// and the kinds that can't be grouped go on their own
+fn media_groups(
+    db: &Repo,
+    locale: Locale,
+    chat_id: i64,
+    media_vec: Vec<Media>,
+    is_duplicate: bool,
+) -> Vec<Outgoing> {
+    let mut groups = Vec::new();
+    let mut singles = Vec::new();
+    let mut sent_albums: Vec<i32> = Vec::new();
+    for media in media_vec {
+        if sent_albums.contains(&media.msg_id) {
+            continue;
+        }
+        if !GROUPABLE.contains(&media.file_type.as_str()) {
+            if let Some(outgoing) = ungrouped(&media) {
+                if !singles.is_empty() {
+                    groups.push(Outgoing::Group(std::mem::take(&mut singles)));
+                }
+                groups.push(outgoing);
+            }
+            continue;
+        }
+        match db.find_album(chat_id, media.msg_id) {
+            Some(album) => {
+                if !singles.is_empty() {
+                    groups.push(Outgoing::Group(std::mem::take(&mut singles)));
+                }
+                let items = album
+                    .msg_ids
+                    .iter()
+                    .filter_map(|id| db.message_media(chat_id, *id, is_duplicate).into_iter().next())
+                    .map(|item| input_media(locale, &item))
+                    .collect::<Vec<_>>();
+                sent_albums.extend(album.msg_ids);
+                groups.push(Outgoing::Group(items));
+            }
+            None => {
+                singles.push(input_media(locale, &media));
+                if singles.len() == MEDIA_GROUP_MAX {
+                    groups.push(Outgoing::Group(std::mem::take(&mut singles)));
+                }
+            }
+        }
+    }
+    if !singles.is_empty() {
+        groups.push(Outgoing::Group(singles));
+    }
+    groups
+}
+
+/// Adds `id` to the chat's network, once the admin asking was found to be an admin of `id` too
+pub fn subscribe(db: Repo, chat_id: i64, id: i64) -> String {
+    let mut config = db.get_config(chat_id);
+    let locale = config.locale;
+    if !config.network.contains(&id) {
+        config.network.push(id);
+    }
+    if db.insert_config(config) {
+        t!(locale, "subscribe.done", id = id)
+    } else {
+        t!(locale, "error.store")
+    }
+}
+
 pub fn handle_command(
-    db: RocksDBRepo,
+    db: Repo,
     tdlib: Arc<Tdlib>,
     command: Command,
     chat_id: i64,
 ) -> Result<HResponse, RequestError> {
-    let get_participants_reply =
-        String::from("Comando ejecutado, ahora puede ejecutar /findinterusers");
-    let response_too_long =
-        String::from("Respuesta demasiado larga para mostart en Telegram, ver logs.");
+    let locale = db.get_config(chat_id).locale;
     let r = match command {
-        Command::Help => HResponse::URL(vec![Command::descriptions()]),
+        Command::Help => HResponse::URL(vec![i18n::help(locale)]),
         Command::LastMediaStored(num) => {
             let media_vec = db.last_media_stored(chat_id, num.into(), false);
-            let vec = media_vec
-                .iter()
-                .map(|media| {
-                    let file_id = str_to_option(&media.file_id).map(|s| s.as_str());
-                    let unique_id = str_to_option(&media.unique_id).map(|s| s.as_str());
-                    prepare_input_media(media.file_type.as_str(), file_id, unique_id)
-                })
-                .collect();
-            HResponse::Media(vec)
+            HResponse::Media(media_groups(&db, locale, chat_id, media_vec, false))
         }
         Command::LastUrlStored(num) => {
             let media_vec = db.last_media_stored(chat_id, num.into(), true);
@@ -145,15 +425,7 @@ pub fn handle_command(
         }
         Command::LastDuplicateMedia(num) => {
             let media_vec = db.last_media_duplicated(chat_id, num.into(), false);
-            let vec = media_vec
-                .iter()
-                .map(|media| {
-                    let file_id = str_to_option(&media.file_id).map(|s| s.as_str());
-                    let unique_id = str_to_option(&media.unique_id).map(|s| s.as_str());
-                    prepare_input_media(media.file_type.as_str(), file_id, unique_id)
-                })
-                .collect();
-            HResponse::Media(vec)
+            HResponse::Media(media_groups(&db, locale, chat_id, media_vec, true))
         }
         Command::LastDuplicateUrls(num) => {
             let media_vec = db.last_media_duplicated(chat_id, num.into(), true);
@@ -164,28 +436,21 @@ pub fn handle_command(
             HResponse::URL(vec)
         }
         Command::FindInterUsers => {
-            let exclude_list: Vec<&str> = vec![
-                "1733079574",
-                "162726413",
-                "1575436070",
-                "1042885111",
-                "785731637",
-                "208056682",
-            ];
-
+            let allowed = db.list_allowed(chat_id);
             let vec = db
                 .get_users_chat_count()
                 .iter()
-                .filter(|tup| {
-                    let user_id = tup.0.user_id.to_string();
-                    !exclude_list.contains(&user_id.as_str())
-                })
+                .filter(|tup| !allowed.contains(&tup.0.user_id))
                 .map(|tup| {
                     let user = tup.0.clone();
                     let count = tup.1;
-                    format!(
-                        "UserId: {}, GroupId: {}, UserName: {} found in {} groups",
-                        user.user_id, user.chat_id, user.user_name, count
+                    t!(
+                        locale,
+                        "line.interuser",
+                        user_id = user.user_id,
+                        chat_id = user.chat_id,
+                        name = user.user_name,
+                        count = count
                     )
                 })
                 .collect::<Vec<_>>();
@@ -196,7 +461,7 @@ pub fn handle_command(
             let users_vec = db.list_user_groups(chat_id, id);
             let vec = users_vec
                 .iter()
-                .map(|user| format!("GroupId: {}, GroupName: {}", user.chat_id, user.chat_name))
+                .map(|user| t!(locale, "line.usergroup", chat_id = user.chat_id, name = user.chat_name))
                 .collect();
             HResponse::URL(vec)
         }
@@ -205,24 +470,34 @@ pub fn handle_command(
             let chat_ids = db.get_chat_ids();
             log::info!("chats: {:?}", chat_ids);
             get_participants(tdlib, chat_ids);
-            HResponse::Text(get_participants_reply)
+            HResponse::Text(t!(locale, "participants.requested"))
         }
         Command::FindInactiveUsers(ndays) => {
+            let allowed = db.list_allowed(chat_id);
             let vec = db
                 .inactive_users_before(ndays)
                 .iter()
+                .filter(|user| !allowed.contains(&user.user_id))
                 .map(|user| {
-                    format!(
-                        "UserId: {}, UserName: {}, Last Update: {}",
-                        user.user_id, user.user_name, Utc.timestamp(user.timestamp, 0)
+                    t!(
+                        locale,
+                        "line.inactive",
+                        user_id = user.user_id,
+                        name = user.user_name,
+                        date = Utc.timestamp(user.timestamp, 0)
                     )
                 })
                 .collect::<Vec<_>>();
             HResponse::URL(vec)
         }
         Command::BanInactiveUsers(ndays) => {
-            let vec = db.inactive_users_before(ndays);
-            HResponse::Ban(vec)
+            let allowed = db.list_allowed(chat_id);
+            let vec = db
+                .inactive_users_before(ndays)
+                .into_iter()
+                .filter(|user| user.chat_id == chat_id && !allowed.contains(&user.user_id))
+                .collect();
+            HResponse::Ban(vec, t!(locale, "reason.inactive", days = ndays))
         }
         Command::ListMedia(num) => {
             let media_vec = db.list_media(num.into());
@@ -230,8 +505,7 @@ pub fn handle_command(
                 .iter()
                 .map(|media| format!("{:?}", media))
                 .collect::<Vec<_>>();
-            log::info!("ListMedia: {}", vec.join("\n"));
-            HResponse::Text(response_too_long)
+            HResponse::Records(String::from("media"), vec, ok!(serde_json::to_string_pretty(&media_vec)))
         }
 
         Command::ListUsers(num) => {
@@ -240,8 +514,7 @@ pub fn handle_command(
                 .iter()
                 .map(|user| format!("{:?}", user))
                 .collect::<Vec<_>>();
-            log::info!("ListUsers: {}", vec.join("\n"));
-            HResponse::Text(response_too_long)
+            HResponse::Records(String::from("users"), vec, ok!(serde_json::to_string_pretty(&users_vec)))
         }
 
         Command::ListDuplicates(num) => {
@@ -250,8 +523,7 @@ pub fn handle_command(
                 .iter()
                 .map(|media| format!("{:?}", media))
                 .collect::<Vec<_>>();
-            log::info!("ListDuplicates: {}", vec.join("\n"));
-            HResponse::Text(response_too_long)
+            HResponse::Records(String::from("duplicates"), vec, ok!(serde_json::to_string_pretty(&media_vec)))
         }
         Command::GetChatIds => {
             let vec = db
@@ -261,6 +533,262 @@ pub fn handle_command(
                 .collect::<Vec<_>>();
             HResponse::Text(vec.join("\n"))
         }
+        Command::SetWindow(ndays) => {
+            let mut config = db.get_config(chat_id);
+            if !config.set_window_days(ndays) {
+                HResponse::Text(t!(locale, "window.range", max = MAX_WINDOW_DAYS))
+            } else if db.insert_config(config) {
+                HResponse::Text(t!(locale, "window.set", days = ndays))
+            } else {
+                HResponse::Text(t!(locale, "error.store"))
+            }
+        }
+        Command::GetWindow => {
+            let config = db.get_config(chat_id);
+            HResponse::Text(t!(locale, "window.set", days = config.window_days()))
+        }
+        Command::SetPhotoDistance(distance) => {
+            if distance > 32 {
+                HResponse::Text(t!(locale, "distance.range"))
+            } else {
+                let mut config = db.get_config(chat_id);
+                config.phash_distance = distance;
+                if !db.insert_config(config) {
+                    HResponse::Text(t!(locale, "error.store"))
+                } else if distance == 0 {
+                    HResponse::Text(t!(locale, "distance.disabled"))
+                } else {
+                    HResponse::Text(t!(locale, "distance.set", bits = distance))
+                }
+            }
+        }
+        Command::Subscribe(id) => {
+            if id == chat_id {
+                HResponse::Text(t!(locale, "subscribe.self"))
+            } else if !db.get_chat_ids().contains(&id) {
+                HResponse::Text(t!(locale, "subscribe.unknown", id = id))
+            } else if db.get_config(chat_id).network.contains(&id) {
+                HResponse::Text(t!(locale, "subscribe.already", id = id))
+            } else {
+                HResponse::Subscribe(id)
+            }
+        }
+        Command::Unsubscribe(id) => {
+            let mut config = db.get_config(chat_id);
+            config.network.retain(|sibling| *sibling != id);
+            if db.insert_config(config) {
+                HResponse::Text(t!(locale, "unsubscribe.done", id = id))
+            } else {
+                HResponse::Text(t!(locale, "error.remove"))
+            }
+        }
+        Command::Network => {
+            let vec = db
+                .get_config(chat_id)
+                .network
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            HResponse::URL(vec)
+        }
+        Command::SetPolicy(args) => {
+            let mut config = db.get_config(chat_id);
+            match parse_policy(&mut config, &args) {
+                Err(e) => HResponse::Text(e),
+                Ok(_) => {
+                    let description = describe_policy(&config);
+                    if db.insert_config(config) {
+                        HResponse::Text(description)
+                    } else {
+                        HResponse::Text(t!(locale, "error.store"))
+                    }
+                }
+            }
+        }
+        Command::GetPolicy => HResponse::Text(describe_policy(&db.get_config(chat_id))),
+        Command::Offenders(num) => {
+            let vec = db
+                .top_offenders(chat_id, num.into())
+                .iter()
+                .map(|offender| {
+                    t!(
+                        locale,
+                        "line.offender",
+                        user_id = offender.user_id,
+                        name = offender.user_name,
+                        count = offender.count,
+                        link = describe(locale, offender.last_chat_id, offender.last_msg_id)
+                    )
+                })
+                .collect::<Vec<_>>();
+            HResponse::URL(vec)
+        }
+        Command::AllowUser(id) => {
+            if db.insert_allowed(chat_id, id) {
+                HResponse::Text(t!(locale, "allow.done", id = id))
+            } else {
+                HResponse::Text(t!(locale, "error.store"))
+            }
+        }
+        Command::DisallowUser(id) => {
+            if !db.is_allowed(chat_id, id) {
+                HResponse::Text(t!(locale, "allow.missing", id = id))
+            } else if db.delete_allowed(chat_id, id) {
+                HResponse::Text(t!(locale, "allow.removed", id = id))
+            } else {
+                HResponse::Text(t!(locale, "error.remove"))
+            }
+        }
+        Command::ListAllowed => {
+            let vec = db
+                .list_allowed(chat_id)
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            HResponse::URL(vec)
+        }
+        Command::AuditLog(args) => match parse_query(&args) {
+            None => HResponse::Text(t!(locale, "audit.usage")),
+            Some(AuditQuery::Export) => match export(db, chat_id) {
+                Some((name, data)) => HResponse::Document(name, data),
+                None => HResponse::Text(t!(locale, "audit.empty")),
+            },
+            Some(AuditQuery::User(user_id)) => {
+                audit_reply(locale, db.list_audit(chat_id, Some(user_id), 0))
+            }
+            Some(AuditQuery::Recent(num)) => audit_reply(locale, db.list_audit(chat_id, None, num)),
+        },
+        Command::SetLanguage(name) => match name.parse::<Locale>() {
+            Err(_) => {
+                let available = Locale::ALL.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+                HResponse::Text(t!(
+                    locale,
+                    "language.unknown",
+                    locale = name.trim(),
+                    available = available.join(", ")
+                ))
+            }
+            Ok(new_locale) => {
+                let mut config = db.get_config(chat_id);
+                config.locale = new_locale;
+                if db.insert_config(config) {
+                    HResponse::Text(t!(new_locale, "language.set"))
+                } else {
+                    HResponse::Text(t!(locale, "error.store"))
+                }
+            }
+        },
+        Command::GetLanguage => HResponse::Text(t!(locale, "language.set")),
+        Command::SetNotice(template) => {
+            let mut config = db.get_config(chat_id);
+            let reply = if template.trim().is_empty() {
+                config.notice = None;
+                Ok(t!(locale, "notice.reset"))
+            } else {
+                match validate(&template) {
+                    Ok(_) => {
+                        config.notice = Some(template);
+                        Ok(t!(locale, "notice.saved"))
+                    }
+                    Err(e) => Err(describe_template_error(locale, e)),
+                }
+            };
+            match reply {
+                Err(e) => HResponse::Text(e),
+                Ok(reply) if db.insert_config(config) => HResponse::Text(reply),
+                Ok(_) => HResponse::Text(t!(locale, "error.store")),
+            }
+        }
+        Command::PreviewNotice => {
+            let config = db.get_config(chat_id);
+            let notice = sample(&config, Utc::now().timestamp());
+            HResponse::Text(duplicate_text(&config, "duplicate", "media", &notice))
+        }
+        Command::SetWarningTtl(secs) => {
+            if secs < 0 || secs > MAX_WARNING_TTL_SECS {
+                HResponse::Text(t!(locale, "warning_ttl.range", max = MAX_WARNING_TTL_SECS))
+            } else {
+                let mut config = db.get_config(chat_id);
+                config.warning_ttl = secs;
+                if !db.insert_config(config) {
+                    HResponse::Text(t!(locale, "error.store"))
+                } else if secs == 0 {
+                    HResponse::Text(t!(locale, "warning_ttl.off"))
+                } else {
+                    HResponse::Text(t!(locale, "warning_ttl.set", secs = secs))
+                }
+            }
+        }
+        Command::SetReplyMode(name) => match name.parse::<ReplyMode>() {
+            Err(_) => {
+                let available = ReplyMode::ALL.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+                HResponse::Text(t!(
+                    locale,
+                    "reply_mode.unknown",
+                    mode = name.trim(),
+                    available = available.join(", ")
+                ))
+            }
+            Ok(mode) => {
+                let mut config = db.get_config(chat_id);
+                config.reply_mode = mode;
+                if db.insert_config(config) {
+                    HResponse::Text(describe_reply_mode(locale, mode))
+                } else {
+                    HResponse::Text(t!(locale, "error.store"))
+                }
+            }
+        },
+        Command::GetReplyMode => {
+            HResponse::Text(describe_reply_mode(locale, db.get_config(chat_id).reply_mode))
+        }
+        Command::SetAlbumMode(name) => match name.parse::<AlbumMode>() {
+            Err(_) => {
+                let available = AlbumMode::ALL.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+                HResponse::Text(t!(
+                    locale,
+                    "album_mode.unknown",
+                    mode = name.trim(),
+                    available = available.join(", ")
+                ))
+            }
+            Ok(mode) => {
+                let mut config = db.get_config(chat_id);
+                config.album_mode = mode;
+                if !db.insert_config(config) {
+                    HResponse::Text(t!(locale, "error.store"))
+                } else if mode == AlbumMode::Whole {
+                    HResponse::Text(t!(locale, "album_mode.whole"))
+                } else {
+                    HResponse::Text(t!(locale, "album_mode.items"))
+                }
+            }
+        },
+        Command::SetTextCheck(args) => {
+            let mut config = db.get_config(chat_id);
+            match parse_text_check(&mut config, &args) {
+                Err(e) => HResponse::Text(e),
+                Ok(()) => {
+                    let reply = if config.text_min_length == 0 {
+                        t!(locale, "text_check.off")
+                    } else {
+                        t!(
+                            locale,
+                            "text_check.set",
+                            length = config.text_min_length,
+                            similarity = config.text_similarity
+                        )
+                    };
+                    if db.insert_config(config) {
+                        HResponse::Text(reply)
+                    } else {
+                        HResponse::Text(t!(locale, "error.store"))
+                    }
+                }
+            }
+        }
+        Command::Track(name) => toggle_file_type(&db, chat_id, &name, true),
+        Command::Untrack(name) => toggle_file_type(&db, chat_id, &name, false),
     };
     Ok(r)
 }
@@ -279,3 +807,417 @@ fn get_participants(tdlib: Arc<Tdlib>, chat_ids: Vec<i64>) {
 
     log::info!("No more updates");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_command, subscribe, Command};
+    use crate::audit;
+    use crate::duplicates::record_offender;
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::{
+        Album, AuditAction, ChatConfig, FileType, HResponse, Locale, Outgoing, Payload, Policy, ReplyMode,
+    };
+    use teloxide::types::InputMedia;
+    use crate::repository::{conformance, Repo};
+    use rtdlib::Tdlib;
+    use std::sync::Arc;
+
+    const CHAT_ID: i64 = -1001592783264;
+
+    fn text(response: HResponse) -> String {
+        match response {
+            HResponse::Text(txt) => txt,
+            HResponse::URL(urls) => urls.join("\n"),
+            _ => panic!("Expected a text response"),
+        }
+    }
+
+    fn run(db: &Repo, command: Command) -> HResponse {
+        handle_command(db.clone(), Arc::new(Tdlib::new()), command, CHAT_ID).unwrap()
+    }
+
+    fn english() -> Repo {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.locale = Locale::En;
+        db.insert_config(config);
+        db
+    }
+
+    #[test]
+    fn window_commands() {
+        let db = english();
+        assert_eq!(text(run(&db, Command::GetWindow)), "Media will be unique for 4 days");
+        assert_eq!(text(run(&db, Command::SetWindow(7))), "Media will be unique for 7 days");
+        assert_eq!(text(run(&db, Command::GetWindow)), "Media will be unique for 7 days");
+        assert_eq!(db.get_config(CHAT_ID).window, 7 * 86400);
+        assert_eq!(
+            text(run(&db, Command::SetWindow(0))),
+            "The window must be between 1 and 3650 days"
+        );
+        run(&db, Command::SetWindow(3651));
+        run(&db, Command::SetWindow(i64::MAX));
+        assert_eq!(db.get_config(CHAT_ID).window_days(), 7);
+    }
+
+    #[test]
+    fn last_url_stored() {
+        let db = english();
+        db.insert_item(conformance::sdo(CHAT_ID, 10, "url", "https://youtu.be/GCI0NMgVfPk"), false);
+        db.insert_item(conformance::sdo(CHAT_ID, 11, "photo", "AQADiq4xG--XSVd4"), true);
+        assert_eq!(text(run(&db, Command::LastUrlStored(5))), "https://youtu.be/GCI0NMgVfPk");
+        match run(&db, Command::LastMediaStored(5)) {
+            HResponse::Media(media) => assert_eq!(media.len(), 1),
+            _ => panic!("Expected a media response"),
+        }
+    }
+
+    #[test]
+    fn albums_are_sent_together() {
+        let db = english();
+        db.insert_item(conformance::sdo(CHAT_ID, 20, "photo", "album_a"), true);
+        db.insert_item(conformance::sdo(CHAT_ID, 21, "photo", "album_b"), true);
+        db.insert_item(conformance::sdo(CHAT_ID, 22, "photo", "single_c"), true);
+        db.insert_album(Album {
+            chat_id: CHAT_ID,
+            media_group_id: String::from("13054627816521573"),
+            msg_ids: vec![20, 21],
+            timestamp: 1633072800,
+        });
+        let groups = match run(&db, Command::LastMediaStored(10)) {
+            HResponse::Media(groups) => groups,
+            _ => panic!("Expected a media response"),
+        };
+        let captions = groups
+            .iter()
+            .map(|outgoing| {
+                let group = match outgoing {
+                    Outgoing::Group(group) => group,
+                    _ => panic!("Expected media groups"),
+                };
+                group
+                    .iter()
+                    .map(|media| match media {
+                        InputMedia::Photo(photo) => photo.caption.clone().unwrap(),
+                        _ => panic!("Expected photos"),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(captions.len(), 2);
+        assert!(captions.iter().any(|c| c.contains("album_a") && c.contains("album_b")));
+        assert!(captions.iter().any(|c| c.contains("single_c") && !c.contains("album")));
+    }
+
+    #[test]
+    fn ungroupable_kinds_go_alone() {
+        let db = english();
+        let payload = Payload::Location { latitude: 40.4168, longitude: -3.7038 };
+        let mut location = conformance::sdo(CHAT_ID, 30, "location", "location:40.4168,-3.7038");
+        location.file_id = Some(serde_json::to_string(&payload).unwrap());
+        db.insert_item(conformance::sdo(CHAT_ID, 31, "photo", "photo_a"), true);
+        db.insert_item(location, true);
+        db.insert_item(conformance::sdo(CHAT_ID, 32, "sticker", "sticker_b"), true);
+        db.insert_item(conformance::sdo(CHAT_ID, 33, "photo", "photo_c"), true);
+        let outgoing = match run(&db, Command::LastMediaStored(10)) {
+            HResponse::Media(outgoing) => outgoing,
+            _ => panic!("Expected a media response"),
+        };
+        assert!(outgoing.iter().any(|o| matches!(o, Outgoing::Payload(p) if *p == payload)));
+        assert!(outgoing.iter().any(|o| matches!(o, Outgoing::File(FileType::Sticker, id) if id == "file_sticker_b")));
+        let photos = outgoing
+            .iter()
+            .map(|o| match o {
+                Outgoing::Group(group) => group.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        assert_eq!(photos, 2);
+    }
+
+    #[test]
+    fn file_type_commands() {
+        let db = english();
+        let listed = text(run(&db, Command::Track(String::new())));
+        assert!(listed.starts_with("Duplicates are checked for: url, photo"));
+        assert!(!listed.contains("sticker"));
+        assert_eq!(
+            text(run(&db, Command::Track(String::from("gif")))),
+            "Unknown kind gif, use one of: url, photo, video, audio, document, voice, animation, video_note, sticker, contact, location, poll, forward"
+        );
+        assert!(text(run(&db, Command::Track(String::from(" Sticker ")))).ends_with("poll, forward, sticker"));
+        run(&db, Command::Track(String::from("sticker")));
+        assert_eq!(db.get_config(CHAT_ID).file_types.iter().filter(|t| **t == FileType::Sticker).count(), 1);
+        assert!(!text(run(&db, Command::Untrack(String::from("url")))).contains("url"));
+        assert!(!db.get_config(CHAT_ID).tracks("url"));
+        for file_type in FileType::ALL.iter() {
+            run(&db, Command::Untrack(file_type.to_string()));
+        }
+        assert_eq!(text(run(&db, Command::Track(String::new()))), "No duplicates are checked");
+    }
+
+    #[test]
+    fn text_check_command() {
+        let db = english();
+        let usage = "Use /settextcheck <min length> [similarity from 1 to 100]";
+        assert_eq!(text(run(&db, Command::SetTextCheck(String::new()))), usage);
+        assert_eq!(text(run(&db, Command::SetTextCheck(String::from("long")))), usage);
+        assert_eq!(text(run(&db, Command::SetTextCheck(String::from("200 150")))), usage);
+        assert_eq!(
+            text(run(&db, Command::SetTextCheck(String::from("200")))),
+            "Texts without links of 200 characters or more are flagged when 90% similar"
+        );
+        run(&db, Command::SetTextCheck(String::from("120 80")));
+        let config = db.get_config(CHAT_ID);
+        assert_eq!((config.text_min_length, config.text_similarity), (120, 80));
+        assert_eq!(text(run(&db, Command::SetTextCheck(String::from("0")))), "Texts without links are not checked");
+        assert_eq!(db.get_config(CHAT_ID).text_min_length, 0);
+    }
+
+    #[test]
+    fn find_inter_users() {
+        let db = english();
+        let user = conformance::user(42);
+        db.insert_user(&user, conformance::chat(CHAT_ID));
+        db.insert_user(&user, conformance::chat(-1001192585346));
+        let reply = text(run(&db, Command::FindInterUsers));
+        assert!(reply.contains("UserId: 42"));
+        assert!(reply.contains("found in 2 groups"));
+    }
+
+    #[test]
+    fn policy_commands() {
+        let db = english();
+        assert_eq!(text(run(&db, Command::GetPolicy)), "Duplicates are deleted");
+        assert_eq!(
+            text(run(&db, Command::SetPolicy(String::from("mute 15")))),
+            "Duplicates are deleted and the sender muted for 15 minutes"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetPolicy(String::from("Escalate 2 4")))),
+            "Duplicates are answered with a warning, the sender is muted for 15 minutes after 2 strikes and banned after 4"
+        );
+        assert_eq!(db.get_config(CHAT_ID).policy, Policy::Escalate);
+
+        run(&db, Command::SetPolicy(String::from("escalate 4 2")));
+        run(&db, Command::SetPolicy(String::from("warn 3")));
+        run(&db, Command::SetPolicy(String::from("kick")));
+        run(&db, Command::SetPolicy(String::from("mute 0")));
+        assert_eq!(
+            text(run(&db, Command::SetPolicy(String::from("mute 527041")))),
+            "Muting lasts at most 527040 minutes"
+        );
+        run(&db, Command::SetPolicy(String::from("mute 9223372036854775807")));
+        let config = db.get_config(CHAT_ID);
+        assert_eq!(config.policy, Policy::Escalate);
+        assert_eq!((config.mute_after, config.ban_after, config.mute_minutes), (2, 4, 15));
+    }
+
+    #[test]
+    fn offenders() {
+        let db = english();
+        assert_eq!(text(run(&db, Command::Offenders(5))), "");
+        let user = conformance::user(42);
+        for msg_id in [30, 31].iter() {
+            record_offender(db.clone(), CHAT_ID, &user, (CHAT_ID, *msg_id));
+        }
+        record_offender(db.clone(), CHAT_ID, &conformance::user(43), (CHAT_ID, 32));
+        assert_eq!(
+            text(run(&db, Command::Offenders(5))),
+            "UserId: 42, UserName: User 42, 2 duplicates, last: https://t.me/c/1592783264/31\n\
+             UserId: 43, UserName: User 43, 1 duplicates, last: https://t.me/c/1592783264/32"
+        );
+        assert_eq!(text(run(&db, Command::Offenders(1))).lines().count(), 1);
+    }
+
+    #[test]
+    fn subscribe_needs_the_other_chat() {
+        let db = english();
+        let other = -1001192585346;
+        assert_eq!(text(run(&db, Command::Subscribe(CHAT_ID))), "A chat can't subscribe to itself");
+        assert_eq!(
+            text(run(&db, Command::Subscribe(other))),
+            "Chat -1001192585346 is not managed by highlander"
+        );
+        db.insert_user(&conformance::user(42), conformance::chat(other));
+        match run(&db, Command::Subscribe(other)) {
+            HResponse::Subscribe(id) => assert_eq!(id, other),
+            _ => panic!("Expected a subscribe response"),
+        }
+        assert!(db.get_config(CHAT_ID).network.is_empty());
+        assert_eq!(
+            subscribe(db.clone(), CHAT_ID, other),
+            "Media shared on -1001192585346 will be flagged as duplicate"
+        );
+        assert_eq!(text(run(&db, Command::Subscribe(other))), "Already subscribed to -1001192585346");
+        assert_eq!(db.get_config(CHAT_ID).network, vec![other]);
+    }
+
+    #[test]
+    fn allowlist_commands() {
+        let db = english();
+        let bot = conformance::user(162726413);
+        for chat in [CHAT_ID, -1001192585346].iter() {
+            db.insert_user(&bot, conformance::chat(*chat));
+            db.insert_user(&conformance::user(42), conformance::chat(*chat));
+        }
+        assert!(text(run(&db, Command::FindInterUsers)).contains("UserId: 162726413"));
+
+        assert_eq!(
+            text(run(&db, Command::AllowUser(162726413))),
+            "User 162726413 will never be flagged nor banned in this chat"
+        );
+        assert_eq!(text(run(&db, Command::ListAllowed)), "162726413");
+        assert!(!db.is_allowed(-1001192585346, 162726413));
+        let reply = text(run(&db, Command::FindInterUsers));
+        assert!(!reply.contains("UserId: 162726413"));
+        assert!(reply.contains("UserId: 42"));
+        match run(&db, Command::BanInactiveUsers(-1)) {
+            HResponse::Ban(users, reason) => {
+                assert_eq!(reason, "inactive for -1 days");
+                assert_eq!(users.len(), 1);
+                assert_eq!((users[0].user_id, users[0].chat_id), (42, CHAT_ID));
+            }
+            _ => panic!("Expected a ban response"),
+        }
+
+        assert_eq!(
+            text(run(&db, Command::DisallowUser(162726413))),
+            "User 162726413 removed from the allowlist"
+        );
+        assert_eq!(
+            text(run(&db, Command::DisallowUser(162726413))),
+            "User 162726413 is not on the allowlist"
+        );
+        assert!(text(run(&db, Command::FindInterUsers)).contains("UserId: 162726413"));
+    }
+
+    #[test]
+    fn auditlog_commands() {
+        let db = english();
+        assert_eq!(
+            text(run(&db, Command::AuditLog(String::new()))),
+            "No moderation actions recorded"
+        );
+        audit::record(db.clone(), AuditAction::Duplicate, CHAT_ID, 42, 10, 0, "unique_id");
+        audit::record(db.clone(), AuditAction::Delete, CHAT_ID, 42, 10, 0, "duplicate, strike 1");
+        audit::record(db.clone(), AuditAction::Ban, CHAT_ID, 43, 0, 1072037897, "inactive for 30 days");
+
+        let reply = text(run(&db, Command::AuditLog(String::new())));
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("ban UserId: 43, by admin 1072037897"));
+        assert_eq!(text(run(&db, Command::AuditLog(String::from("1")))).lines().count(), 1);
+
+        let reply = text(run(&db, Command::AuditLog(String::from("user 42"))));
+        assert_eq!(reply.lines().count(), 2);
+        assert!(reply.lines().all(|line| line.contains("UserId: 42")));
+        assert!(text(run(&db, Command::AuditLog(String::from("user")))).starts_with("Usage"));
+        match run(&db, Command::AuditLog(String::from("export"))) {
+            HResponse::Document(name, data) => {
+                assert_eq!(name, "audit_-1001592783264.jsonl");
+                assert_eq!(String::from_utf8(data).unwrap().lines().count(), 3);
+            }
+            _ => panic!("Expected a document"),
+        }
+    }
+
+    #[test]
+    fn listings_carry_json_records() {
+        let db = english();
+        db.insert_user(&conformance::user(42), conformance::chat(CHAT_ID));
+        match run(&db, Command::ListUsers(10)) {
+            HResponse::Records(name, lines, json) => {
+                assert_eq!(name, "users");
+                assert_eq!(lines.len(), 1);
+                let users: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+                assert_eq!(users[0]["user_id"], 42);
+            }
+            _ => panic!("Expected records"),
+        }
+    }
+
+    #[test]
+    fn language_commands() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        assert_eq!(text(run(&db, Command::GetWindow)), "El contenido sera unico durante 4 dias");
+        assert!(text(run(&db, Command::Help)).starts_with("Estos son los comandos disponibles:\n/help - "));
+        assert_eq!(
+            text(run(&db, Command::SetLanguage(String::from("fr")))),
+            "Idioma desconocido fr, use uno de: es, en"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetLanguage(String::from("EN")))),
+            "Replies in this chat are in English"
+        );
+        assert_eq!(db.get_config(CHAT_ID).locale, Locale::En);
+        assert_eq!(text(run(&db, Command::GetWindow)), "Media will be unique for 4 days");
+        assert!(text(run(&db, Command::Help)).contains("/setlanguage - language of the replies in this chat"));
+    }
+
+    #[test]
+    fn notice_commands() {
+        let db = english();
+        let preview = text(run(&db, Command::PreviewNotice));
+        assert!(preview.starts_with("Duplicate message: media already shared in the last 4 days"));
+
+        assert_eq!(
+            text(run(&db, Command::SetNotice(String::from("{user} posted {link}")))),
+            "Unknown placeholder link, use: {user}, {type}, {original_link}, {age}, {window}"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetNotice(String::from("{user posted")))),
+            "The notice has unbalanced braces"
+        );
+        assert!(db.get_config(CHAT_ID).notice.is_none());
+
+        assert_eq!(
+            text(run(&db, Command::SetNotice(String::from("{user}, this {type} was shared {age}: {original_link}")))),
+            "Duplicate notice saved, see it with /previewnotice"
+        );
+        assert_eq!(
+            text(run(&db, Command::PreviewNotice)),
+            "Connor MacLeod, this photo was shared 2 days ago: https://t.me/c/1592783264/1234"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetNotice(String::new()))),
+            "The default duplicate notice will be used"
+        );
+        assert!(db.get_config(CHAT_ID).notice.is_none());
+    }
+
+    #[test]
+    fn warning_ttl_command() {
+        let db = english();
+        assert_eq!(db.get_config(CHAT_ID).warning_ttl, 0);
+        assert_eq!(
+            text(run(&db, Command::SetWarningTtl(-5))),
+            "The timeout must be between 0 and 172800 seconds"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetWarningTtl(90))),
+            "Duplicate warnings will be deleted after 90 seconds"
+        );
+        assert_eq!(db.get_config(CHAT_ID).warning_ttl, 90);
+        assert_eq!(text(run(&db, Command::SetWarningTtl(0))), "Duplicate warnings will be kept");
+        assert_eq!(db.get_config(CHAT_ID).warning_ttl, 0);
+    }
+
+    #[test]
+    fn reply_mode_commands() {
+        let db = english();
+        assert_eq!(text(run(&db, Command::GetReplyMode)), "Notices link the original message");
+        assert_eq!(
+            text(run(&db, Command::SetReplyMode(String::from("forward")))),
+            "Unknown mode forward, use one of: link, reply, quote"
+        );
+        assert_eq!(
+            text(run(&db, Command::SetReplyMode(String::from(" Reply ")))),
+            "Notices reply to the original message while it is within the window"
+        );
+        assert_eq!(db.get_config(CHAT_ID).reply_mode, ReplyMode::Reply);
+        run(&db, Command::SetReplyMode(String::from("quote")));
+        assert!(text(run(&db, Command::GetReplyMode)).contains("quote it to the poster"));
+    }
+}
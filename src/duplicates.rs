@@ -1,14 +1,17 @@
-use lazy_static::lazy_static;
-use regex::Regex;
-
 use std::sync::Arc;
 
+use chrono::offset::Utc;
 use teloxide::prelude::*;
-use teloxide::types::{Chat, MediaKind, MessageKind, User};
+use teloxide::types::{Chat, ForwardKind, ForwardNonChannel, ForwardedFrom, MediaKind, MessageKind, User};
 
+use crate::audit;
+use crate::fingerprint;
+use crate::i18n;
+use crate::kinds;
 use crate::models::*;
-use crate::repository::Repository;
-use crate::rocksdb::RocksDBRepo;
+use crate::notice::{duplicate_status, duplicate_text, Notice};
+use crate::repository::Repo;
+use crate::urls::{message_urls, url_id};
 
 pub fn extract_last250(text: &str) -> &str {
     let l = text.len();
@@ -16,84 +19,153 @@ pub fn extract_last250(text: &str) -> &str {
     text.get(i..l).unwrap_or("")
 }
 
-pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Status {
+/// Counts a duplicate against its sender, once per message
+pub fn record_offender(db: Repo, chat_id: i64, user: &User, original: (i64, i32)) -> bool {
+    let count = match db.get_offender(chat_id, user.id) {
+        Some(offender) => offender.count + 1,
+        None => 1,
+    };
+    let user_name = user.username.as_ref().unwrap_or(&user.first_name);
+    db.insert_offender(Offender {
+        chat_id,
+        user_id: user.id,
+        user_name: user_name.to_string(),
+        count,
+        last_chat_id: original.0,
+        last_msg_id: original.1,
+        timestamp: Utc::now().timestamp(),
+    })
+}
+
+/// Checks the `urls` of a text, skipping those in `known` which were already stored for the message
+fn check_urls(
+    db: Repo,
+    chat: Arc<Chat>,
+    msg_id: i32,
+    t: &str,
+    urls: &[String],
+    user_name: &str,
+    status: Status,
+    known: &[String],
+) -> Status {
+    let mut statuses: Vec<(Status, &str)> = Vec::new();
+    urls.iter().for_each(|url| {
+        log::info!("Detected url: {}", url);
+        let unique_id = url_id(url);
+        if known.contains(&unique_id) {
+            log::info!("url {} already stored for {}", url, msg_id);
+            return;
+        }
+        let chat = chat.clone();
+        let sdo = SDO {
+            chat,
+            msg_id,
+            file_type: String::from("url"),
+            unique_id: unique_id,
+            file_id: None,
+        };
+        let new_status = handle_message(db.clone(), &status, sdo, "urls", user_name);
+        statuses.push((new_status, url.as_str()));
+    });
+
+    if statuses.len() == 1 {
+        statuses[0].0.clone()
+    } else if statuses.len() > 1 {
+        let has_valid_url = statuses.iter().any(|el| !el.0.action);
+        log::info!("Has Valid Url: {}", has_valid_url);
+        if has_valid_url {
+            // At least 1 url is NOT duplicate
+            let mut result =
+                statuses
+                    .into_iter()
+                    .fold((status, t.to_string()), |acc, el| {
+                        log::info!("status: {:?}", acc.0);
+                        if el.0.action {
+                            let stat = acc.0.clone();
+                            let new_text = acc.1.replace(el.1, "DUPLICATED");
+                            (stat, new_text)
+                        } else {
+                            (el.0, acc.1)
+                        }
+                    });
+            result.0.text = result.1.to_string();
+            result.0
+        } else {
+            statuses[0].0.clone()
+        }
+    } else {
+        status
+    }
+}
+
+pub fn detect_duplicates(db: Repo, message: &Message, user: &User, is_admin: bool) -> Status {
+    let r = check_message(db.clone(), message, user);
+    settle(db, message.chat.id, user, is_admin, message.id, r, "unique_id")
+}
+
+/// Stores the message's media or flags it as a duplicate, without counting it against the user
+pub fn check_message(db: Repo, message: &Message, user: &User) -> Status {
     let kind: MessageKind = message.kind.clone();
     let chat: Arc<Chat> = Arc::new(message.chat.clone());
     let msg_id: i32 = message.id;
     //log::info!("Message received: {:?}", message);
 
     store_user(db.clone(), user, chat.clone());
+    let user_name: &str = user.username.as_ref().unwrap_or(&user.first_name);
 
-    let success = "Media will be unique for 5 days";
+    let config = db.get_config(chat.id);
+    let success = t!(config.locale, "window.set", days = config.window_days());
     let mut status = Status {
         action: false,
         respond: false,
-        text: success.to_string(),
+        text: success,
+        reply_to: None,
+        quote: None,
+        original: None,
+    };
+
+    let urls = message_urls(message);
+    let captioned = match message.caption() {
+        Some(caption) if !urls.is_empty() => {
+            let status = Status::new(&status);
+            Some(check_urls(db.clone(), chat.clone(), msg_id, caption, &urls, user_name, status, &[]))
+        }
+        _ => None,
     };
 
-    let r: Status = match kind {
+    let forward = forward_id(message).map(|unique_id| {
+        let sdo = SDO {
+            chat: chat.clone(),
+            msg_id,
+            file_type: String::from("forward"),
+            unique_id,
+            file_id: None,
+        };
+        handle_message(db.clone(), &status, sdo, "forward", user_name)
+    });
+
+    let content = match kind {
         MessageKind::Common(msg_common) => match msg_common.media_kind {
-            MediaKind::Text(text) => {
-                lazy_static! {
-                    static ref RE: Regex = ok!(Regex::new("(http|ftp|https)://([\\w_-]+(?:(?:\\.[\\w_-]+)+))([\\w.,@?^=%&:/~+#-]*[\\w@?^=%&/~+#-])?"));
-                }
-                let t: &str = &*text.text;
-                let mut statuses: Vec<(Status, &str)> = Vec::new();
-                RE.captures_iter(t).for_each(|cap| {
-                    let url = cap.get(0).unwrap().as_str();
-                    log::info!("Detected url: {}", url);
-                    let chat = chat.clone();
-                    let unique_id = extract_last250(url).into();
-                    let sdo = SDO {
-                        chat,
-                        msg_id,
-                        file_type: String::from("url"),
-                        unique_id: unique_id,
-                        file_id: None,
-                    };
-                    let new_status = handle_message(db.clone(), &status, sdo, "urls");
-                    statuses.push((new_status, url));
-                });
-
-                if statuses.len() == 1 {
-                    statuses[0].0.clone()
-                } else if statuses.len() > 1 {
-                    let has_valid_url = statuses.iter().any(|el| !el.0.action);
-                    log::info!("Has Valid Url: {}", has_valid_url);
-                    if has_valid_url {
-                        // At least 1 url is NOT duplicate
-                        let mut result =
-                            statuses
-                                .into_iter()
-                                .fold((status, t.to_string()), |acc, el| {
-                                    log::info!("status: {:?}", acc.0);
-                                    if el.0.action {
-                                        let stat = acc.0.clone();
-                                        let new_text = acc.1.replace(el.1, "DUPLICATED");
-                                        (stat, new_text)
-                                    } else {
-                                        (el.0, acc.1)
-                                    }
-                                });
-                        result.0.text = result.1.to_string();
-                        result.0
-                    } else {
-                        statuses[0].0.clone()
-                    }
-                } else {
-                    status
-                }
+            MediaKind::Text(text) if !urls.is_empty() => {
+                check_urls(db.clone(), chat.clone(), msg_id, &text.text, &urls, user_name, status, &[])
             }
-            /*MediaKind::Animation(animation) => {
+            MediaKind::Text(text) => check_text(db, &status, chat.clone(), msg_id, &text.text, user_name),
+            MediaKind::Animation(animation) => {
                 let file_unique_id = animation.animation.file_unique_id;
                 let file_id = animation.animation.file_id;
                 log::info!("Animation: {:?}", message);
                 let caption = &*animation.caption.unwrap_or(message.id.to_string());
                 status.text = caption.into();
                 let chat = chat.clone();
-                let sdo = SDO { chat, msg_id, file_type: String::from("animation"), unique_id: file_unique_id, file_id: Some(file_id) };
-                handle_message(db, status, sdo, "media")
-            },*/
+                let sdo = SDO {
+                    chat,
+                    msg_id,
+                    file_type: String::from("animation"),
+                    unique_id: file_unique_id,
+                    file_id: Some(file_id),
+                };
+                handle_message(db, &status, sdo, "media", user_name)
+            }
             MediaKind::Audio(audio) => {
                 let file_unique_id = audio.audio.file_unique_id;
                 let file_id = audio.audio.file_id;
@@ -108,7 +180,7 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
                     unique_id: file_unique_id,
                     file_id: Some(file_id),
                 };
-                handle_message(db, &status, sdo, "media")
+                handle_message(db, &status, sdo, "media", user_name)
             }
             MediaKind::Document(document) => {
                 let file_unique_id = document.document.file_unique_id;
@@ -124,7 +196,7 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
                     unique_id: file_unique_id,
                     file_id: Some(file_id),
                 };
-                handle_message(db, &status, sdo, "media")
+                handle_message(db, &status, sdo, "media", user_name)
             }
             MediaKind::Photo(photo) => {
                 log::info!("Photo: {:?}", message);
@@ -141,7 +213,7 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
                         unique_id: file_unique_id.into(),
                         file_id: Some(file_id.into()),
                     };
-                    handle_message(db.clone(), &acc, sdo, "media")
+                    handle_message(db.clone(), &acc, sdo, "media", user_name)
                 })
             }
             MediaKind::Video(video) => {
@@ -158,7 +230,7 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
                     unique_id: file_unique_id,
                     file_id: Some(file_id),
                 };
-                handle_message(db, &status, sdo, "media")
+                handle_message(db, &status, sdo, "media", user_name)
             }
             MediaKind::Voice(voice) => {
                 let file_unique_id = voice.voice.file_unique_id;
@@ -174,7 +246,47 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
                     unique_id: file_unique_id,
                     file_id: Some(file_id),
                 };
-                handle_message(db, &status, sdo, "media")
+                handle_message(db, &status, sdo, "media", user_name)
+            }
+            MediaKind::VideoNote(video_note) => {
+                let file_unique_id = video_note.video_note.file_unique_id;
+                let file_id = video_note.video_note.file_id;
+                log::info!("VideoNote: {:?}", message);
+                let chat = chat.clone();
+                let sdo = SDO {
+                    chat,
+                    msg_id,
+                    file_type: String::from("video_note"),
+                    unique_id: file_unique_id,
+                    file_id: Some(file_id),
+                };
+                handle_message(db, &status, sdo, "media", user_name)
+            }
+            MediaKind::Sticker(sticker) => {
+                let file_unique_id = sticker.sticker.file_unique_id;
+                let file_id = sticker.sticker.file_id;
+                log::info!("Sticker: {:?}", message);
+                let chat = chat.clone();
+                let sdo = SDO {
+                    chat,
+                    msg_id,
+                    file_type: String::from("sticker"),
+                    unique_id: file_unique_id,
+                    file_id: Some(file_id),
+                };
+                handle_message(db, &status, sdo, "media", user_name)
+            }
+            MediaKind::Contact(contact) => {
+                log::info!("Contact: {:?}", message);
+                check_payload(db, &status, chat, msg_id, kinds::contact(&contact.contact), user_name)
+            }
+            MediaKind::Location(location) => {
+                log::info!("Location: {:?}", message);
+                check_payload(db, &status, chat, msg_id, kinds::location(&location.location), user_name)
+            }
+            MediaKind::Poll(poll) => {
+                log::info!("Poll: {:?}", message);
+                check_payload(db, &status, chat, msg_id, kinds::poll(&poll.poll), user_name)
             }
             _ => {
                 log::info!("Other attachment");
@@ -186,10 +298,150 @@ pub fn detect_duplicates(db: RocksDBRepo, message: &Message, user: &User) -> Sta
             status
         }
     };
+    match forward.into_iter().chain(captioned).find(|status| status.action) {
+        Some(status) if !content.action => status,
+        _ => content,
+    }
+}
+
+/// Forwards are keyed on the post they come from: the channel and its message id, or the
+/// sender name and date when the sender hides their account
+fn forward_id(message: &Message) -> Option<String> {
+    match &message.kind {
+        MessageKind::Common(msg_common) => match &msg_common.forward_kind {
+            ForwardKind::Channel(channel) => Some(format!("forward:{}/{}", channel.chat.id, channel.message_id)),
+            ForwardKind::NonChannel(ForwardNonChannel {
+                date,
+                from: ForwardedFrom::SenderName(name),
+            }) => Some(format!("forward:{}@{}", name, date)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fingerprints long texts, a text similar to one already fingerprinted is stored under the
+/// unique id of that one so it's flagged as its duplicate
+fn check_text(db: Repo, status: &Status, chat: Arc<Chat>, msg_id: i32, text: &str, user_name: &str) -> Status {
+    let config = db.get_config(chat.id);
+    let normalized = fingerprint::normalize(text);
+    if config.text_min_length == 0 || normalized.chars().count() < config.text_min_length {
+        return Status::new(status);
+    }
+    let hash = fingerprint::simhash(&normalized);
+    let max_distance = fingerprint::max_distance(config.text_similarity);
+    let unique_id = match db.find_similar_fingerprint(chat.id, hash, max_distance) {
+        Some(original) => {
+            log::info!("text {} similar to {}", msg_id, original.msg_id);
+            fingerprint::unique_id(original.hash)
+        }
+        None => {
+            db.insert_fingerprint(PHash {
+                chat_id: chat.id,
+                msg_id,
+                hash,
+                timestamp: Utc::now().timestamp(),
+            });
+            fingerprint::unique_id(hash)
+        }
+    };
+    let sdo = SDO {
+        chat,
+        msg_id,
+        file_type: String::from("text"),
+        unique_id,
+        file_id: None,
+    };
+    handle_message(db, status, sdo, "text", user_name)
+}
+
+/// Media without a file is stored with its payload as file id, so it can be sent back
+fn check_payload(db: Repo, status: &Status, chat: Arc<Chat>, msg_id: i32, payload: Payload, user_name: &str) -> Status {
+    let sdo = SDO {
+        chat,
+        msg_id,
+        file_type: kinds::file_type(&payload).to_string(),
+        unique_id: kinds::unique_id(&payload),
+        file_id: Some(ok!(serde_json::to_string(&payload))),
+    };
+    handle_message(db, status, sdo, "media", user_name)
+}
+
+/// Re-checks the urls of an edited message. Urls edited out stop counting as originals and
+/// only the ones edited in can be flagged, the rest were already checked
+pub fn detect_edited_duplicates(db: Repo, message: &Message, user: &User, is_admin: bool) -> Status {
+    let chat: Arc<Chat> = Arc::new(message.chat.clone());
+    let msg_id: i32 = message.id;
+    let user_name: &str = user.username.as_ref().unwrap_or(&user.first_name);
+
+    let config = db.get_config(chat.id);
+    let status = Status {
+        action: false,
+        respond: false,
+        text: t!(config.locale, "window.set", days = config.window_days()),
+        reply_to: None,
+        quote: None,
+        original: None,
+    };
+    let text = match message.text().or_else(|| message.caption()) {
+        Some(text) => text,
+        None => return status,
+    };
+
+    let urls = message_urls(message);
+    let unique_ids: Vec<String> = urls.iter().map(|url| url_id(url)).collect();
+    let mut known: Vec<String> = db
+        .message_media(chat.id, msg_id, true)
+        .into_iter()
+        .map(|media| media.unique_id)
+        .collect();
+    for media in db.message_media(chat.id, msg_id, false) {
+        if media.file_type != "url" {
+            continue;
+        }
+        if unique_ids.contains(&media.unique_id) {
+            known.push(media.unique_id);
+        } else {
+            log::info!("url {} edited out of {}", media.unique_id, msg_id);
+            db.delete_media(chat.id, &media.unique_id);
+        }
+    }
+
+    let r = check_urls(db.clone(), chat.clone(), msg_id, text, &urls, user_name, status, &known);
+    settle(db, chat.id, user, is_admin, msg_id, r, "unique_id")
+}
+
+/// Ignores duplicates of admins and allowed users, counts and audits everybody else's
+/// under the check that flagged them
+pub fn settle(
+    db: Repo,
+    chat_id: i64,
+    user: &User,
+    is_admin: bool,
+    msg_id: i32,
+    r: Status,
+    rule: &str,
+) -> Status {
+    if r.action && (is_admin || db.is_allowed(chat_id, user.id)) {
+        log::info!("duplicate from admin or allowed user {}, ignoring", user.id);
+        return Status {
+            action: false,
+            respond: false,
+            text: r.text,
+            reply_to: None,
+            quote: None,
+            original: None,
+        };
+    }
+    if r.action {
+        let original = r.original.unwrap_or((chat_id, msg_id));
+        record_offender(db.clone(), chat_id, user, original);
+        audit::record(db, AuditAction::Duplicate, chat_id, user.id, msg_id, 0, rule);
+    }
     r
 }
 
-fn store_user(db: RocksDBRepo, user: &User, chat: Arc<Chat>) -> bool {
+fn store_user(db: Repo, user: &User, chat: Arc<Chat>) -> bool {
     let chat = chat.clone();
     if db.chat_user_exists(user, chat.clone()) {
         log::info!("store_user: user {} exists on chat {}", user.id, chat.id);
@@ -200,34 +452,55 @@ fn store_user(db: RocksDBRepo, user: &User, chat: Arc<Chat>) -> bool {
     }
 }
 
-fn handle_message(db: RocksDBRepo, acc: &Status, sdo: SDO, table: &str) -> Status {
+fn handle_message(db: Repo, acc: &Status, sdo: SDO, table: &str, user_name: &str) -> Status {
     let is_media = table == "media";
+    if !db.get_config(sdo.chat.id).tracks(&sdo.file_type) {
+        log::info!("{} is not checked on chat {}", sdo.file_type, sdo.chat.id);
+        return Status::new(acc);
+    }
     match db.item_exists(sdo.clone(), is_media) {
         None => {
-            log::info!("inserting new media: {:?}", sdo);
-            db.insert_item(sdo, is_media);
-            Status::new(acc)
+            let config = db.get_config(sdo.chat.id);
+            match db.find_in_network(&sdo.unique_id, &config.network) {
+                None => {
+                    log::info!("inserting new media: {:?}", sdo);
+                    db.insert_item(sdo, is_media);
+                    Status::new(acc)
+                }
+                Some(media) => {
+                    log::info!("duplicate media on chat {}: {:?}", media.chat_id, media);
+                    let window_days = db.get_config(media.chat_id).window_days();
+                    let notice = Notice::new(user_name, &sdo.chat, &media, window_days);
+                    db.insert_duplicate(sdo);
+                    let text = duplicate_text(&config, "duplicate.network", table, &notice);
+                    duplicate_status(&config, &notice, text, Utc::now().timestamp())
+                }
+            }
         }
         Some(media) => {
             log::info!("duplicate media: {:?}", media);
-            let chat_id = media.chat_id;
+            let config = db.get_config(sdo.chat.id);
+            let window_days = db.get_config(media.chat_id).window_days();
+            let notice = Notice::new(user_name, &sdo.chat, &media, window_days);
             db.insert_duplicate(sdo);
-            let orig_chat_id = match chat_id.to_string().strip_prefix("-100") {
-                Some(s) => s.parse::<i64>().unwrap_or(chat_id),
-                None => 0,
-            };
-            let orig_msg_id = media.msg_id;
-            log::info!("orginal {} - {}", orig_chat_id, orig_msg_id);
-            Status { action: true, respond: true, text: format!("Mensaje Duplicado: {} ya se ha compartido en los ultimos 5 dias.\nVer mensaje original: https://t.me/c/{}/{}", table, orig_chat_id, orig_msg_id) }
+            log::info!("orginal {:?}", notice.original);
+            let text = duplicate_text(&config, "duplicate", table, &notice);
+            duplicate_status(&config, &notice, text, Utc::now().timestamp())
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::duplicates::extract_last250;
+    use crate::duplicates::{detect_duplicates, detect_edited_duplicates, extract_last250};
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::{AuditAction, ChatConfig, FileType, Payload};
+    use crate::repository::{conformance, Repo};
     use lazy_static::lazy_static;
     use regex::Regex;
+    use serde_json::json;
+    use std::sync::Arc;
+    use teloxide::types::Message;
 
     const T1: &str = "hola https://twitter.com/plaforscience/status/1379526168513277960";
     const T2: &str = "hola https://twitter.com/plaforscience/status/1379526168513277960 y ademas https://youtu.be/GCI0NMgVfPk";
@@ -280,4 +553,363 @@ mod tests {
 
         assert_eq!(count, 1);
     }
+
+    const CHAT_ID: i64 = -1001592783264;
+    const USER_ID: i64 = 1072037897;
+
+    fn message(msg_id: i32, content: serde_json::Value) -> Message {
+        let mut msg = json!({
+            "message_id": msg_id,
+            "date": 1633072800,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Highlander" },
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Connor" }
+        });
+        for (k, v) in content.as_object().unwrap() {
+            msg[k] = v.clone();
+        }
+        // Telegram marks the urls of a text as entities
+        if let Some(text) = msg["text"].as_str().map(String::from) {
+            let entities = RE
+                .find_iter(&text)
+                .map(|m| {
+                    json!({
+                        "type": "url",
+                        "offset": text[..m.start()].encode_utf16().count(),
+                        "length": m.as_str().encode_utf16().count()
+                    })
+                })
+                .collect::<Vec<_>>();
+            msg["entities"] = json!(entities);
+        }
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn photo(unique_id: &str) -> serde_json::Value {
+        json!({
+            "photo": [{
+                "file_id": format!("file_{}", unique_id),
+                "file_unique_id": unique_id,
+                "width": 90,
+                "height": 90,
+                "file_size": 1024
+            }]
+        })
+    }
+
+    #[test]
+    fn detects_duplicate_url() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        let first = message(10, json!({ "text": T1 }));
+        let status = detect_duplicates(db.clone(), &first, &user, false);
+        assert!(!status.action);
+
+        let second = message(11, json!({ "text": T1 }));
+        let status = detect_duplicates(db.clone(), &second, &user, false);
+        assert!(status.action);
+        assert!(status.respond);
+        assert!(status.text.contains("https://t.me/c/1592783264/10"));
+        assert!(db.chat_dbuser_exists(USER_ID, CHAT_ID));
+
+        let offender = db.get_offender(CHAT_ID, USER_ID).unwrap();
+        assert_eq!((offender.count, offender.last_chat_id, offender.last_msg_id), (1, CHAT_ID, 10));
+        assert_eq!(offender.user_name, "Connor");
+
+        let entries = db.list_audit(CHAT_ID, Some(USER_ID), 0);
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].action, entries[0].msg_id), (AuditAction::Duplicate, 11));
+    }
+
+    #[test]
+    fn edited_urls() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        detect_duplicates(db.clone(), &message(10, json!({ "text": "hola" })), &user, false);
+        detect_duplicates(db.clone(), &message(11, json!({ "text": T1 })), &user, false);
+
+        // a duplicate url edited in is flagged once
+        let edited = message(10, json!({ "text": T1, "edit_date": 1633072900 }));
+        let status = detect_edited_duplicates(db.clone(), &edited, &user, false);
+        assert!(status.action);
+        assert!(status.text.contains("https://t.me/c/1592783264/11"));
+        let status = detect_edited_duplicates(db.clone(), &edited, &user, false);
+        assert!(!status.action);
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().count, 1);
+
+        // an url edited out no longer counts as the original
+        let edited = message(11, json!({ "text": "sin enlace", "edit_date": 1633072900 }));
+        assert!(!detect_edited_duplicates(db.clone(), &edited, &user, false).action);
+        assert!(db.message_media(CHAT_ID, 11, false).is_empty());
+        let status = detect_duplicates(db.clone(), &message(12, json!({ "text": T1 })), &user, false);
+        assert!(!status.action);
+    }
+
+    #[test]
+    fn admin_edits_update_urls() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let admin = conformance::user(208056682);
+        let user = conformance::user(USER_ID);
+
+        detect_duplicates(db.clone(), &message(10, json!({ "text": "hola" })), &admin, true);
+        let edited = message(10, json!({ "text": T1, "edit_date": 1633072900 }));
+        assert!(!detect_edited_duplicates(db.clone(), &edited, &admin, true).action);
+        let status = detect_duplicates(db.clone(), &message(11, json!({ "text": T1 })), &user, false);
+        assert!(status.text.contains("https://t.me/c/1592783264/10"));
+
+        let edited = message(10, json!({ "text": "sin enlace", "edit_date": 1633072900 }));
+        assert!(!detect_edited_duplicates(db.clone(), &edited, &admin, true).action);
+        assert!(db.message_media(CHAT_ID, 10, false).is_empty());
+        assert!(db.get_offender(CHAT_ID, 208056682).is_none());
+    }
+
+    #[test]
+    fn stickers_are_opt_in() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let sticker = json!({
+            "sticker": {
+                "file_id": "file_sticker",
+                "file_unique_id": "sticker",
+                "width": 512,
+                "height": 512,
+                "is_animated": false
+            }
+        });
+        detect_duplicates(db.clone(), &message(10, sticker.clone()), &user, false);
+        assert!(!detect_duplicates(db.clone(), &message(11, sticker.clone()), &user, false).action);
+        assert!(db.message_media(CHAT_ID, 10, false).is_empty());
+
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.file_types.push(FileType::Sticker);
+        config.file_types.retain(|t| *t != FileType::Photo);
+        db.insert_config(config);
+        detect_duplicates(db.clone(), &message(12, sticker.clone()), &user, false);
+        assert!(detect_duplicates(db.clone(), &message(13, sticker), &user, false).action);
+        assert_eq!(db.message_media(CHAT_ID, 12, false)[0].file_type, "sticker");
+
+        detect_duplicates(db.clone(), &message(14, photo("AQADBAADr60xG")), &user, false);
+        assert!(!detect_duplicates(db.clone(), &message(15, photo("AQADBAADr60xG")), &user, false).action);
+    }
+
+    #[test]
+    fn detects_duplicate_poll() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let poll = |id: &str, question: &str| {
+            json!({
+                "poll": {
+                    "id": id,
+                    "question": question,
+                    "options": [{ "text": "Pizza", "voter_count": 0 }, { "text": "Pasta", "voter_count": 0 }],
+                    "total_voter_count": 0,
+                    "is_closed": false,
+                    "is_anonymous": true,
+                    "type": "regular",
+                    "allows_multiple_answers": false
+                }
+            })
+        };
+        assert!(!detect_duplicates(db.clone(), &message(10, poll("1", "Pizza or pasta?")), &user, false).action);
+        assert!(!detect_duplicates(db.clone(), &message(11, poll("2", "Pasta or pizza?")), &user, false).action);
+        let status = detect_duplicates(db.clone(), &message(12, poll("3", "pizza or pasta? ")), &user, false);
+        assert!(status.action);
+        assert!(status.text.contains("https://t.me/c/1592783264/10"));
+
+        let stored = &db.message_media(CHAT_ID, 10, false)[0];
+        assert_eq!(stored.file_type, "poll");
+        let payload: Payload = serde_json::from_str(&stored.file_id).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Poll {
+                question: String::from("Pizza or pasta?"),
+                options: vec![String::from("Pizza"), String::from("Pasta")]
+            }
+        );
+    }
+
+    #[test]
+    fn detects_forwarded_posts() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let channel_post = |post_id: i32| {
+            json!({
+                "text": "Sin enlaces",
+                "forward_from_chat": { "id": -1001234567890i64, "type": "channel", "title": "News", "username": "news" },
+                "forward_from_message_id": post_id,
+                "forward_date": 1633000000
+            })
+        };
+        assert!(!detect_duplicates(db.clone(), &message(10, channel_post(5)), &user, false).action);
+        assert!(!detect_duplicates(db.clone(), &message(11, channel_post(6)), &user, false).action);
+        let status = detect_duplicates(db.clone(), &message(12, channel_post(5)), &user, false);
+        assert!(status.action);
+        assert!(status.text.contains("https://t.me/c/1592783264/10"));
+        // the same text, not forwarded, is not a duplicate
+        assert!(!detect_duplicates(db.clone(), &message(13, json!({ "text": "Sin enlaces" })), &user, false).action);
+
+        let hidden = |date: i64| json!({ "text": "Hola", "forward_sender_name": "Connor", "forward_date": date });
+        assert!(!detect_duplicates(db.clone(), &message(14, hidden(1633000000)), &user, false).action);
+        assert!(!detect_duplicates(db.clone(), &message(15, hidden(1633000001)), &user, false).action);
+        assert!(detect_duplicates(db.clone(), &message(16, hidden(1633000000)), &user, false).action);
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().count, 2);
+
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.file_types.retain(|t| *t != FileType::Forward);
+        db.insert_config(config);
+        assert!(!detect_duplicates(db.clone(), &message(17, channel_post(6)), &user, false).action);
+    }
+
+    #[test]
+    fn detects_similar_texts() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let wall = "Compro oro y plata al mejor precio, pago al contado y sin esperas, \
+            escribidme por privado y os atiendo hoy mismo en el centro de la ciudad";
+        assert!(!detect_duplicates(db.clone(), &message(10, json!({ "text": wall })), &user, false).action);
+
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.text_min_length = 100;
+        db.insert_config(config);
+        assert!(!detect_duplicates(db.clone(), &message(11, json!({ "text": wall })), &user, false).action);
+        let reposted = wall.to_uppercase().replace(", ", ",\n\n");
+        let status = detect_duplicates(db.clone(), &message(12, json!({ "text": reposted })), &user, false);
+        assert!(status.action);
+        assert!(status.text.contains("https://t.me/c/1592783264/11"));
+        assert_eq!(db.message_media(CHAT_ID, 11, false)[0].file_type, "text");
+
+        // short texts and texts with urls are left alone
+        assert!(!detect_duplicates(db.clone(), &message(13, json!({ "text": "hola" })), &user, false).action);
+        assert!(!detect_duplicates(db.clone(), &message(14, json!({ "text": "hola" })), &user, false).action);
+        let linked = format!("{} {}", wall, T1);
+        assert!(!detect_duplicates(db.clone(), &message(15, json!({ "text": linked })), &user, false).action);
+    }
+
+    #[test]
+    fn custom_notice() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.notice = Some(String::from("{user}: {type} ya compartido en {original_link}"));
+        db.insert_config(config);
+
+        detect_duplicates(db.clone(), &message(10, json!({ "text": T1 })), &user, false);
+        let status = detect_duplicates(db.clone(), &message(11, json!({ "text": T1 })), &user, false);
+        assert!(status.action);
+        assert_eq!(status.text, "User 1072037897: url ya compartido en https://t.me/c/1592783264/10");
+    }
+
+    #[test]
+    fn basic_group_replies_to_original() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let group = json!({ "id": -592783264, "type": "group", "title": "Highlander" });
+
+        detect_duplicates(db.clone(), &message(10, json!({ "text": T1, "chat": group })), &user, false);
+        let status = detect_duplicates(db.clone(), &message(11, json!({ "text": T1, "chat": group })), &user, false);
+        assert!(status.action);
+        assert_eq!(status.reply_to, Some(10));
+        assert!(!status.text.contains("t.me"));
+
+        detect_duplicates(db.clone(), &message(12, json!({ "text": T3 })), &user, false);
+        let status = detect_duplicates(db.clone(), &message(13, json!({ "text": T3 })), &user, false);
+        assert_eq!(status.reply_to, None);
+    }
+
+    #[test]
+    fn keeps_message_with_one_new_url() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        detect_duplicates(db.clone(), &message(10, json!({ "text": T1 })), &user, false);
+        let status = detect_duplicates(db.clone(), &message(11, json!({ "text": T2 })), &user, false);
+        assert!(!status.action);
+        assert!(status.text.contains("DUPLICATED"));
+    }
+
+    #[test]
+    fn detects_duplicate_photo() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        let status = detect_duplicates(db.clone(), &message(10, photo("AQADiq4xG--XSVd4")), &user, false);
+        assert!(!status.action);
+        let status = detect_duplicates(db.clone(), &message(11, photo("AQADiq4xG--XSQr3")), &user, false);
+        assert!(!status.action);
+        let status = detect_duplicates(db.clone(), &message(12, photo("AQADiq4xG--XSVd4")), &user, false);
+        assert!(status.action);
+        assert_eq!(db.last_media_duplicated(CHAT_ID, 5, false).len(), 1);
+        detect_duplicates(db.clone(), &message(13, photo("AQADiq4xG--XSQr3")), &user, false);
+        assert_eq!(db.get_offender(CHAT_ID, USER_ID).unwrap().count, 2);
+    }
+
+    #[test]
+    fn allowed_user_is_not_flagged() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        db.insert_allowed(CHAT_ID, USER_ID);
+
+        detect_duplicates(db.clone(), &message(10, photo("AQADiq4xG--XSVd4")), &user, false);
+        let status = detect_duplicates(db.clone(), &message(11, photo("AQADiq4xG--XSVd4")), &user, false);
+        assert!(!status.action);
+        assert!(!status.respond);
+        assert!(db.get_offender(CHAT_ID, USER_ID).is_none());
+        assert!(db.list_audit(CHAT_ID, None, 0).is_empty());
+    }
+
+    #[test]
+    fn admin_duplicate_is_not_counted() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        detect_duplicates(db.clone(), &message(10, photo("AQADiq4xG--XSVd4")), &user, true);
+        let status = detect_duplicates(db.clone(), &message(11, photo("AQADiq4xG--XSVd4")), &user, true);
+        assert!(!status.action);
+        assert!(db.get_offender(CHAT_ID, USER_ID).is_none());
+        assert!(db.list_audit(CHAT_ID, None, 0).is_empty());
+    }
+
+    #[test]
+    fn expired_media_is_not_duplicate() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let mut config = db.get_config(CHAT_ID);
+        config.window = -1;
+        db.insert_config(config);
+
+        detect_duplicates(db.clone(), &message(10, photo("AQADiq4xG--XSVd4")), &user, false);
+        let status = detect_duplicates(db.clone(), &message(11, photo("AQADiq4xG--XSVd4")), &user, false);
+        assert!(!status.action);
+    }
+
+    #[test]
+    fn detects_duplicate_canonical_url() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+
+        let first = message(10, json!({ "text": "mira https://youtu.be/GCI0NMgVfPk" }));
+        assert!(!detect_duplicates(db.clone(), &first, &user, false).action);
+        let second = message(11, json!({ "text": "https://www.youtube.com/watch?v=GCI0NMgVfPk&feature=share&utm_source=tg" }));
+        assert!(detect_duplicates(db.clone(), &second, &user, false).action);
+    }
+
+    #[test]
+    fn detects_duplicate_in_network() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let user = conformance::user(USER_ID);
+        let sibling: i64 = -1001192585346;
+        db.insert_item(conformance::sdo(sibling, 77, "photo", "AQADiq4xG--XSVd4"), true);
+
+        let status = detect_duplicates(db.clone(), &message(10, photo("AQADiq4xG--XSVd4")), &user, false);
+        assert!(!status.action);
+
+        let mut config = db.get_config(CHAT_ID);
+        config.network = vec![sibling];
+        db.insert_config(config);
+        db.insert_item(conformance::sdo(sibling, 78, "photo", "AQADiq4xG--XSQr3"), true);
+        let status = detect_duplicates(db.clone(), &message(11, photo("AQADiq4xG--XSQr3")), &user, false);
+        assert!(status.action);
+        assert!(status.text.contains("https://t.me/c/1192585346/78"));
+    }
 }
@@ -0,0 +1,85 @@
+/// Words per shingle, reordered sentences share most shingles while unrelated texts don't
+const SHINGLE_WORDS: usize = 3;
+
+/// Lowercase words separated by single spaces, so spacing and case changes don't count
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Simhash of the word shingles of a normalized text: similar texts differ in few bits
+pub fn simhash(normalized: &str) -> u64 {
+    let words = normalized.split(' ').collect::<Vec<_>>();
+    let mut weights = [0i32; 64];
+    for shingle in words.windows(SHINGLE_WORDS.min(words.len())) {
+        let hash = fnv1a(shingle.join(" ").as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
+
+/// Bits two simhashes may differ in to be `similarity` percent alike
+pub fn max_distance(similarity: u32) -> u32 {
+    (100 - similarity.min(100)) * 64 / 100
+}
+
+/// Unique id texts are stored under, similar texts share the one of the first seen
+pub fn unique_id(hash: u64) -> String {
+    format!("text:{:016x}", hash)
+}
+
+/// Stored ids must not change between builds, unlike those of `DefaultHasher`
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{max_distance, normalize, simhash};
+    use crate::phash::hamming;
+
+    const ANNOUNCEMENT: &str = "Este sabado a las 18:00 quedamos en la plaza mayor para la \
+        asamblea mensual del grupo, traed propuestas para las actividades del proximo trimestre \
+        y no olvideis confirmar asistencia respondiendo a este mensaje";
+
+    #[test]
+    fn normalizes_spacing_and_case() {
+        assert_eq!(normalize("  Hola\n\nMUNDO \t cruel "), "hola mundo cruel");
+        let shouted = ANNOUNCEMENT.to_uppercase().replace(' ', "  ");
+        assert_eq!(simhash(&normalize(ANNOUNCEMENT)), simhash(&normalize(&shouted)));
+    }
+
+    #[test]
+    fn similar_texts_are_close() {
+        let original = simhash(&normalize(ANNOUNCEMENT));
+        let edited = simhash(&normalize(&ANNOUNCEMENT.replace("18:00", "19:00")));
+        let other = simhash(&normalize(
+            "Se vende bicicleta de montaña en buen estado, ruedas nuevas y frenos de disco, \
+             interesados escribid por privado y os paso fotos y precio",
+        ));
+        assert!(hamming(original, edited) < hamming(original, other));
+        assert!(hamming(original, other) > max_distance(80));
+    }
+
+    #[test]
+    fn similarity_to_distance() {
+        assert_eq!(max_distance(100), 0);
+        assert_eq!(max_distance(90), 6);
+        assert_eq!(max_distance(150), 0);
+        assert_eq!(max_distance(0), 64);
+    }
+}
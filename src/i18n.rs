@@ -0,0 +1,386 @@
+//! Message catalogues for every reply the bot sends.
+//!
+//! Entries are looked up by key in the chat's locale, `{name}` placeholders are
+//! filled in by the `t!` macro. Every catalogue must define the same keys.
+
+use std::fmt::Display;
+
+use teloxide::utils::command::BotCommand;
+
+use crate::commands::Command;
+use crate::models::Locale;
+
+const ES: &[(&str, &str)] = &[
+    ("admin.only", "Lamentablemente, este comando es solo para usuarios Admin"),
+    ("results.empty", "No se encontraron resultados"),
+    ("error.store", "No se pudo guardar el cambio, ver logs"),
+    ("error.remove", "No se pudo eliminar, ver logs"),
+    ("duplicate", "Mensaje Duplicado: {kind} ya se ha compartido en los ultimos {days} dias.\nVer mensaje original: {link}"),
+    ("duplicate.network", "Mensaje Duplicado: {kind} ya se ha compartido en otro grupo en los ultimos {days} dias.\nVer mensaje original: {link}"),
+    ("duplicate.photo", "Mensaje Casi Duplicado: una foto muy similar ya se ha compartido en los ultimos {days} dias.\nVer mensaje original: {link}"),
+    ("age.days", "hace {n} dias"),
+    ("age.hours", "hace {n} horas"),
+    ("age.minutes", "hace {n} minutos"),
+    ("notice.sample_user", "Connor MacLeod"),
+    ("notice.saved", "Aviso de duplicados guardado, puede verlo con /previewnotice"),
+    ("notice.reset", "Se usara el aviso de duplicados por defecto"),
+    ("notice.too_long", "El aviso no puede superar {max} caracteres"),
+    ("notice.unbalanced", "Hay llaves sin cerrar en el aviso"),
+    ("notice.unknown", "Marcador desconocido {name}, use: {available}"),
+    ("sanction.mute", "\nAviso {strikes}: silenciado durante {minutes} minutos."),
+    ("sanction.ban", "\nAviso {strikes}: expulsado del grupo."),
+    ("reason.duplicates", "duplicados repetidos"),
+    ("reason.inactive", "inactivos durante {days} dias"),
+    ("media.caption", "Parte del contenido {id}"),
+    ("participants.requested", "Comando ejecutado, ahora puede ejecutar /findinterusers"),
+    ("line.interuser", "Usuario: {user_id}, Grupo: {chat_id}, Nombre: {name} presente en {count} grupos"),
+    ("line.usergroup", "Grupo: {chat_id}, Nombre del grupo: {name}"),
+    ("line.inactive", "Usuario: {user_id}, Nombre: {name}, Ultima actividad: {date}"),
+    ("line.offender", "Usuario: {user_id}, Nombre: {name}, {count} duplicados, ultimo: {link}"),
+    ("window.set", "El contenido sera unico durante {days} dias"),
+    ("window.range", "La ventana debe ser de entre 1 y {max} dias"),
+    ("link.reply", "el mensaje al que responde este aviso"),
+    ("link.message", "mensaje {id}"),
+    ("warning_ttl.set", "Los avisos de duplicados se borraran tras {secs} segundos"),
+    ("warning_ttl.off", "Los avisos de duplicados no se borraran"),
+    ("warning_ttl.range", "El tiempo debe estar entre 0 y {max} segundos"),
+    ("distance.range", "La distancia debe estar entre 0 y 32"),
+    ("distance.disabled", "Deteccion de fotos casi duplicadas desactivada"),
+    ("distance.set", "Las fotos a {bits} bits o menos se marcaran como duplicadas"),
+    ("subscribe.self", "Un chat no puede suscribirse a si mismo"),
+    ("subscribe.unknown", "El chat {id} no esta gestionado por highlander"),
+    ("subscribe.already", "Ya esta suscrito a {id}"),
+    ("subscribe.not_admin", "Solo un admin de {id} puede suscribir este chat a su contenido"),
+    ("subscribe.done", "El contenido compartido en {id} se marcara como duplicado"),
+    ("unsubscribe.done", "Suscripcion a {id} cancelada"),
+    ("policy.warn", "Los duplicados reciben un aviso"),
+    ("policy.delete", "Los duplicados se borran"),
+    ("policy.mute", "Los duplicados se borran y su autor queda silenciado durante {minutes} minutos"),
+    ("policy.escalate", "Los duplicados reciben un aviso, su autor queda silenciado durante {minutes} minutos tras {mute_after} avisos y expulsado tras {ban_after}"),
+    ("policy.unknown", "Politica desconocida {policy}, use warn, delete, mute o escalate"),
+    ("policy.nan", "{value} no es un numero"),
+    ("policy.min", "Los valores deben ser al menos 1"),
+    ("policy.order", "Silenciar debe ir antes que expulsar"),
+    ("policy.max_mute", "Se puede silenciar como mucho {max} minutos"),
+    ("policy.args", "Argumentos inesperados para {policy}"),
+    ("allow.done", "El usuario {id} nunca sera marcado ni expulsado en este chat"),
+    ("allow.missing", "El usuario {id} no esta en la lista de permitidos"),
+    ("allow.removed", "El usuario {id} ya no esta en la lista de permitidos"),
+    ("audit.empty", "No hay acciones de moderacion registradas"),
+    ("audit.usage", "Uso: /auditlog [n], /auditlog user <id> o /auditlog export"),
+    ("audit.line", "{date} {action} Usuario: {user_id}, por {actor} ({rule}){message}"),
+    ("audit.rule", "regla"),
+    ("audit.admin", "admin {id}"),
+    ("audit.message", ", mensaje: {link}"),
+    ("language.set", "Las respuestas en este chat son en español"),
+    ("language.unknown", "Idioma desconocido {locale}, use uno de: {available}"),
+    ("reply_mode.link", "Los avisos enlazan el mensaje original"),
+    ("reply_mode.reply", "Los avisos responden al mensaje original mientras siga en la ventana"),
+    ("reply_mode.quote", "Los avisos responden al mensaje original y lo citan al autor del duplicado mientras siga en la ventana"),
+    ("reply_mode.unknown", "Modo desconocido {mode}, use uno de: {available}"),
+    ("album.partial", "{duplicates} de {total} elementos del album ya se habian compartido.\n"),
+    ("album_mode.items", "De los albumes se borraran solo los elementos repetidos"),
+    ("album_mode.whole", "Los albumes con elementos repetidos se borraran enteros"),
+    ("album_mode.unknown", "Modo desconocido {mode}, use uno de: {available}"),
+    ("file_types.list", "Se buscan duplicados de: {file_types}"),
+    ("file_types.none", "No se buscan duplicados"),
+    ("file_types.unknown", "Tipo desconocido {file_type}, use uno de: {available}"),
+    ("text_check.set", "Los textos sin enlaces de {length} caracteres o mas se marcaran cuando sean un {similarity}% similares"),
+    ("text_check.off", "Los textos sin enlaces no se comprueban"),
+    ("text_check.usage", "Use /settextcheck <longitud minima> [similitud de 1 a 100]"),
+    ("page.footer", "Pagina {n}/{total}"),
+    ("page.previous", "« Anterior"),
+    ("page.next", "Siguiente »"),
+    ("page.expired", "Este listado ha caducado, ejecute el comando de nuevo"),
+    ("plan.summary", "Plan de expulsion: {count} usuarios {reason}, confirme en {minutes} minutos"),
+    ("plan.line", "{n}. Usuario: {user_id}, Nombre: {name}, Grupo: {chat_id}, Ultima actividad: {date}"),
+    ("plan.confirm", "Confirmar"),
+    ("plan.cancel", "Cancelar"),
+    ("plan.empty", "No hay usuarios inactivos que expulsar"),
+    ("plan.not_owner", "Solo el admin que pidio el plan puede confirmarlo"),
+    ("plan.missing", "Este plan de expulsion ya no existe"),
+    ("plan.expired", "Este plan de expulsion ha caducado, ejecute el comando de nuevo"),
+    ("plan.cancelled", "Plan de expulsion cancelado"),
+    ("plan.report", "{banned} usuarios expulsados, {failed} fallidos"),
+    ("plan.failed", "Usuario: {user_id}, Grupo: {chat_id}: {error}"),
+    ("help.header", "Estos son los comandos disponibles:"),
+    ("help.help", "muestra este texto"),
+    ("help.findinterusers", "busca usuarios presentes en varios grupos"),
+    ("help.lastmediastored", "muestra los ultimos n contenidos guardados"),
+    ("help.lasturlstored", "muestra las ultimas n urls guardadas"),
+    ("help.lastduplicatemedia", "muestra los ultimos n contenidos duplicados"),
+    ("help.lastduplicateurls", "muestra las ultimas n urls duplicadas"),
+    ("help.listusergroups", "lista los grupos de un usuario"),
+    ("help.getchatparticipants", "descarga los participantes de todos los grupos"),
+    ("help.findinactiveusers", "busca usuarios inactivos durante mas de n dias"),
+    ("help.baninactiveusers", "expulsa a los usuarios inactivos durante mas de n dias"),
+    ("help.listmedia", "lista n contenidos guardados"),
+    ("help.listusers", "lista n usuarios guardados"),
+    ("help.listduplicates", "lista n duplicados guardados"),
+    ("help.getchatids", "muestra los ids de los chats gestionados por highlander"),
+    ("help.setwindow", "define durante cuantos dias el contenido es unico en este chat"),
+    ("help.getwindow", "muestra durante cuantos dias el contenido es unico en este chat"),
+    ("help.setphotodistance", "marca fotos que difieren en n bits o menos de su hash perceptual, 0 lo desactiva"),
+    ("help.subscribe", "marca tambien el contenido ya compartido en el chat indicado, requiere ser admin alli tambien"),
+    ("help.unsubscribe", "deja de marcar el contenido compartido en el chat indicado"),
+    ("help.network", "lista los chats a los que esta suscrito este chat"),
+    ("help.setpolicy", "que hacer con los duplicados: warn, delete, mute [minutos] o escalate [silenciar_tras] [expulsar_tras]"),
+    ("help.getpolicy", "muestra que ocurre con los duplicados en este chat"),
+    ("help.offenders", "lista los n usuarios con mas duplicados"),
+    ("help.allowuser", "nunca marca ni expulsa al usuario indicado en este chat"),
+    ("help.disallowuser", "quita al usuario indicado de la lista de permitidos del chat"),
+    ("help.listallowed", "lista los usuarios que nunca se marcan ni expulsan en este chat"),
+    ("help.auditlog", "historial de moderacion: /auditlog [n], /auditlog user <id> o /auditlog export"),
+    ("help.setlanguage", "idioma de las respuestas en este chat: es o en"),
+    ("help.getlanguage", "muestra el idioma de las respuestas en este chat"),
+    ("help.setnotice", "aviso de duplicados con {user}, {type}, {original_link}, {age} y {window}, vacio para el de por defecto"),
+    ("help.previewnotice", "muestra el aviso de duplicados con un ejemplo"),
+    ("help.setwarningttl", "borra los avisos de duplicados tras n segundos, 0 los conserva"),
+    ("help.setreplymode", "como señalan los avisos el original: link, reply o quote"),
+    ("help.getreplymode", "muestra como señalan los avisos el mensaje original"),
+    ("help.setalbummode", "que se borra de un album con repetidos: items o whole"),
+    ("help.track", "busca duplicados del tipo dado, sin tipo lista los que se buscan"),
+    ("help.untrack", "deja de buscar duplicados del tipo dado"),
+    ("help.settextcheck", "marca textos de al menos n caracteres [similares en p por ciento], 0 lo desactiva"),
+];
+
+const EN: &[(&str, &str)] = &[
+    ("admin.only", "Sorry, this command is for admins only"),
+    ("results.empty", "No results found"),
+    ("error.store", "Could not store the change, see logs"),
+    ("error.remove", "Could not remove it, see logs"),
+    ("duplicate", "Duplicate message: {kind} already shared in the last {days} days.\nSee the original message: {link}"),
+    ("duplicate.network", "Duplicate message: {kind} already shared on another group in the last {days} days.\nSee the original message: {link}"),
+    ("duplicate.photo", "Near duplicate message: a very similar photo was already shared in the last {days} days.\nSee the original message: {link}"),
+    ("age.days", "{n} days ago"),
+    ("age.hours", "{n} hours ago"),
+    ("age.minutes", "{n} minutes ago"),
+    ("notice.sample_user", "Connor MacLeod"),
+    ("notice.saved", "Duplicate notice saved, see it with /previewnotice"),
+    ("notice.reset", "The default duplicate notice will be used"),
+    ("notice.too_long", "The notice can't be longer than {max} characters"),
+    ("notice.unbalanced", "The notice has unbalanced braces"),
+    ("notice.unknown", "Unknown placeholder {name}, use: {available}"),
+    ("sanction.mute", "\nStrike {strikes}: muted for {minutes} minutes."),
+    ("sanction.ban", "\nStrike {strikes}: banned from the group."),
+    ("reason.duplicates", "repeated duplicates"),
+    ("reason.inactive", "inactive for {days} days"),
+    ("media.caption", "Part of media {id}"),
+    ("participants.requested", "Command executed, you can now run /findinterusers"),
+    ("line.interuser", "UserId: {user_id}, GroupId: {chat_id}, UserName: {name} found in {count} groups"),
+    ("line.usergroup", "GroupId: {chat_id}, GroupName: {name}"),
+    ("line.inactive", "UserId: {user_id}, UserName: {name}, Last Update: {date}"),
+    ("line.offender", "UserId: {user_id}, UserName: {name}, {count} duplicates, last: {link}"),
+    ("window.set", "Media will be unique for {days} days"),
+    ("window.range", "The window must be between 1 and {max} days"),
+    ("link.reply", "the message this notice replies to"),
+    ("link.message", "message {id}"),
+    ("warning_ttl.set", "Duplicate warnings will be deleted after {secs} seconds"),
+    ("warning_ttl.off", "Duplicate warnings will be kept"),
+    ("warning_ttl.range", "The timeout must be between 0 and {max} seconds"),
+    ("distance.range", "The distance must be between 0 and 32"),
+    ("distance.disabled", "Near duplicate photo detection disabled"),
+    ("distance.set", "Photos within {bits} bits will be flagged as duplicates"),
+    ("subscribe.self", "A chat can't subscribe to itself"),
+    ("subscribe.unknown", "Chat {id} is not managed by highlander"),
+    ("subscribe.already", "Already subscribed to {id}"),
+    ("subscribe.not_admin", "Only an admin of {id} can subscribe this chat to its media"),
+    ("subscribe.done", "Media shared on {id} will be flagged as duplicate"),
+    ("unsubscribe.done", "Unsubscribed from {id}"),
+    ("policy.warn", "Duplicates are answered with a warning"),
+    ("policy.delete", "Duplicates are deleted"),
+    ("policy.mute", "Duplicates are deleted and the sender muted for {minutes} minutes"),
+    ("policy.escalate", "Duplicates are answered with a warning, the sender is muted for {minutes} minutes after {mute_after} strikes and banned after {ban_after}"),
+    ("policy.unknown", "Unknown policy {policy}, use warn, delete, mute or escalate"),
+    ("policy.nan", "{value} is not a number"),
+    ("policy.min", "Values must be at least 1"),
+    ("policy.order", "Muting must come before banning"),
+    ("policy.max_mute", "Muting lasts at most {max} minutes"),
+    ("policy.args", "Unexpected arguments for {policy}"),
+    ("allow.done", "User {id} will never be flagged nor banned in this chat"),
+    ("allow.missing", "User {id} is not on the allowlist"),
+    ("allow.removed", "User {id} removed from the allowlist"),
+    ("audit.empty", "No moderation actions recorded"),
+    ("audit.usage", "Usage: /auditlog [n], /auditlog user <id> or /auditlog export"),
+    ("audit.line", "{date} {action} UserId: {user_id}, by {actor} ({rule}){message}"),
+    ("audit.rule", "rule"),
+    ("audit.admin", "admin {id}"),
+    ("audit.message", ", message: {link}"),
+    ("language.set", "Replies in this chat are in English"),
+    ("language.unknown", "Unknown language {locale}, use one of: {available}"),
+    ("reply_mode.link", "Notices link the original message"),
+    ("reply_mode.reply", "Notices reply to the original message while it is within the window"),
+    ("reply_mode.quote", "Notices reply to the original message and quote it to the poster of the duplicate while it is within the window"),
+    ("reply_mode.unknown", "Unknown mode {mode}, use one of: {available}"),
+    ("album.partial", "{duplicates} of {total} album items were already shared.\n"),
+    ("album_mode.items", "Only the repeated items of albums will be deleted"),
+    ("album_mode.whole", "Albums with repeated items will be deleted whole"),
+    ("album_mode.unknown", "Unknown album mode {mode}, use one of: {available}"),
+    ("file_types.list", "Duplicates are checked for: {file_types}"),
+    ("file_types.none", "No duplicates are checked"),
+    ("file_types.unknown", "Unknown kind {file_type}, use one of: {available}"),
+    ("text_check.set", "Texts without links of {length} characters or more are flagged when {similarity}% similar"),
+    ("text_check.off", "Texts without links are not checked"),
+    ("text_check.usage", "Use /settextcheck <min length> [similarity from 1 to 100]"),
+    ("page.footer", "Page {n}/{total}"),
+    ("page.previous", "« Previous"),
+    ("page.next", "Next »"),
+    ("page.expired", "This listing expired, run the command again"),
+    ("plan.summary", "Ban plan: {count} users {reason}, confirm within {minutes} minutes"),
+    ("plan.line", "{n}. UserId: {user_id}, UserName: {name}, GroupId: {chat_id}, Last Update: {date}"),
+    ("plan.confirm", "Confirm"),
+    ("plan.cancel", "Cancel"),
+    ("plan.empty", "No inactive users to ban"),
+    ("plan.not_owner", "Only the admin who requested the plan can confirm it"),
+    ("plan.missing", "This ban plan no longer exists"),
+    ("plan.expired", "This ban plan expired, run the command again"),
+    ("plan.cancelled", "Ban plan cancelled"),
+    ("plan.report", "Banned {banned} users, {failed} failed"),
+    ("plan.failed", "UserId: {user_id}, GroupId: {chat_id}: {error}"),
+    ("help.header", "These commands are supported:"),
+    ("help.help", "display this text"),
+    ("help.findinterusers", "find users present in multiple groups"),
+    ("help.lastmediastored", "retrieves the last n stored media"),
+    ("help.lasturlstored", "retrieves the last n stored urls"),
+    ("help.lastduplicatemedia", "retrieves the last n duplicate media found"),
+    ("help.lastduplicateurls", "retrieves the last n duplicate URLs found"),
+    ("help.listusergroups", "list a user's groups"),
+    ("help.getchatparticipants", "fetch the participants of every managed group"),
+    ("help.findinactiveusers", "find all users who've remained inactive over n days"),
+    ("help.baninactiveusers", "ban all users who've remained inactive over n days"),
+    ("help.listmedia", "list n stored media"),
+    ("help.listusers", "list n stored users"),
+    ("help.listduplicates", "list n stored duplicates"),
+    ("help.getchatids", "Get the Ids of all chats managed by highlander"),
+    ("help.setwindow", "set for how many days media remains unique in this chat"),
+    ("help.getwindow", "show for how many days media remains unique in this chat"),
+    ("help.setphotodistance", "flag photos differing in at most n bits of their perceptual hash, 0 disables it"),
+    ("help.subscribe", "also flag media already shared on the given chat, needs admin rights there too"),
+    ("help.unsubscribe", "stop flagging media shared on the given chat"),
+    ("help.network", "list the chats this chat is subscribed to"),
+    ("help.setpolicy", "what to do on duplicates: warn, delete, mute [minutes] or escalate [mute_after] [ban_after]"),
+    ("help.getpolicy", "show what happens on duplicates in this chat"),
+    ("help.offenders", "list the n users who posted the most duplicates"),
+    ("help.allowuser", "never flag nor ban the given user id in this chat"),
+    ("help.disallowuser", "remove the given user id from this chat's allowlist"),
+    ("help.listallowed", "list the user ids never flagged nor banned in this chat"),
+    ("help.auditlog", "moderation history: /auditlog [n], /auditlog user <id> or /auditlog export"),
+    ("help.setlanguage", "language of the replies in this chat: es or en"),
+    ("help.getlanguage", "show the language of the replies in this chat"),
+    ("help.setnotice", "duplicate notice using {user}, {type}, {original_link}, {age} and {window}, empty for the default"),
+    ("help.previewnotice", "show the duplicate notice on a sample"),
+    ("help.setwarningttl", "delete duplicate warnings after n seconds, 0 keeps them"),
+    ("help.setreplymode", "how notices point at the original: link, reply or quote"),
+    ("help.getreplymode", "show how notices point at the original message"),
+    ("help.setalbummode", "what is deleted from an album with repeated items: items or whole"),
+    ("help.track", "check duplicates of the given kind, without one lists the checked kinds"),
+    ("help.untrack", "stop checking duplicates of the given kind"),
+    ("help.settextcheck", "flag texts of at least n characters [similar in p percent], 0 disables it"),
+];
+
+fn catalogue(locale: Locale) -> &'static [(&'static str, &'static str)] {
+    match locale {
+        Locale::Es => ES,
+        Locale::En => EN,
+    }
+}
+
+fn lookup(locale: Locale, key: &str) -> Option<&'static str> {
+    catalogue(locale)
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, text)| *text)
+}
+
+/// The entry for `key` with its placeholders filled in, the key itself when missing
+pub fn tr(locale: Locale, key: &str, args: &[(&str, &dyn Display)]) -> String {
+    match lookup(locale, key) {
+        Some(text) => args.iter().fold(String::from(text), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), &value.to_string())
+        }),
+        None => {
+            log::error!("tr: no {} entry for {}", locale, key);
+            String::from(key)
+        }
+    }
+}
+
+/// Command names as typed, without the slash
+fn command_names() -> Vec<String> {
+    Command::descriptions()
+        .lines()
+        .filter_map(|line| line.strip_prefix('/'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// The command list with descriptions in the chat's language
+pub fn help(locale: Locale) -> String {
+    let mut lines = vec![tr(locale, "help.header", &[])];
+    lines.extend(command_names().iter().map(|name| {
+        format!("/{} - {}", name, tr(locale, &format!("help.{}", name), &[]))
+    }));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{catalogue, command_names, help, lookup, tr};
+    use crate::i18n;
+    use crate::models::Locale;
+    use std::collections::BTreeSet;
+
+    fn keys(locale: Locale) -> BTreeSet<&'static str> {
+        catalogue(locale).iter().map(|(k, _)| *k).collect()
+    }
+
+    fn placeholders(text: &str) -> BTreeSet<String> {
+        text.split('{')
+            .skip(1)
+            .filter_map(|part| part.split('}').next())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn catalogues_define_the_same_keys() {
+        for locale in Locale::ALL.iter() {
+            assert_eq!(keys(*locale).len(), catalogue(*locale).len(), "repeated keys in {}", locale);
+            assert_eq!(keys(*locale), keys(Locale::En), "keys of {} and en differ", locale);
+        }
+    }
+
+    #[test]
+    fn entries_use_the_same_placeholders() {
+        for locale in Locale::ALL.iter() {
+            for (key, text) in catalogue(*locale) {
+                let english = lookup(Locale::En, key).unwrap();
+                assert_eq!(placeholders(text), placeholders(english), "{} in {}", key, locale);
+            }
+        }
+    }
+
+    #[test]
+    fn every_command_is_described() {
+        let names = command_names();
+        assert!(names.contains(&String::from("auditlog")));
+        for locale in Locale::ALL.iter() {
+            for name in &names {
+                let key = format!("help.{}", name);
+                assert!(lookup(*locale, &key).is_some(), "missing {} in {}", key, locale);
+            }
+            assert_eq!(help(*locale).lines().count(), names.len() + 1);
+        }
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        assert_eq!(t!(Locale::En, "window.set", days = 4), "Media will be unique for 4 days");
+        assert_eq!(
+            tr(Locale::Es, "subscribe.unknown", &[("id", &-1001592783264i64)]),
+            "El chat -1001592783264 no esta gestionado por highlander"
+        );
+        assert_eq!(tr(Locale::Es, "no.such.key", &[]), "no.such.key");
+    }
+}
@@ -0,0 +1,147 @@
+//! Binary keys for the rocksdb column families.
+//!
+//! Every chat scoped key is the chat id as 8 big-endian bytes (sign bit flipped so
+//! negative ids sort before positive ones), a 2 byte big-endian suffix length and the
+//! suffix itself. The 8 byte chat id is the prefix used by the prefix extractor.
+
+use std::convert::TryInto;
+
+pub const CHAT_PREFIX_LEN: usize = 8;
+const LEN_BYTES: usize = 2;
+const SIGN_BIT: u64 = 1 << 63;
+
+pub fn chat_prefix(chat_id: i64) -> [u8; CHAT_PREFIX_LEN] {
+    ((chat_id as u64) ^ SIGN_BIT).to_be_bytes()
+}
+
+fn chat_from_prefix(bytes: &[u8]) -> Option<i64> {
+    let prefix: [u8; CHAT_PREFIX_LEN] = bytes.get(..CHAT_PREFIX_LEN)?.try_into().ok()?;
+    Some((u64::from_be_bytes(prefix) ^ SIGN_BIT) as i64)
+}
+
+fn length_prefixed(suffix: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(LEN_BYTES + suffix.len());
+    k.extend_from_slice(&(suffix.len() as u16).to_be_bytes());
+    k.extend_from_slice(suffix);
+    k
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub chat_id: i64,
+    pub suffix: Vec<u8>,
+}
+
+impl Key {
+    pub fn new(chat_id: i64, suffix: &[u8]) -> Self {
+        Self {
+            chat_id,
+            suffix: suffix.to_vec(),
+        }
+    }
+
+    pub fn with_str(chat_id: i64, suffix: &str) -> Self {
+        Key::new(chat_id, suffix.as_bytes())
+    }
+
+    pub fn with_id(chat_id: i64, id: i64) -> Self {
+        Key::new(chat_id, &id.to_be_bytes())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut k = chat_prefix(self.chat_id).to_vec();
+        k.extend(length_prefixed(&self.suffix));
+        k
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let chat_id = chat_from_prefix(bytes)?;
+        let len_bytes: [u8; LEN_BYTES] = bytes
+            .get(CHAT_PREFIX_LEN..CHAT_PREFIX_LEN + LEN_BYTES)?
+            .try_into()
+            .ok()?;
+        let suffix = &bytes[CHAT_PREFIX_LEN + LEN_BYTES..];
+        if suffix.len() != u16::from_be_bytes(len_bytes) as usize {
+            return None;
+        }
+        Some(Key::new(chat_id, suffix))
+    }
+
+    pub fn suffix_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.suffix).ok()
+    }
+
+    pub fn suffix_id(&self) -> Option<i64> {
+        let id: [u8; 8] = self.suffix.as_slice().try_into().ok()?;
+        Some(i64::from_be_bytes(id))
+    }
+}
+
+/// Media by unique id across chats: length-prefixed unique id followed by the chat id
+pub fn index_key(unique_id: &str, chat_id: i64) -> Vec<u8> {
+    let mut k = index_prefix(unique_id);
+    k.extend_from_slice(&chat_prefix(chat_id));
+    k
+}
+
+pub fn index_prefix(unique_id: &str) -> Vec<u8> {
+    length_prefixed(unique_id.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let keys = vec![
+            Key::with_str(-1001592783264, "AQADiq4xG--XSVd4"),
+            Key::with_str(-4242, "https://youtube.com/watch?v=GCI0NMgVfPk"),
+            Key::with_id(162726413, 1072037897),
+            Key::with_id(-1, -1),
+            Key::new(0, b""),
+        ];
+        for k in keys {
+            assert_eq!(Key::decode(&k.encode()), Some(k));
+        }
+    }
+
+    #[test]
+    fn suffixes() {
+        let k = Key::decode(&Key::with_id(-1001445478423, 1072037897).encode()).unwrap();
+        assert_eq!(k.chat_id, -1001445478423);
+        assert_eq!(k.suffix_id(), Some(1072037897));
+        let k = Key::decode(&Key::with_str(-4242, "AQADiq4xG--XSVd4").encode()).unwrap();
+        assert_eq!(k.suffix_str(), Some("AQADiq4xG--XSVd4"));
+        assert_eq!(k.suffix_id(), None);
+    }
+
+    #[test]
+    fn prefix_is_fixed_width() {
+        let short = Key::with_str(-4242, "a").encode();
+        let long = Key::with_str(-1001592783264, "a").encode();
+        assert_eq!(short.len(), long.len());
+        assert!(short.starts_with(&chat_prefix(-4242)));
+        assert!(!long.starts_with(&chat_prefix(-4242)));
+    }
+
+    #[test]
+    fn chat_ids_sort_numerically() {
+        let ids = vec![-1001592783264, -1001192585346, -4242, 0, 162726413];
+        let mut encoded = ids.iter().map(|id| chat_prefix(*id)).collect::<Vec<_>>();
+        encoded.sort();
+        let decoded = encoded
+            .iter()
+            .map(|p| chat_from_prefix(p).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, ids);
+    }
+
+    #[test]
+    fn rejects_string_keys() {
+        assert_eq!(Key::decode(b"-1001592783264_AQADiq4xG--XSVd4"), None);
+        assert_eq!(Key::decode(b"-1001445478423_1072037897"), None);
+        assert_eq!(Key::decode(b"config_-1001445478423"), None);
+        assert_eq!(Key::decode(b"1592783264"), None);
+    }
+}
@@ -0,0 +1,97 @@
+use teloxide::types::{Contact, Location, Poll};
+
+use crate::fingerprint::fnv1a;
+use crate::models::{FileType, Payload};
+
+pub fn contact(contact: &Contact) -> Payload {
+    Payload::Contact {
+        phone_number: contact.phone_number.clone(),
+        first_name: contact.first_name.clone(),
+        last_name: contact.last_name.clone(),
+    }
+}
+
+pub fn location(location: &Location) -> Payload {
+    Payload::Location {
+        latitude: location.latitude,
+        longitude: location.longitude,
+    }
+}
+
+pub fn poll(poll: &Poll) -> Payload {
+    Payload::Poll {
+        question: poll.question.clone(),
+        options: poll.options.iter().map(|option| option.text.clone()).collect(),
+    }
+}
+
+pub fn file_type(payload: &Payload) -> FileType {
+    match payload {
+        Payload::Contact { .. } => FileType::Contact,
+        Payload::Location { .. } => FileType::Location,
+        Payload::Poll { .. } => FileType::Poll,
+    }
+}
+
+/// Contacts by phone digits, locations rounded to about 10 meters and polls by a hash of
+/// their question and options, ignoring case and surrounding spaces
+pub fn unique_id(payload: &Payload) -> String {
+    match payload {
+        Payload::Contact { phone_number, .. } => {
+            let digits: String = phone_number.chars().filter(|c| c.is_ascii_digit()).collect();
+            format!("contact:{}", digits)
+        }
+        Payload::Location { latitude, longitude } => format!("location:{:.4},{:.4}", latitude, longitude),
+        Payload::Poll { question, options } => {
+            let text = std::iter::once(question)
+                .chain(options.iter())
+                .map(|s| s.trim().to_lowercase())
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("poll:{:016x}", fnv1a(text.as_bytes()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unique_id;
+    use crate::models::Payload;
+
+    fn poll(question: &str, options: &[&str]) -> Payload {
+        Payload::Poll {
+            question: question.into(),
+            options: options.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn contacts_by_phone() {
+        let contact = |phone: &str, name: &str| Payload::Contact {
+            phone_number: phone.into(),
+            first_name: name.into(),
+            last_name: None,
+        };
+        assert_eq!(unique_id(&contact("+34 600 11 22 33", "Connor")), "contact:34600112233");
+        assert_eq!(unique_id(&contact("+34600112233", "Duncan")), "contact:34600112233");
+    }
+
+    #[test]
+    fn nearby_locations() {
+        let here = Payload::Location { latitude: 40.416775, longitude: -3.703790 };
+        let close = Payload::Location { latitude: 40.416781, longitude: -3.703812 };
+        let far = Payload::Location { latitude: 40.4268, longitude: -3.703790 };
+        assert_eq!(unique_id(&here), "location:40.4168,-3.7038");
+        assert_eq!(unique_id(&here), unique_id(&close));
+        assert_ne!(unique_id(&here), unique_id(&far));
+    }
+
+    #[test]
+    fn polls_by_question_and_options() {
+        let a = poll("Pizza or pasta?", &["Pizza", "Pasta"]);
+        assert_eq!(unique_id(&a), unique_id(&poll(" pizza or pasta? ", &["PIZZA", "pasta"])));
+        assert_ne!(unique_id(&a), unique_id(&poll("Pizza or pasta?", &["Pasta", "Pizza"])));
+        assert_ne!(unique_id(&a), unique_id(&poll("Pizza or pasta?", &["Pizza", "Pasta", "Both"])));
+        assert!(unique_id(&a).starts_with("poll:"));
+    }
+}
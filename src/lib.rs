@@ -1,10 +1,26 @@
 #[macro_use]
 pub mod macros;
+pub mod canonical;
+pub mod cleanup;
 pub mod commands;
+pub mod albums;
 pub mod api_listener;
+pub mod audit;
+pub mod bans;
 pub mod duplicates;
+pub mod fingerprint;
+pub mod i18n;
+pub mod keys;
+pub mod kinds;
+pub mod links;
+pub mod memory_repo;
 pub mod models;
+pub mod notice;
+pub mod phash;
+pub mod policy;
+pub mod render;
 pub mod repository;
 pub mod time;
+pub mod urls;
 pub mod rocksdb;
-//pub mod sqlite_repo;
+pub mod sqlite_repo;
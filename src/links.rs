@@ -0,0 +1,140 @@
+use teloxide::types::{Chat, ChatKind, ChatPublic, PublicChatKind};
+
+use crate::i18n;
+use crate::models::Locale;
+
+/// How a notice points at the original message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Original {
+    Link(String),
+    /// No link exists, the notice is sent as a reply to this message instead
+    Reply(i32),
+    /// Neither a link nor a reply can reach it, e.g. a basic group of the network
+    Unreachable(i32),
+}
+
+/// `t.me/c/` link, only private supergroups and channels have one
+pub fn private_link(chat_id: i64, msg_id: i32) -> Option<String> {
+    let id = chat_id.to_string().strip_prefix("-100")?.parse::<i64>().ok()?;
+    Some(format!("https://t.me/c/{}/{}", id, msg_id))
+}
+
+/// Public chats are linked by username, supergroups and channels by id, basic groups and
+/// private chats can't be linked
+pub fn message_link(chat: &Chat, msg_id: i32) -> Option<String> {
+    match &chat.kind {
+        ChatKind::Public(ChatPublic { kind, .. }) => {
+            let username = match kind {
+                PublicChatKind::Channel(channel) => channel.username.as_ref(),
+                PublicChatKind::Supergroup(supergroup) => supergroup.username.as_ref(),
+                PublicChatKind::Group(_) => return None,
+            };
+            match username {
+                Some(username) => Some(format!("https://t.me/{}/{}", username, msg_id)),
+                None => private_link(chat.id, msg_id),
+            }
+        }
+        ChatKind::Private(_) => None,
+    }
+}
+
+/// Locates message `msg_id` of `chat_id`, seen from a message posted on `chat`
+pub fn locate(chat: &Chat, chat_id: i64, msg_id: i32) -> Original {
+    if chat_id == chat.id {
+        match message_link(chat, msg_id) {
+            Some(link) => Original::Link(link),
+            None => Original::Reply(msg_id),
+        }
+    } else {
+        match private_link(chat_id, msg_id) {
+            Some(link) => Original::Link(link),
+            None => Original::Unreachable(msg_id),
+        }
+    }
+}
+
+impl Original {
+    pub fn text(&self, locale: Locale) -> String {
+        match self {
+            Original::Link(link) => link.clone(),
+            Original::Reply(_) => t!(locale, "link.reply"),
+            Original::Unreachable(id) => t!(locale, "link.message", id = id),
+        }
+    }
+
+    pub fn reply_to(&self) -> Option<i32> {
+        match self {
+            Original::Reply(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// A message of a chat known only by id, for listings
+pub fn describe(locale: Locale, chat_id: i64, msg_id: i32) -> String {
+    private_link(chat_id, msg_id).unwrap_or_else(|| t!(locale, "link.message", id = msg_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, locate, message_link, private_link, Original};
+    use crate::models::Locale;
+    use serde_json::json;
+    use teloxide::types::Chat;
+
+    const SUPERGROUP_ID: i64 = -1001592783264;
+    const GROUP_ID: i64 = -592783264;
+
+    fn chat(value: serde_json::Value) -> Chat {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn private_supergroups() {
+        let supergroup = chat(json!({"id": SUPERGROUP_ID, "type": "supergroup", "title": "Highlander"}));
+        assert_eq!(message_link(&supergroup, 31), Some(String::from("https://t.me/c/1592783264/31")));
+        assert_eq!(private_link(GROUP_ID, 31), None);
+        assert_eq!(private_link(1072037897, 31), None);
+    }
+
+    #[test]
+    fn public_chats_by_username() {
+        let supergroup = chat(json!({
+            "id": SUPERGROUP_ID,
+            "type": "supergroup",
+            "title": "Highlander",
+            "username": "highlander_es"
+        }));
+        assert_eq!(message_link(&supergroup, 31), Some(String::from("https://t.me/highlander_es/31")));
+        let channel = chat(json!({"id": -1001234567890i64, "type": "channel", "title": "News", "username": "news"}));
+        assert_eq!(message_link(&channel, 7), Some(String::from("https://t.me/news/7")));
+        let private_channel = chat(json!({"id": -1001234567890i64, "type": "channel", "title": "News"}));
+        assert_eq!(message_link(&private_channel, 7), Some(String::from("https://t.me/c/1234567890/7")));
+    }
+
+    #[test]
+    fn groups_and_private_chats_reply() {
+        let group = chat(json!({"id": GROUP_ID, "type": "group", "title": "Highlander"}));
+        assert_eq!(message_link(&group, 31), None);
+        assert_eq!(locate(&group, GROUP_ID, 31), Original::Reply(31));
+        assert_eq!(locate(&group, GROUP_ID, 31).reply_to(), Some(31));
+
+        let private = chat(json!({"id": 1072037897, "type": "private", "first_name": "Connor", "username": "connor"}));
+        assert_eq!(message_link(&private, 31), None);
+        assert_eq!(locate(&private, 1072037897, 31), Original::Reply(31));
+    }
+
+    #[test]
+    fn other_chats_of_the_network() {
+        let group = chat(json!({"id": GROUP_ID, "type": "group", "title": "Highlander"}));
+        let original = locate(&group, SUPERGROUP_ID, 31);
+        assert_eq!(original, Original::Link(String::from("https://t.me/c/1592783264/31")));
+        assert_eq!(original.reply_to(), None);
+
+        let supergroup = chat(json!({"id": SUPERGROUP_ID, "type": "supergroup", "title": "Highlander"}));
+        let original = locate(&supergroup, GROUP_ID, 31);
+        assert_eq!(original, Original::Unreachable(31));
+        assert_eq!(original.text(Locale::En), "message 31");
+        assert_eq!(describe(Locale::Es, GROUP_ID, 31), "mensaje 31");
+    }
+}
@@ -1,4 +1,12 @@
 #[macro_use]
 pub mod macros {
     macro_rules! ok (($result:expr) => ($result.unwrap()));
+
+    /// Translated message, needs `i18n` in scope: `t!(locale, "window.set", days = 4)`
+    macro_rules! t {
+        ($locale:expr, $key:expr) => (i18n::tr($locale, $key, &[]));
+        ($locale:expr, $key:expr, $($name:ident = $value:expr),+ $(,)?) => (
+            i18n::tr($locale, $key, &[$((stringify!($name), &$value as &dyn std::fmt::Display)),+])
+        );
+    }
 }
@@ -2,20 +2,23 @@
 mod macros;
 
 use teloxide::prelude::*;
-use teloxide::types::{ChatMember, ChatMemberStatus, True};
+use teloxide::types::{ChatMember, ChatMemberStatus, ChatPermissions, InputFile, InputMedia, User};
 use teloxide::utils::command::BotCommand;
 use teloxide::RequestError;
 
 use tokio::spawn;
+use tokio::time::sleep;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 //use std::convert::Infallible;
 use std::env;
+use std::collections::HashMap;
 use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use chrono::Local;
+use chrono::{Duration, Local, Utc};
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use log::LevelFilter;
 use pretty_env_logger::env_logger::Builder;
@@ -23,19 +26,28 @@ use pretty_env_logger::env_logger::Builder;
 use rtdlib::types::UpdateAuthorizationState;
 use rtdlib::Tdlib;
 
+use highlander::albums::{check_album, settle_album, AlbumBuffer, ALBUM_WAIT_MS};
 use highlander::api_listener::tgram_listener;
+use highlander::audit;
+use highlander::bans::{ban_record, execute_plan, keyboard, new_plan, parse_callback, summary, PlanAction};
+use highlander::cleanup;
 use highlander::commands::*;
-use highlander::duplicates::detect_duplicates;
-use highlander::models::HResponse;
+use highlander::duplicates::{detect_duplicates, detect_edited_duplicates};
+use highlander::phash::{detect_near_album, detect_near_duplicates};
+use highlander::policy::{evaluate, Sanction};
+use highlander::render::{keyboard as page_keyboard, page_text, parse_callback as parse_page_callback, render, PageCache, Reply};
+use highlander::i18n;
+use highlander::models::{AuditAction, FileType, HResponse, Locale, Outgoing, Payload, Status, MAX_MUTE_MINUTES};
 use highlander::models::User as DBUser;
-use highlander::repository::Repository;
-use highlander::rocksdb::RocksDBRepo;
+use highlander::repository::{init_from_env, user_to_db, Repo};
 
 static INIT_FLAG: AtomicBool = AtomicBool::new(true);
 
 lazy_static! {
-    static ref DB: RocksDBRepo = Repository::init();
+    static ref DB: Repo = init_from_env();
     static ref TDLIB: Arc<Tdlib> = Arc::new(Tdlib::new());
+    static ref PAGES: PageCache = PageCache::default();
+    static ref ALBUMS: AlbumBuffer = AlbumBuffer::default();
 }
 
 fn init_tgram() -> () {
@@ -132,20 +144,13 @@ async fn run() {
 
     log::info!("Starting Highlander bot...");
     let bot = Bot::from_env().auto_send();
+    let resumed = cleanup::resume(bot.clone(), DB.clone());
+    log::info!("Resumed {} pending warning deletions", resumed);
 
     Dispatcher::new(bot)
         .messages_handler(|rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| {
             UnboundedReceiverStream::new(rx).for_each_concurrent(None, |cx| async move {
-                let is_test_mode: bool = match env::var("HIGHLANDER_TEST_MODE") {
-                    Ok(mode) => {
-                        if mode == "true" {
-                            true
-                        } else {
-                            false
-                        }
-                    }
-                    Err(_) => false,
-                };
+                let is_test_mode = test_mode();
 
                 if INIT_FLAG.load(Ordering::Relaxed) {
                     init_tgram();
@@ -157,29 +162,39 @@ async fn run() {
                 match message.from() {
                     Some(user) => {
                         // Handle normal messages
-                        let member: ChatMember =
-                            ok!(cx.requester.get_chat_member(message.chat.id, user.id).await);
-                        let is_admin = match member.status() {
-                            ChatMemberStatus::Administrator => true,
-                            ChatMemberStatus::Owner => true,
-                            _ => false,
-                        };
+                        let is_admin = is_admin(&cx, user.id).await;
+                        // admins are never flagged, except when testing the bot
+                        let exempt = is_admin && !is_test_mode;
 
-                        let status = detect_duplicates(DB.clone(), &message, user);
-                        if is_test_mode || !is_admin {
-                            if status.respond {
-                                let mr = cx.answer(status.text).await;
-                                match mr {
-                                    Ok(m) => log::info!("Responded: {:?}", m),
-                                    Err(e) => log::error!("Error: {:?}", e),
-                                }
+                        let locale = DB.get_config(message.chat.id).locale;
+                        let checked = match message.media_group_id() {
+                            // the first message of an album checks all of them
+                            Some(group_id) if ALBUMS.push(message.clone(), group_id) => {
+                                sleep(std::time::Duration::from_millis(ALBUM_WAIT_MS)).await;
+                                let messages = ALBUMS.take(message.chat.id, group_id);
+                                let mut statuses = check_album(DB.clone(), &messages, user);
+                                let near = detect_near_album(DB.clone(), &cx.requester, &messages, &mut statuses).await;
+                                settle_album(DB.clone(), message.chat.id, group_id, statuses, &near, user, exempt).map(|album| {
+                                    let to_delete = album.to_delete(&DB.get_config(message.chat.id));
+                                    (album.status, to_delete)
+                                })
                             }
-                            if status.action {
-                                let mr = cx.delete_message().await;
-                                match mr {
-                                    Ok(m) => log::info!("Deleted message: {:?}", m),
-                                    Err(e) => log::error!("Error: {:?}", e),
-                                }
+                            Some(_) => None,
+                            None => {
+                                let status = detect_duplicates(DB.clone(), &message, user, exempt);
+                                let status = if status.action {
+                                    status
+                                } else {
+                                    detect_near_duplicates(DB.clone(), &cx.requester, &message, exempt)
+                                        .await
+                                        .unwrap_or(status)
+                                };
+                                Some((status, vec![message.id]))
+                            }
+                        };
+                        if let Some((status, to_delete)) = checked {
+                            if !exempt {
+                                respond(&cx, user, status, locale, &to_delete).await;
                             }
                         }
 
@@ -196,23 +211,34 @@ async fn run() {
                                         match cr {
                                             Ok(hr) => match hr {
                                                 HResponse::URL(urls) => {
-                                                    let ans: String = urls.join("\n");
-                                                    if ans.is_empty() {
-                                                        ok!(cx.answer("No results found").await);
+                                                    send_listing(&cx, locale, &urls, "results", None).await;
+                                                }
+                                                HResponse::Records(name, lines, json) => {
+                                                    send_listing(&cx, locale, &lines, &name, Some(json)).await;
+                                                }
+                                                HResponse::Subscribe(id) => {
+                                                    let text = if is_admin_of(&cx, id, user.id).await {
+                                                        subscribe(DB.clone(), message.chat.id, id)
                                                     } else {
-                                                        match cx.answer(ans.as_str()).await {
-                                                            Ok(_) => (),
-                                                            Err(e) => {
-                                                                log::error!("Error {}", e);
-                                                                log::info!("Tried to send {}", ans)
-                                                            }
-                                                        }
+                                                        t!(locale, "subscribe.not_admin", id = id)
+                                                    };
+                                                    if let Err(e) = cx.answer(text).await {
+                                                        log::error!("Error: {:?}", e);
                                                     }
                                                 }
-                                                HResponse::Media(vec) => {
-                                                    match cx.answer_media_group(vec).await {
-                                                        Ok(_) => (),
-                                                        Err(e) => log::error!("Error: {:?}", e)
+                                                HResponse::Document(name, data) => {
+                                                    if let Err(e) = send_document(&cx, name, data).await {
+                                                        log::error!("Error: {:?}", e);
+                                                    }
+                                                }
+                                                HResponse::Media(groups) => {
+                                                    if groups.is_empty() {
+                                                        ok!(cx.answer(t!(locale, "results.empty")).await);
+                                                    }
+                                                    for outgoing in groups {
+                                                        if let Err(e) = send_media(&cx, outgoing).await {
+                                                            log::error!("Error: {:?}", e);
+                                                        }
                                                     }
                                                 }
                                                 HResponse::Text(msg) => {
@@ -221,17 +247,27 @@ async fn run() {
                                                         Err(e) => log::error!("Error: {:?}", e)
                                                     }
                                                 }
-                                                HResponse::Ban(users) => {
-                                                    let b = users.iter()
-                                                                 .map(|user| ban_user(&cx, user))
-                                                                 .collect::<Vec<_>>();
-                                                    log::info!("Banned {} users", b.len())
+                                                HResponse::Ban(users, reason) => {
+                                                    let users = exclude_admins(&cx, users).await;
+                                                    let plan = new_plan(message.chat.id, user.id, users, &reason);
+                                                    let text = summary(locale, &plan);
+                                                    let markup = keyboard(locale, &plan);
+                                                    if plan.users.is_empty() {
+                                                        ok!(cx.answer(t!(locale, "plan.empty")).await);
+                                                    } else if DB.insert_ban_plan(plan) {
+                                                        match cx.answer(text).reply_markup(markup).await {
+                                                            Ok(_) => (),
+                                                            Err(e) => log::error!("Error: {:?}", e)
+                                                        }
+                                                    } else {
+                                                        ok!(cx.answer(t!(locale, "error.store")).await);
+                                                    }
                                                 }
                                             },
                                             Err(e) => log::error!("Error: {:?}", e)
                                         }
                                     } else {
-                                        ok!(cx.answer("Lamentablemente, este comando es solo para usuarios Admin").await);
+                                        ok!(cx.answer(t!(locale, "admin.only")).await);
                                     }
                                 }
                                 Err(_) => ()
@@ -243,15 +279,355 @@ async fn run() {
                 }
             })
         })
+        .edited_messages_handler(|rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| {
+            UnboundedReceiverStream::new(rx).for_each_concurrent(None, |cx| async move {
+                let message: &Message = &cx.update;
+                if let Some(user) = message.from() {
+                    // admin edits still update the stored urls, they are only never flagged
+                    let exempt = is_admin(&cx, user.id).await && !test_mode();
+                    let locale = DB.get_config(message.chat.id).locale;
+                    let status = detect_edited_duplicates(DB.clone(), message, user, exempt);
+                    if !exempt {
+                        respond(&cx, user, status, locale, &[message.id]).await;
+                    }
+                }
+            })
+        })
+        .callback_queries_handler(|rx: DispatcherHandlerRx<AutoSend<Bot>, CallbackQuery>| {
+            UnboundedReceiverStream::new(rx).for_each_concurrent(None, |cx| async move {
+                match cx.update.data.as_deref().and_then(parse_page_callback) {
+                    Some((token, n)) => handle_page_callback(&cx, &token, n).await,
+                    None => handle_ban_callback(cx).await,
+                }
+            })
+        })
         .dispatch()
         .await;
 }
 
 type Cx = UpdateWithCx<AutoSend<Bot>, Message>;
 
-async fn ban_user(cx: &Cx, user: &DBUser) -> Result<True, RequestError> {
-    cx.requester
-        .ban_chat_member(user.chat_id, user.user_id)
-        .until_date(0)
-        .await
+/// Sends stored media back, a lone item of a group on its own as groups need two at least
+async fn send_media(cx: &Cx, outgoing: Outgoing) -> Result<(), RequestError> {
+    let mut group = match outgoing {
+        Outgoing::Group(group) if group.len() != 1 => {
+            return cx.answer_media_group(group).await.map(|_| ());
+        }
+        Outgoing::Group(group) => group,
+        Outgoing::File(file_type, file_id) => {
+            let file = InputFile::FileId(file_id);
+            let r = match file_type {
+                FileType::Animation => cx.answer_animation(file).await,
+                FileType::Sticker => cx.answer_sticker(file).await,
+                FileType::VideoNote => cx.answer_video_note(file).await,
+                FileType::Voice => cx.answer_voice(file).await,
+                _ => cx.answer_document(file).await,
+            };
+            return r.map(|_| ());
+        }
+        Outgoing::Payload(payload) => {
+            let r = match payload {
+                Payload::Contact { phone_number, first_name, last_name } => {
+                    cx.answer_contact(phone_number, first_name).last_name(last_name.unwrap_or_default()).await
+                }
+                Payload::Location { latitude, longitude } => cx.answer_location(latitude, longitude).await,
+                Payload::Poll { question, options } => cx.answer_poll(question, options).await,
+            };
+            return r.map(|_| ());
+        }
+    };
+    let r = match ok!(group.pop()) {
+        InputMedia::Photo(p) => cx.answer_photo(p.media).caption(p.caption.unwrap_or_default()).await,
+        InputMedia::Video(v) => cx.answer_video(v.media).caption(v.caption.unwrap_or_default()).await,
+        InputMedia::Animation(a) => cx.answer_animation(a.media).caption(a.caption.unwrap_or_default()).await,
+        InputMedia::Audio(a) => cx.answer_audio(a.media).caption(a.caption.unwrap_or_default()).await,
+        InputMedia::Document(d) => cx.answer_document(d.media).caption(d.caption.unwrap_or_default()).await,
+    };
+    r.map(|_| ())
+}
+
+/// Admins are also checked when HIGHLANDER_TEST_MODE is true
+fn test_mode() -> bool {
+    match env::var("HIGHLANDER_TEST_MODE") {
+        Ok(mode) => mode == "true",
+        Err(_) => false,
+    }
+}
+
+async fn is_admin(cx: &Cx, user_id: i64) -> bool {
+    let member: ChatMember = ok!(cx.requester.get_chat_member(cx.update.chat.id, user_id).await);
+    match member.status() {
+        ChatMemberStatus::Administrator => true,
+        ChatMemberStatus::Owner => true,
+        _ => false,
+    }
+}
+
+/// Whether the user administers another chat, false when the bot can't look it up
+async fn is_admin_of(cx: &Cx, chat_id: i64, user_id: i64) -> bool {
+    match cx.requester.get_chat_member(chat_id, user_id).await {
+        Ok(member) => matches!(
+            member.status(),
+            ChatMemberStatus::Administrator | ChatMemberStatus::Owner
+        ),
+        Err(e) => {
+            log::error!("is_admin_of: {} on {}: {:?}", user_id, chat_id, e);
+            false
+        }
+    }
+}
+
+/// Notifies the duplicate and sanctions its poster as the chat's policy says, a deleting
+/// sanction removes the `to_delete` messages
+async fn respond(cx: &Cx, user: &User, status: Status, locale: Locale, to_delete: &[i32]) {
+    let chat_id = cx.update.chat.id;
+    let sanction = if status.action {
+        Some(evaluate(DB.clone(), chat_id, user.id))
+    } else {
+        None
+    };
+    if status.respond {
+        let text = match sanction {
+            Some((s, strikes)) => format!("{}{}", status.text, s.notice(locale, strikes)),
+            None => status.text,
+        };
+        let ttl = if status.action {
+            DB.get_config(chat_id).warning_ttl
+        } else {
+            0
+        };
+        send_notice(cx, text, status.reply_to, status.quote, ttl).await;
+    }
+    if let Some((s, strikes)) = sanction {
+        apply_sanction(cx, user, s, strikes, locale, to_delete).await;
+    }
+}
+
+/// Sends a duplicate notice, quoting the original to the poster when asked, and schedules
+/// the bot's messages for deletion after `ttl` seconds
+async fn send_notice(cx: &Cx, text: String, reply_to: Option<i32>, quote: Option<(i64, i32)>, ttl: i64) {
+    let chat_id = cx.update.chat.id;
+    let mut request = cx.answer(text);
+    if let Some(id) = reply_to {
+        request = request.reply_to_message_id(id).allow_sending_without_reply(true);
+    }
+    let mut sent = Vec::new();
+    match request.await {
+        Ok(m) => {
+            log::info!("Responded: {:?}", m);
+            sent.push(m.id);
+        }
+        Err(e) => log::error!("Error: {:?}", e),
+    }
+    if let Some((from_chat_id, msg_id)) = quote {
+        let r = cx
+            .requester
+            .copy_message(chat_id, from_chat_id, msg_id)
+            .reply_to_message_id(cx.update.id)
+            .allow_sending_without_reply(true)
+            .await;
+        match r {
+            Ok(copy) => sent.push(copy.message_id),
+            Err(e) => log::error!("Could not quote {} of {}: {:?}", msg_id, from_chat_id, e),
+        }
+    }
+    let now = Utc::now().timestamp();
+    for id in sent {
+        if let Some(d) = cleanup::schedule(DB.clone(), chat_id, id, ttl, now) {
+            spawn(cleanup::delete_after(cx.requester.clone(), DB.clone(), d));
+        }
+    }
+}
+
+async fn apply_sanction(
+    cx: &Cx,
+    user: &User,
+    sanction: Sanction,
+    strikes: u32,
+    locale: Locale,
+    to_delete: &[i32],
+) {
+    let user_id = user.id;
+    let chat_id = cx.update.chat.id;
+    let rule = format!("duplicate, strike {}", strikes);
+    if sanction.deletes() {
+        for msg_id in to_delete {
+            match cx.requester.delete_message(chat_id, *msg_id).await {
+                Ok(m) => {
+                    log::info!("Deleted message: {:?}", m);
+                    audit::record(DB.clone(), AuditAction::Delete, chat_id, user_id, *msg_id, 0, &rule);
+                }
+                Err(e) => log::error!("Error: {:?}", e),
+            }
+        }
+    }
+    let (r, action) = match sanction {
+        Sanction::Mute(minutes) => {
+            let until = Utc::now() + Duration::minutes(minutes.min(MAX_MUTE_MINUTES));
+            let r = cx
+                .requester
+                .restrict_chat_member(chat_id, user_id, ChatPermissions::default())
+                .until_date(until.timestamp() as u64)
+                .await;
+            (r, AuditAction::Mute)
+        }
+        Sanction::Ban => {
+            let r = cx.requester.ban_chat_member(chat_id, user_id).until_date(0).await;
+            let dbuser = user_to_db(user, Arc::new(cx.update.chat.clone()));
+            let error = r.as_ref().err().map(|e| e.to_string());
+            let reason = t!(locale, "reason.duplicates");
+            DB.insert_ban(ban_record(&dbuser, &reason, 0, error));
+            (r, AuditAction::Ban)
+        }
+        Sanction::Warn | Sanction::Delete => return,
+    };
+    match r {
+        Ok(_) => {
+            log::info!("{:?} applied to {} on {}", sanction, user_id, chat_id);
+            let msg_id = to_delete.first().copied().unwrap_or(cx.update.id);
+            audit::record(DB.clone(), action, chat_id, user_id, msg_id, 0, &rule);
+        }
+        Err(e) => log::error!("Error: {:?}", e),
+    }
+}
+
+/// Splits long listings across messages or pages, or attaches them as a file when huge
+async fn send_listing(cx: &Cx, locale: Locale, lines: &[String], name: &str, json: Option<String>) {
+    if lines.iter().all(|line| line.is_empty()) {
+        ok!(cx.answer(t!(locale, "results.empty")).await);
+        return;
+    }
+    let r = match render(lines, name, json) {
+        Reply::Messages(messages) => {
+            for text in messages {
+                if let Err(e) = cx.answer(text).await {
+                    log::error!("Error: {:?}", e);
+                }
+            }
+            return;
+        }
+        Reply::Pages(pages) => {
+            let total = pages.len();
+            let text = page_text(locale, &pages[0], 1, total);
+            let token = PAGES.insert(pages, Utc::now().timestamp());
+            let markup = page_keyboard(locale, &token, 1, total);
+            cx.answer(text).reply_markup(markup).await.map(|_| ())
+        }
+        Reply::Document(file_name, data) => {
+            log::info!("send_listing: {} lines sent as {}", lines.len(), file_name);
+            send_document(cx, file_name, data).await
+        }
+    };
+    if let Err(e) = r {
+        log::error!("Error: {:?}", e);
+    }
+}
+
+/// Attaches the data as a file built in memory, nothing is written on the server
+async fn send_document(cx: &Cx, file_name: String, data: Vec<u8>) -> Result<(), RequestError> {
+    cx.answer_document(InputFile::memory(file_name, data)).await.map(|_| ())
 }
+
+type CallbackCx = UpdateWithCx<AutoSend<Bot>, CallbackQuery>;
+
+/// Language of the chat the callback's message was sent to
+fn callback_locale(query: &CallbackQuery) -> Locale {
+    match &query.message {
+        Some(message) => DB.get_config(message.chat.id).locale,
+        None => Locale::default(),
+    }
+}
+
+async fn handle_page_callback(cx: &CallbackCx, token: &str, n: usize) {
+    let query = &cx.update;
+    let locale = callback_locale(query);
+    let page = PAGES.page(token, n, Utc::now().timestamp());
+    let answer = match page {
+        Some(_) => cx.requester.answer_callback_query(query.id.clone()).await,
+        None => {
+            cx.requester
+                .answer_callback_query(query.id.clone())
+                .text(t!(locale, "page.expired"))
+                .await
+        }
+    };
+    if let Err(e) = answer {
+        log::error!("Error: {:?}", e);
+    }
+    if let (Some((page, total)), Some(message)) = (page, &query.message) {
+        let edit = cx
+            .requester
+            .edit_message_text(message.chat.id, message.id, page_text(locale, &page, n, total))
+            .reply_markup(page_keyboard(locale, token, n, total))
+            .await;
+        if let Err(e) = edit {
+            log::error!("Error: {:?}", e);
+        }
+    }
+}
+
+/// Admins are never part of a ban plan, whatever their activity
+async fn exclude_admins(cx: &Cx, users: Vec<DBUser>) -> Vec<DBUser> {
+    let mut admins: HashMap<i64, Vec<i64>> = HashMap::new();
+    for chat_id in users.iter().map(|user| user.chat_id).unique() {
+        match cx.requester.get_chat_administrators(chat_id).await {
+            Ok(members) => {
+                admins.insert(chat_id, members.iter().map(|member| member.user.id).collect());
+            }
+            Err(e) => log::error!("Could not list admins of {}: {:?}", chat_id, e),
+        }
+    }
+    users
+        .into_iter()
+        .filter(|user| match admins.get(&user.chat_id) {
+            Some(ids) => !ids.contains(&user.user_id),
+            None => false,
+        })
+        .collect()
+}
+
+async fn handle_ban_callback(cx: CallbackCx) {
+    let query = &cx.update;
+    let (action, token) = match query.data.as_deref().and_then(parse_callback) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let locale = callback_locale(query);
+    let plan = DB.get_ban_plan(&token);
+    if let Some(plan) = &plan {
+        if plan.admin_id != query.from.id {
+            let answer = cx
+                .requester
+                .answer_callback_query(query.id.clone())
+                .text(t!(locale, "plan.not_owner"))
+                .await;
+            if let Err(e) = answer {
+                log::error!("Error: {:?}", e);
+            }
+            return;
+        }
+    }
+    // answered before banning, Telegram drops callback answers sent too late
+    if let Err(e) = cx.requester.answer_callback_query(query.id.clone()).await {
+        log::error!("Error: {:?}", e);
+    }
+    // claimed before running it, a second tap or callback finds it gone
+    let reply = match plan.and_then(|_| DB.take_ban_plan(&token)) {
+        None => t!(locale, "plan.missing"),
+        Some(plan) if plan.is_expired(Utc::now().timestamp()) => t!(locale, "plan.expired"),
+        Some(plan) => match action {
+            PlanAction::Cancel => t!(locale, "plan.cancelled"),
+            PlanAction::Confirm => execute_plan(&cx.requester, DB.clone(), &plan).await,
+        },
+    };
+    if let Some(message) = &query.message {
+        let edit = cx
+            .requester
+            .edit_message_text(message.chat.id, message.id, reply)
+            .await;
+        if let Err(e) = edit {
+            log::error!("Error: {:?}", e);
+        }
+    }
+}
+
@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rtdlib::types::UpdateDeleteMessages;
+use teloxide::types::{Chat, User};
+
+use chrono::offset::Utc;
+use chrono::Duration;
+
+use itertools::Itertools;
+
+use super::models::User as DBUser;
+use super::models::{
+    Album, AuditEntry, Ban, BanPlan, ChatConfig, Deletion, Group, Mapping, Media, Offender,
+    PHash, Strikes, SDO,
+};
+use super::phash::hamming;
+use super::repository::*;
+
+#[derive(Default)]
+struct State {
+    media: BTreeMap<(i64, String), Media>,
+    duplicates: BTreeMap<(i64, String), Media>,
+    users: BTreeMap<(i64, i64), DBUser>,
+    mappings: HashMap<(i64, i64), Mapping>,
+    groups: HashMap<i64, Group>,
+    configs: HashMap<i64, ChatConfig>,
+    phashes: BTreeMap<(i64, i32), PHash>,
+    fingerprints: BTreeMap<(i64, i32), PHash>,
+    strikes: HashMap<(i64, i64), Strikes>,
+    offenders: HashMap<(i64, i64), Offender>,
+    allowed: BTreeSet<(i64, i64)>,
+    ban_plans: HashMap<String, BanPlan>,
+    bans: Vec<Ban>,
+    audit: Vec<AuditEntry>,
+    deletions: BTreeMap<(i64, i32), Deletion>,
+    albums: HashMap<(i64, String), Album>,
+}
+
+fn last_media(
+    media: &BTreeMap<(i64, String), Media>,
+    chat_id: i64,
+    limit: usize,
+    is_url: bool,
+) -> Vec<Media> {
+    let mut media_vec = media
+        .values()
+        .filter(|media| media.chat_id == chat_id)
+        .filter(|media| (media.file_type == "url") == is_url)
+        .map(|media| (media.msg_id, media.clone()))
+        .into_group_map()
+        .into_iter()
+        .map(|(_, g)| g.first().unwrap().clone())
+        .collect::<Vec<_>>();
+    media_vec.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    media_vec.truncate(limit);
+    media_vec
+}
+
+/// Closest hash of the chat within the window and `max_distance` bits
+fn similar(
+    hashes: &BTreeMap<(i64, i32), PHash>,
+    chat_id: i64,
+    hash: u64,
+    max_distance: u32,
+    window: i64,
+) -> Option<PHash> {
+    let now = Utc::now().timestamp();
+    hashes
+        .values()
+        .filter(|phash| phash.chat_id == chat_id && now - phash.timestamp <= window)
+        .map(|phash| (hamming(phash.hash, hash), phash))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, phash)| phash.clone())
+}
+
+fn truncated<T: Clone>(values: impl Iterator<Item = T>, limit: usize) -> Vec<T> {
+    let mut vec = values.collect::<Vec<_>>();
+    if limit > 0 {
+        vec.truncate(limit);
+    }
+    vec
+}
+
+/// Keeps everything in memory, meant for tests and throwaway deployments
+#[derive(Clone, Default)]
+pub struct MemoryRepo {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryRepo {
+    fn state(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+
+    fn window(&self, chat_id: i64) -> i64 {
+        self.get_config(chat_id).window
+    }
+}
+
+impl Repository<Media> for MemoryRepo {
+    fn init() -> Self {
+        MemoryRepo::default()
+    }
+
+    fn chat_user_exists(&self, user: &User, chat: Arc<Chat>) -> bool {
+        self.chat_dbuser_exists(user.id, chat.id)
+    }
+
+    fn chat_dbuser_exists(&self, user_id: i64, chat_id: i64) -> bool {
+        self.state().users.contains_key(&(chat_id, user_id))
+    }
+
+    fn update_user_timestamp(&self, user: &User, chat: Arc<Chat>) -> bool {
+        self.insert_dbuser(user_to_db(user, chat))
+    }
+
+    fn insert_user(&self, user: &User, chat: Arc<Chat>) -> bool {
+        log::info!("insert_user: {} on chat {}", user.id, chat.id);
+        self.update_user_timestamp(user, chat)
+    }
+
+    #[allow(unused_variables)]
+    fn item_exists(&self, sdo: SDO, is_media: bool) -> Option<Media> {
+        let window = self.window(sdo.chat.id);
+        let k = (sdo.chat.id, sdo.unique_id);
+        match self.state().media.get(&k) {
+            Some(media) if Utc::now().timestamp() - media.timestamp <= window => {
+                Some(media.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert_item(&self, sdo: SDO, _is_media: bool) -> bool {
+        let media = sdo_to_media(sdo);
+        let k = (media.chat_id, media.unique_id.clone());
+        self.state().media.insert(k, media);
+        true
+    }
+
+    fn insert_duplicate(&self, sdo: SDO) -> bool {
+        let media = sdo_to_media(sdo);
+        let k = (media.chat_id, media.unique_id.clone());
+        self.state().duplicates.insert(k, media);
+        true
+    }
+
+    fn message_media(&self, chat_id: i64, msg_id: i32, is_duplicate: bool) -> Vec<Media> {
+        let state = self.state();
+        let media = if is_duplicate { &state.duplicates } else { &state.media };
+        media
+            .values()
+            .filter(|media| media.chat_id == chat_id && media.msg_id == msg_id)
+            .cloned()
+            .collect()
+    }
+
+    fn delete_media(&self, chat_id: i64, unique_id: &str) -> bool {
+        self.state().media.remove(&(chat_id, unique_id.to_string()));
+        true
+    }
+
+    fn delete_item(&self, deleted_messages: UpdateDeleteMessages) -> () {
+        let chat_id = deleted_messages.chat_id();
+        for api_id in deleted_messages.message_ids() {
+            match self.find_mapping(*api_id, chat_id) {
+                None => log::error!("Mapping {}_{} not found", chat_id, api_id),
+                Some(mapping) => {
+                    self.state().media.remove(&(chat_id, mapping.unique_id));
+                }
+            }
+        }
+    }
+
+    fn insert_mapping(&self, api_id: i64, chat_id: i64, unique_id: &str) -> bool {
+        let mapping = Mapping {
+            unique_id: unique_id.into(),
+            chat_id,
+            api_id,
+            timestamp: Utc::now().timestamp(),
+        };
+        self.state().mappings.insert((chat_id, api_id), mapping);
+        true
+    }
+
+    fn find_mapping(&self, api_id: i64, chat_id: i64) -> Option<Mapping> {
+        self.state().mappings.get(&(chat_id, api_id)).cloned()
+    }
+
+    fn last_media_stored(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        last_media(&self.state().media, chat_id, limit, is_url)
+    }
+
+    fn last_media_duplicated(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        last_media(&self.state().duplicates, chat_id, limit, is_url)
+    }
+
+    #[allow(unused_variables)]
+    fn list_user_groups(&self, chat_id: i64, user_id: i64) -> Vec<DBUser> {
+        self.state()
+            .users
+            .values()
+            .filter(|user| user.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    fn get_chat_ids(&self) -> Vec<i64> {
+        self.state().users.keys().map(|(chat_id, _)| *chat_id).dedup().collect()
+    }
+
+    fn insert_dbuser(&self, user: DBUser) -> bool {
+        self.state().users.insert((user.chat_id, user.user_id), user);
+        true
+    }
+
+    fn list_media(&self, limit: usize) -> Vec<Media> {
+        truncated(self.state().media.values().cloned(), limit)
+    }
+
+    fn list_users(&self, limit: usize) -> Vec<DBUser> {
+        truncated(self.state().users.values().cloned(), limit)
+    }
+
+    fn list_duplicates(&self, limit: usize) -> Vec<Media> {
+        truncated(self.state().duplicates.values().cloned(), limit)
+    }
+
+    fn get_users_chat_count(&self) -> Vec<(DBUser, usize)> {
+        self.state()
+            .users
+            .values()
+            .map(|user| (user.user_id, user.clone()))
+            .into_group_map()
+            .into_iter()
+            .map(|(_, g)| {
+                let count = g.len();
+                let user = g.first().unwrap().clone();
+                (user, count)
+            })
+            .filter(|tup| tup.1 > 1)
+            .collect()
+    }
+
+    fn inactive_users_before(&self, ndays: i64) -> Vec<DBUser> {
+        let offset_day = Utc::now() - Duration::days(ndays);
+        self.state()
+            .users
+            .values()
+            .filter(|user| user.timestamp < offset_day.timestamp())
+            .cloned()
+            .collect()
+    }
+
+    fn insert_group(&self, group: Group) -> bool {
+        self.state().groups.insert(group.supergroup_id, group);
+        true
+    }
+
+    fn get_group(&self, supergroup_id: i64) -> Option<Group> {
+        self.state().groups.get(&supergroup_id).cloned()
+    }
+
+    fn get_config(&self, chat_id: i64) -> ChatConfig {
+        match self.state().configs.get(&chat_id) {
+            Some(config) => config.clone(),
+            None => ChatConfig::new(chat_id),
+        }
+    }
+
+    fn insert_config(&self, config: ChatConfig) -> bool {
+        self.state().configs.insert(config.chat_id, config);
+        true
+    }
+
+    fn insert_phash(&self, phash: PHash) -> bool {
+        self.state().phashes.insert((phash.chat_id, phash.msg_id), phash);
+        true
+    }
+
+    fn find_similar_phash(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        let window = self.window(chat_id);
+        similar(&self.state().phashes, chat_id, hash, max_distance, window)
+    }
+
+    fn insert_fingerprint(&self, fingerprint: PHash) -> bool {
+        self.state().fingerprints.insert((fingerprint.chat_id, fingerprint.msg_id), fingerprint);
+        true
+    }
+
+    fn find_similar_fingerprint(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        let window = self.window(chat_id);
+        similar(&self.state().fingerprints, chat_id, hash, max_distance, window)
+    }
+
+    fn find_in_network(&self, unique_id: &str, chat_ids: &[i64]) -> Option<Media> {
+        let now = Utc::now().timestamp();
+        chat_ids
+            .iter()
+            .map(|chat_id| (self.window(*chat_id), *chat_id))
+            .filter_map(|(window, chat_id)| {
+                let k = (chat_id, unique_id.to_string());
+                match self.state().media.get(&k) {
+                    Some(media) if now - media.timestamp <= window => Some(media.clone()),
+                    _ => None,
+                }
+            })
+            .min_by_key(|media| media.timestamp)
+    }
+
+    fn get_strikes(&self, chat_id: i64, user_id: i64) -> Option<Strikes> {
+        self.state().strikes.get(&(chat_id, user_id)).cloned()
+    }
+
+    fn insert_strikes(&self, strikes: Strikes) -> bool {
+        self.state()
+            .strikes
+            .insert((strikes.chat_id, strikes.user_id), strikes);
+        true
+    }
+
+    fn get_offender(&self, chat_id: i64, user_id: i64) -> Option<Offender> {
+        self.state().offenders.get(&(chat_id, user_id)).cloned()
+    }
+
+    fn insert_offender(&self, offender: Offender) -> bool {
+        self.state()
+            .offenders
+            .insert((offender.chat_id, offender.user_id), offender);
+        true
+    }
+
+    fn top_offenders(&self, chat_id: i64, limit: usize) -> Vec<Offender> {
+        let mut offenders = self
+            .state()
+            .offenders
+            .values()
+            .filter(|offender| offender.chat_id == chat_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        offenders.sort_by(|a, b| b.count.cmp(&a.count).then(b.timestamp.cmp(&a.timestamp)));
+        truncated(offenders.into_iter(), limit)
+    }
+
+    fn insert_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        self.state().allowed.insert((chat_id, user_id));
+        true
+    }
+
+    fn delete_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        self.state().allowed.remove(&(chat_id, user_id));
+        true
+    }
+
+    fn is_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        self.state().allowed.contains(&(chat_id, user_id))
+    }
+
+    fn list_allowed(&self, chat_id: i64) -> Vec<i64> {
+        self.state()
+            .allowed
+            .range((chat_id, i64::MIN)..=(chat_id, i64::MAX))
+            .map(|(_, user_id)| *user_id)
+            .collect()
+    }
+
+    fn insert_ban_plan(&self, plan: BanPlan) -> bool {
+        let now = Utc::now().timestamp();
+        let mut state = self.state();
+        state.ban_plans.retain(|_, plan| !plan.is_expired(now));
+        state.ban_plans.insert(plan.token.clone(), plan);
+        true
+    }
+
+    fn get_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        self.state().ban_plans.get(token).cloned()
+    }
+
+    fn take_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        self.state().ban_plans.remove(token)
+    }
+
+    fn insert_ban(&self, ban: Ban) -> bool {
+        self.state().bans.push(ban);
+        true
+    }
+
+    fn list_bans(&self, chat_id: i64, limit: usize) -> Vec<Ban> {
+        let mut bans = self
+            .state()
+            .bans
+            .iter()
+            .filter(|ban| ban.chat_id == chat_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        bans.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        truncated(bans.into_iter(), limit)
+    }
+
+    fn insert_audit(&self, entry: AuditEntry) -> bool {
+        self.state().audit.push(entry);
+        true
+    }
+
+    fn list_audit(&self, chat_id: i64, user_id: Option<i64>, limit: usize) -> Vec<AuditEntry> {
+        let entries = self
+            .state()
+            .audit
+            .iter()
+            .rev()
+            .filter(|entry| entry.chat_id == chat_id)
+            .filter(|entry| user_id.map_or(true, |id| entry.user_id == id))
+            .cloned()
+            .collect::<Vec<_>>();
+        truncated(entries.into_iter(), limit)
+    }
+
+    fn insert_deletion(&self, deletion: Deletion) -> bool {
+        let key = (deletion.chat_id, deletion.msg_id);
+        self.state().deletions.insert(key, deletion);
+        true
+    }
+
+    fn delete_deletion(&self, chat_id: i64, msg_id: i32) -> bool {
+        self.state().deletions.remove(&(chat_id, msg_id));
+        true
+    }
+
+    fn list_deletions(&self) -> Vec<Deletion> {
+        self.state()
+            .deletions
+            .values()
+            .cloned()
+            .sorted_by_key(|deletion| deletion.due)
+            .collect()
+    }
+
+    fn insert_album(&self, album: Album) -> bool {
+        let key = (album.chat_id, album.media_group_id.clone());
+        self.state().albums.insert(key, album);
+        true
+    }
+
+    fn get_album(&self, chat_id: i64, media_group_id: &str) -> Option<Album> {
+        self.state()
+            .albums
+            .get(&(chat_id, media_group_id.to_string()))
+            .cloned()
+    }
+
+    fn find_album(&self, chat_id: i64, msg_id: i32) -> Option<Album> {
+        self.state()
+            .albums
+            .values()
+            .find(|album| album.chat_id == chat_id && album.msg_ids.contains(&msg_id))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryRepo;
+    use crate::repository::conformance;
+
+    #[test]
+    fn test_conformance() {
+        let repo = MemoryRepo::default();
+        conformance::run(&repo);
+    }
+}
@@ -1,6 +1,8 @@
 use teloxide::types::Chat;
 use teloxide::types::InputMedia;
 
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use serde::{Serialize, Deserialize};
@@ -10,6 +12,12 @@ pub struct Status {
     pub action: bool,
     pub respond: bool,
     pub text: String,
+    /// Message the response should reply to
+    pub reply_to: Option<i32>,
+    /// Chat and message id of an original to quote to the poster
+    pub quote: Option<(i64, i32)>,
+    /// Chat and message id of the original a duplicate repeats
+    pub original: Option<(i64, i32)>,
 }
 
 impl Status {
@@ -18,6 +26,9 @@ impl Status {
             action: status.action,
             respond: status.respond,
             text: status.text.clone(),
+            reply_to: status.reply_to,
+            quote: status.quote,
+            original: status.original,
         }
     }
 }
@@ -37,6 +48,7 @@ pub struct Media {
     pub chat_id: i64,
     pub msg_id: i32,
     pub file_type: String,
+    /// Telegram file id, or the `Payload` as json for contacts, locations and polls
     pub file_id: String,
     pub timestamp: i64
 }
@@ -65,7 +77,526 @@ pub enum ColFam {
     MappingCF(Mapping)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+pub const DEFAULT_WINDOW_SECS: i64 = 345600;
+pub const DAY_SECS: i64 = 86400;
+pub const MAX_WINDOW_DAYS: i64 = 3650;
+/// A leap year, Telegram takes longer restrictions as forever
+pub const MAX_MUTE_MINUTES: i64 = 527040;
+
+fn default_window() -> i64 {
+    DEFAULT_WINDOW_SECS
+}
+
+/// Hamming distance under which two photos are considered the same, 0 disables the check
+fn default_phash_distance() -> u32 {
+    0
+}
+
+fn default_mute_minutes() -> i64 {
+    60
+}
+
+fn default_mute_after() -> u32 {
+    2
+}
+
+fn default_file_types() -> Vec<FileType> {
+    FileType::ALL.iter().copied().filter(|t| *t != FileType::Sticker).collect()
+}
+
+fn default_text_similarity() -> u32 {
+    90
+}
+
+fn default_ban_after() -> u32 {
+    3
+}
+
+/// What happens to the sender of a duplicate
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Reply only, the message stays
+    Warn,
+    /// Reply and delete the message
+    Delete,
+    /// Delete and mute the sender for `mute_minutes`
+    Mute,
+    /// Delete, mute after `mute_after` strikes and ban after `ban_after`
+    Escalate,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Delete
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(Policy::Warn),
+            "delete" => Ok(Policy::Delete),
+            "mute" => Ok(Policy::Mute),
+            "escalate" => Ok(Policy::Escalate),
+            other => Err(format!("Unknown policy {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Policy::Warn => "warn",
+            Policy::Delete => "delete",
+            Policy::Mute => "mute",
+            Policy::Escalate => "escalate",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Language of the bot's replies in a chat
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Locale {
+    Es,
+    En,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::Es, Locale::En];
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale::Es
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "es" => Ok(Locale::Es),
+            "en" => Ok(Locale::En),
+            other => Err(format!("Unknown language {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Locale::Es => "es",
+            Locale::En => "en",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How duplicate notices point at the original message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyMode {
+    /// Link it, replying only when no link exists
+    Link,
+    /// Reply to it while it's within the window
+    Reply,
+    /// Reply to it and also copy it as a reply to the duplicate
+    Quote,
+}
+
+impl ReplyMode {
+    pub const ALL: [ReplyMode; 3] = [ReplyMode::Link, ReplyMode::Reply, ReplyMode::Quote];
+}
+
+impl Default for ReplyMode {
+    fn default() -> Self {
+        ReplyMode::Link
+    }
+}
+
+impl FromStr for ReplyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "link" => Ok(ReplyMode::Link),
+            "reply" => Ok(ReplyMode::Reply),
+            "quote" => Ok(ReplyMode::Quote),
+            other => Err(format!("Unknown reply mode {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for ReplyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ReplyMode::Link => "link",
+            ReplyMode::Reply => "reply",
+            ReplyMode::Quote => "quote",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What a deleting sanction removes from an album that repeats media
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlbumMode {
+    /// Only the items already shared
+    Items,
+    /// Every message of the album
+    Whole,
+}
+
+impl AlbumMode {
+    pub const ALL: [AlbumMode; 2] = [AlbumMode::Items, AlbumMode::Whole];
+}
+
+impl Default for AlbumMode {
+    fn default() -> Self {
+        AlbumMode::Items
+    }
+}
+
+impl FromStr for AlbumMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "items" => Ok(AlbumMode::Items),
+            "whole" => Ok(AlbumMode::Whole),
+            other => Err(format!("Unknown album mode {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for AlbumMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AlbumMode::Items => "items",
+            AlbumMode::Whole => "whole",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Kinds of media checked for duplicates, named as the `file_type` they are stored with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Url,
+    Photo,
+    Video,
+    Audio,
+    Document,
+    Voice,
+    Animation,
+    VideoNote,
+    /// Opt-in, the same stickers are sent over and over on purpose
+    Sticker,
+    Contact,
+    Location,
+    Poll,
+    /// Forwards, by the post they come from
+    Forward,
+}
+
+impl FileType {
+    pub const ALL: [FileType; 13] = [
+        FileType::Url,
+        FileType::Photo,
+        FileType::Video,
+        FileType::Audio,
+        FileType::Document,
+        FileType::Voice,
+        FileType::Animation,
+        FileType::VideoNote,
+        FileType::Sticker,
+        FileType::Contact,
+        FileType::Location,
+        FileType::Poll,
+        FileType::Forward,
+    ];
+}
+
+impl FromStr for FileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "url" => Ok(FileType::Url),
+            "photo" => Ok(FileType::Photo),
+            "video" => Ok(FileType::Video),
+            "audio" => Ok(FileType::Audio),
+            "document" => Ok(FileType::Document),
+            "voice" => Ok(FileType::Voice),
+            "animation" => Ok(FileType::Animation),
+            "video_note" => Ok(FileType::VideoNote),
+            "sticker" => Ok(FileType::Sticker),
+            "contact" => Ok(FileType::Contact),
+            "location" => Ok(FileType::Location),
+            "poll" => Ok(FileType::Poll),
+            "forward" => Ok(FileType::Forward),
+            other => Err(format!("Unknown file type {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FileType::Url => "url",
+            FileType::Photo => "photo",
+            FileType::Video => "video",
+            FileType::Audio => "audio",
+            FileType::Document => "document",
+            FileType::Voice => "voice",
+            FileType::Animation => "animation",
+            FileType::VideoNote => "video_note",
+            FileType::Sticker => "sticker",
+            FileType::Contact => "contact",
+            FileType::Location => "location",
+            FileType::Poll => "poll",
+            FileType::Forward => "forward",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Content of media without a Telegram file, enough to send it back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Payload {
+    Contact {
+        phone_number: String,
+        first_name: String,
+        last_name: Option<String>,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+    },
+    Poll {
+        question: String,
+        options: Vec<String>,
+    },
+}
+
+/// Stored media as it is sent back
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    /// Items sent as one media group
+    Group(Vec<InputMedia>),
+    /// Media that can't be grouped, by file id
+    File(FileType, String),
+    Payload(Payload),
+}
+
+/// Per chat settings, stored as json so new fields can be added with a default
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatConfig {
+    pub chat_id: i64,
+    #[serde(default = "default_window")]
+    pub window: i64,
+    #[serde(default = "default_phash_distance")]
+    pub phash_distance: u32,
+    /// Sibling chats whose media also counts as already shared here
+    #[serde(default)]
+    pub network: Vec<i64>,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default = "default_mute_minutes")]
+    pub mute_minutes: i64,
+    #[serde(default = "default_mute_after")]
+    pub mute_after: u32,
+    #[serde(default = "default_ban_after")]
+    pub ban_after: u32,
+    #[serde(default)]
+    pub locale: Locale,
+    /// Custom duplicate notice, the catalogue one is used when unset
+    #[serde(default)]
+    pub notice: Option<String>,
+    /// Seconds before the bot deletes its own duplicate warning, 0 keeps it
+    #[serde(default)]
+    pub warning_ttl: i64,
+    #[serde(default)]
+    pub reply_mode: ReplyMode,
+    #[serde(default)]
+    pub album_mode: AlbumMode,
+    /// Kinds of media checked for duplicates
+    #[serde(default = "default_file_types")]
+    pub file_types: Vec<FileType>,
+    /// Texts without urls this long at least are fingerprinted, 0 disables it
+    #[serde(default)]
+    pub text_min_length: usize,
+    /// Percent of fingerprint bits two texts share to be duplicates
+    #[serde(default = "default_text_similarity")]
+    pub text_similarity: u32,
+}
+
+impl ChatConfig {
+    pub fn new(chat_id: i64) -> Self {
+        Self {
+            chat_id,
+            window: DEFAULT_WINDOW_SECS,
+            phash_distance: default_phash_distance(),
+            network: Vec::new(),
+            policy: Policy::default(),
+            mute_minutes: default_mute_minutes(),
+            mute_after: default_mute_after(),
+            ban_after: default_ban_after(),
+            locale: Locale::default(),
+            notice: None,
+            warning_ttl: 0,
+            reply_mode: ReplyMode::default(),
+            album_mode: AlbumMode::default(),
+            file_types: default_file_types(),
+            text_min_length: 0,
+            text_similarity: default_text_similarity(),
+        }
+    }
+
+    /// Unknown file types are always checked
+    pub fn tracks(&self, file_type: &str) -> bool {
+        match file_type.parse::<FileType>() {
+            Ok(file_type) => self.file_types.contains(&file_type),
+            Err(_) => true,
+        }
+    }
+
+    pub fn window_days(&self) -> i64 {
+        self.window / DAY_SECS
+    }
+
+    /// Leaves the window untouched and returns false when `ndays` is out of range
+    pub fn set_window_days(&mut self, ndays: i64) -> bool {
+        if ndays < 1 || ndays > MAX_WINDOW_DAYS {
+            return false;
+        }
+        match ndays.checked_mul(DAY_SECS) {
+            Some(window) => {
+                self.window = window;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Duplicates posted by a user in a chat, kept next to the chat's users
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Strikes {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub count: u32,
+    pub timestamp: i64
+}
+
+/// Messages posted together as a media group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Album {
+    pub chat_id: i64,
+    pub media_group_id: String,
+    pub msg_ids: Vec<i32>,
+    pub timestamp: i64
+}
+
+/// A bot message to delete once `due` is reached
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Deletion {
+    pub chat_id: i64,
+    pub msg_id: i32,
+    pub due: i64
+}
+
+/// How long an admin has to confirm a ban plan
+pub const BAN_PLAN_TTL_SECS: i64 = 600;
+
+/// Users picked by BanInactiveUsers, waiting for the requesting admin to confirm
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BanPlan {
+    pub token: String,
+    pub chat_id: i64,
+    pub admin_id: i64,
+    pub users: Vec<User>,
+    pub reason: String,
+    pub timestamp: i64
+}
+
+impl BanPlan {
+    pub fn is_expired(&self, now: i64) -> bool {
+        now - self.timestamp > BAN_PLAN_TTL_SECS
+    }
+}
+
+/// Outcome of a ban attempt, admin_id is 0 when the bot banned on its own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub user_name: String,
+    pub reason: String,
+    pub admin_id: i64,
+    pub error: Option<String>,
+    pub timestamp: i64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Duplicate,
+    Delete,
+    Mute,
+    Ban,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AuditAction::Duplicate => "duplicate",
+            AuditAction::Delete => "delete",
+            AuditAction::Mute => "mute",
+            AuditAction::Ban => "ban",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One moderation action, actor_id is the admin behind it or 0 when a rule triggered it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub msg_id: i32,
+    pub actor_id: i64,
+    pub rule: String,
+    pub timestamp: i64
+}
+
+/// Lifetime count of duplicates posted by a user in a chat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Offender {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub user_name: String,
+    pub count: u32,
+    /// Original the last duplicate repeated, in another chat for network duplicates
+    pub last_chat_id: i64,
+    pub last_msg_id: i32,
+    pub timestamp: i64
+}
+
+/// 64 bit similarity hash of a message: the dhash of a photo or the simhash of a text
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PHash {
+    pub chat_id: i64,
+    pub msg_id: i32,
+    pub hash: u64,
+    pub timestamp: i64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Group {
     pub supergroup_id: i64,
     pub chat_id: i64,
@@ -74,8 +605,16 @@ pub struct Group {
 }
 
 pub enum HResponse {
-    Ban(Vec<User>),
-    Media(Vec<InputMedia>),
+    /// Users to ban and the reason recorded with each ban
+    Ban(Vec<User>, String),
+    /// Media groups, each album on its own, and media that can't be grouped
+    Media(Vec<Outgoing>),
     URL(Vec<String>),
+    /// Listing name, one line per record and the records as JSON for the file attachment
+    Records(String, Vec<String>, String),
+    /// File name and contents, sent as an attachment
+    Document(String, Vec<u8>),
+    /// Chat to share duplicates with, once the sender is known to be an admin there too
+    Subscribe(i64),
     Text(String),
 }
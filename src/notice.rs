@@ -0,0 +1,278 @@
+use chrono::offset::Utc;
+use teloxide::types::Chat;
+
+use crate::i18n;
+use crate::links::{locate, private_link, Original};
+use crate::models::{ChatConfig, Locale, Media, ReplyMode, Status, DAY_SECS};
+
+/// Placeholders a custom notice may use
+pub const PLACEHOLDERS: [&str; 5] = ["user", "type", "original_link", "age", "window"];
+/// Custom notices longer than this are rejected, Telegram caps messages at 4096
+pub const MAX_NOTICE_LEN: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    Empty,
+    TooLong,
+    Unbalanced,
+    Unknown(String),
+}
+
+/// What a duplicate notice talks about: who posted it and the original message
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub user: String,
+    pub file_type: String,
+    pub chat_id: i64,
+    pub msg_id: i32,
+    pub original: Original,
+    pub timestamp: i64,
+    pub window_days: i64,
+}
+
+impl Notice {
+    /// Notice for a duplicate of `original` posted on `chat`
+    pub fn new(user: &str, chat: &Chat, original: &Media, window_days: i64) -> Self {
+        Self {
+            user: user.into(),
+            file_type: original.file_type.clone(),
+            chat_id: original.chat_id,
+            msg_id: original.msg_id,
+            original: locate(chat, original.chat_id, original.msg_id),
+            timestamp: original.timestamp,
+            window_days,
+        }
+    }
+}
+
+/// A piece of a template
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(String),
+}
+
+/// Literal text and placeholder names in order of appearance
+fn placeholders(template: &str) -> Result<Vec<Part>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut name: Option<String> = None;
+    for c in template.chars() {
+        match c {
+            '{' if name.is_some() => return Err(TemplateError::Unbalanced),
+            '{' => {
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                name = Some(String::new());
+            }
+            '}' => match name.take() {
+                Some(n) => parts.push(Part::Placeholder(n)),
+                None => return Err(TemplateError::Unbalanced),
+            },
+            c => match name.as_mut() {
+                Some(n) => n.push(c),
+                None => text.push(c),
+            },
+        }
+    }
+    if name.is_some() {
+        return Err(TemplateError::Unbalanced);
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+pub fn validate(template: &str) -> Result<(), TemplateError> {
+    if template.trim().is_empty() {
+        return Err(TemplateError::Empty);
+    }
+    if template.chars().count() > MAX_NOTICE_LEN {
+        return Err(TemplateError::TooLong);
+    }
+    let unknown = placeholders(template)?.into_iter().find_map(|part| match part {
+        Part::Placeholder(name) if !PLACEHOLDERS.contains(&name.as_str()) => Some(name),
+        _ => None,
+    });
+    match unknown {
+        Some(unknown) => Err(TemplateError::Unknown(unknown)),
+        None => Ok(()),
+    }
+}
+
+/// How long ago the original was posted, in the largest whole unit
+pub fn age(locale: Locale, secs: i64) -> String {
+    let secs = secs.max(0);
+    if secs >= 86400 {
+        t!(locale, "age.days", n = secs / 86400)
+    } else if secs >= 3600 {
+        t!(locale, "age.hours", n = secs / 3600)
+    } else {
+        t!(locale, "age.minutes", n = secs / 60)
+    }
+}
+
+/// Fills the placeholders in one pass, values are never scanned for placeholders themselves
+pub fn render(template: &str, locale: Locale, notice: &Notice, now: i64) -> String {
+    let parts = match placeholders(template) {
+        Ok(parts) => parts,
+        Err(e) => {
+            log::error!("render: invalid notice {:?}: {:?}", template, e);
+            return template.to_string();
+        }
+    };
+    let mut text = String::with_capacity(template.len());
+    for part in parts {
+        match part {
+            Part::Text(literal) => text.push_str(&literal),
+            Part::Placeholder(name) => match name.as_str() {
+                "user" => text.push_str(&notice.user),
+                "type" => text.push_str(&notice.file_type),
+                "original_link" => text.push_str(&notice.original.text(locale)),
+                "age" => text.push_str(&age(locale, now - notice.timestamp)),
+                "window" => text.push_str(&notice.window_days.to_string()),
+                _ => {
+                    text.push('{');
+                    text.push_str(&name);
+                    text.push('}');
+                }
+            },
+        }
+    }
+    text
+}
+
+/// The chat's own notice when it set one, the catalogue entry `key` otherwise
+pub fn duplicate_text(config: &ChatConfig, key: &str, kind: &str, notice: &Notice) -> String {
+    match &config.notice {
+        Some(template) => render(template, config.locale, notice, Utc::now().timestamp()),
+        None => t!(
+            config.locale,
+            key,
+            kind = kind,
+            days = notice.window_days,
+            link = notice.original.text(config.locale)
+        ),
+    }
+}
+
+/// Status of a duplicate, replying to and quoting the original as the chat's reply mode asks.
+/// Originals older than their window are only linked, they may be gone
+pub fn duplicate_status(config: &ChatConfig, notice: &Notice, text: String, now: i64) -> Status {
+    let fresh = now - notice.timestamp <= notice.window_days * DAY_SECS;
+    let reply_to = match config.reply_mode {
+        ReplyMode::Reply | ReplyMode::Quote if fresh && notice.chat_id == config.chat_id => {
+            Some(notice.msg_id)
+        }
+        _ => notice.original.reply_to(),
+    };
+    let quote = match config.reply_mode {
+        ReplyMode::Quote if fresh => Some((notice.chat_id, notice.msg_id)),
+        _ => None,
+    };
+    Status {
+        action: true,
+        respond: true,
+        text,
+        reply_to,
+        quote,
+        original: Some((notice.chat_id, notice.msg_id)),
+    }
+}
+
+/// A made up duplicate, shared two days ago, to preview notices with
+pub fn sample(config: &ChatConfig, now: i64) -> Notice {
+    let msg_id = 1234;
+    Notice {
+        user: t!(config.locale, "notice.sample_user"),
+        file_type: String::from("photo"),
+        chat_id: config.chat_id,
+        msg_id,
+        original: match private_link(config.chat_id, msg_id) {
+            Some(link) => Original::Link(link),
+            None => Original::Reply(msg_id),
+        },
+        timestamp: now - 2 * 86400,
+        window_days: config.window_days(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        age, duplicate_status, duplicate_text, render, sample, validate, TemplateError,
+        MAX_NOTICE_LEN,
+    };
+    use crate::models::{ChatConfig, Locale, ReplyMode};
+
+    const CHAT_ID: i64 = -1001592783264;
+
+    #[test]
+    fn validates_templates() {
+        assert_eq!(validate("{user}, {type} repetido: {original_link} ({age}, {window} dias)"), Ok(()));
+        assert_eq!(validate("sin marcadores"), Ok(()));
+        assert_eq!(validate("  "), Err(TemplateError::Empty));
+        assert_eq!(validate(&"a".repeat(MAX_NOTICE_LEN + 1)), Err(TemplateError::TooLong));
+        assert_eq!(validate("{user"), Err(TemplateError::Unbalanced));
+        assert_eq!(validate("user}"), Err(TemplateError::Unbalanced));
+        assert_eq!(validate("{{user}}"), Err(TemplateError::Unbalanced));
+        assert_eq!(validate("{link}"), Err(TemplateError::Unknown(String::from("link"))));
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let config = ChatConfig::new(CHAT_ID);
+        let now = 1633072800;
+        let notice = sample(&config, now);
+        assert_eq!(
+            render("{user}: {type} {original_link} {age} / {window}", Locale::En, &notice, now),
+            "Connor MacLeod: photo https://t.me/c/1592783264/1234 2 days ago / 4"
+        );
+        let group = sample(&ChatConfig::new(-592783264), now);
+        assert_eq!(
+            render("{original_link}", Locale::En, &group, now),
+            "the message this notice replies to"
+        );
+        assert_eq!(age(Locale::En, 7200), "2 hours ago");
+
+        // values are not rendered again
+        let mut tricky = notice.clone();
+        tricky.user = String::from("{window} {age}");
+        assert_eq!(render("{user} in {window}", Locale::En, &tricky, now), "{window} {age} in 4");
+        assert_eq!(age(Locale::Es, 59), "hace 0 minutos");
+    }
+
+    #[test]
+    fn falls_back_to_the_catalogue() {
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.locale = Locale::En;
+        let notice = sample(&config, 1633072800);
+        let text = duplicate_text(&config, "duplicate", "media", &notice);
+        assert!(text.starts_with("Duplicate message: media already shared in the last 4 days"));
+        config.notice = Some(String::from("{user} again"));
+        assert_eq!(duplicate_text(&config, "duplicate", "media", &notice), "Connor MacLeod again");
+    }
+
+    #[test]
+    fn reply_modes() {
+        let mut config = ChatConfig::new(CHAT_ID);
+        let now = 1633072800;
+        let notice = sample(&config, now);
+        let status = duplicate_status(&config, &notice, String::new(), now);
+        assert_eq!((status.reply_to, status.quote), (None, None));
+
+        config.reply_mode = ReplyMode::Reply;
+        let status = duplicate_status(&config, &notice, String::new(), now);
+        assert_eq!((status.reply_to, status.quote), (Some(1234), None));
+
+        config.reply_mode = ReplyMode::Quote;
+        let status = duplicate_status(&config, &notice, String::new(), now);
+        assert_eq!((status.reply_to, status.quote), (Some(1234), Some((CHAT_ID, 1234))));
+
+        // past the window the original may be gone, it is only linked
+        let status = duplicate_status(&config, &notice, String::new(), now + 3 * 86400);
+        assert_eq!((status.reply_to, status.quote), (None, None));
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::offset::Utc;
+use image::imageops::FilterType;
+
+use std::sync::Arc;
+
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::{MediaKind, MessageKind, PhotoSize};
+
+use crate::duplicates::settle;
+use crate::links::locate;
+use crate::models::{PHash, Status, SDO};
+use crate::notice::{duplicate_status, duplicate_text, Notice};
+use crate::repository::Repo;
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Difference hash: one bit per pixel telling whether it's brighter than its right neighbour
+pub fn dhash(bytes: &[u8]) -> Option<u64> {
+    let img = match image::load_from_memory(bytes) {
+        Ok(img) => img,
+        Err(e) => {
+            log::error!("dhash: {}", e);
+            return None;
+        }
+    };
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    Some(hash)
+}
+
+async fn download(bot: &AutoSend<Bot>, photo: &PhotoSize) -> Option<Vec<u8>> {
+    let file = match bot.get_file(photo.file_id.clone()).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("download: {}", e);
+            return None;
+        }
+    };
+    let mut bytes: Vec<u8> = Vec::new();
+    match bot.inner().download_file(&file.file_path, &mut bytes).await {
+        Ok(_) => Some(bytes),
+        Err(e) => {
+            log::error!("download: {}", e);
+            None
+        }
+    }
+}
+
+/// Smallest size of the photo a message carries
+fn smallest_photo(message: &Message) -> Option<&PhotoSize> {
+    match &message.kind {
+        MessageKind::Common(msg_common) => match &msg_common.media_kind {
+            MediaKind::Photo(photo) => photo.photo.iter().min_by_key(|p| p.width * p.height),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Looks the hash up among the chat's photos. A near duplicate has the media the unique id
+/// check stored for it dropped and is returned as a duplicate, otherwise the hash is stored
+pub fn check_hash(db: Repo, message: &Message, photo: &PhotoSize, hash: u64) -> Option<Status> {
+    let config = db.get_config(message.chat.id);
+    match db.find_similar_phash(message.chat.id, hash, config.phash_distance) {
+        None => {
+            db.insert_phash(PHash {
+                chat_id: message.chat.id,
+                msg_id: message.id,
+                hash,
+                timestamp: Utc::now().timestamp(),
+            });
+            None
+        }
+        Some(original) => {
+            log::info!("near duplicate of {:?}", original);
+            for media in db.message_media(message.chat.id, message.id, false) {
+                db.delete_media(message.chat.id, &media.unique_id);
+            }
+            let sdo = SDO {
+                chat: Arc::new(message.chat.clone()),
+                msg_id: message.id,
+                file_type: String::from("photo"),
+                unique_id: photo.file_unique_id.clone(),
+                file_id: Some(photo.file_id.clone()),
+            };
+            db.insert_duplicate(sdo);
+            let notice = Notice {
+                user: message
+                    .from()
+                    .map(|user| user.username.as_ref().unwrap_or(&user.first_name).clone())
+                    .unwrap_or_default(),
+                file_type: String::from("photo"),
+                chat_id: original.chat_id,
+                msg_id: original.msg_id,
+                original: locate(&message.chat, original.chat_id, original.msg_id),
+                timestamp: original.timestamp,
+                window_days: config.window_days(),
+            };
+            let text = duplicate_text(&config, "duplicate.photo", "photo", &notice);
+            Some(duplicate_status(&config, &notice, text, Utc::now().timestamp()))
+        }
+    }
+}
+
+/// Hashes a photo the unique id check let through, see `check_hash`
+pub async fn near_duplicate(db: Repo, bot: &AutoSend<Bot>, message: &Message) -> Option<Status> {
+    let config = db.get_config(message.chat.id);
+    if config.phash_distance == 0 || !config.tracks("photo") {
+        return None;
+    }
+    let smallest = smallest_photo(message)?;
+    let bytes = download(bot, smallest).await?;
+    let hash = dhash(&bytes)?;
+    log::info!("Photo {} dhash {:016x}", message.id, hash);
+    check_hash(db, message, smallest, hash)
+}
+
+/// Catches photos re-encoded, resized or slightly cropped that slip through the unique id check
+pub async fn detect_near_duplicates(
+    db: Repo,
+    bot: &AutoSend<Bot>,
+    message: &Message,
+    is_admin: bool,
+) -> Option<Status> {
+    let status = near_duplicate(db.clone(), bot, message).await?;
+    let user = message.from()?;
+    Some(settle(db, message.chat.id, user, is_admin, message.id, status, "phash"))
+}
+
+/// Runs the photo hash check on the album items the unique id check let through, replacing
+/// their status. Returns the ids it flagged
+pub async fn detect_near_album(
+    db: Repo,
+    bot: &AutoSend<Bot>,
+    messages: &[Message],
+    statuses: &mut [(i32, Status)],
+) -> Vec<i32> {
+    let mut flagged = Vec::new();
+    for (message, (id, status)) in messages.iter().zip(statuses.iter_mut()) {
+        if status.action {
+            continue;
+        }
+        if let Some(near) = near_duplicate(db.clone(), bot, message).await {
+            *status = near;
+            flagged.push(*id);
+        }
+    }
+    flagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dhash, hamming};
+    use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb};
+
+    fn gradient(shift: u8, reverse: bool) -> Vec<u8> {
+        let img = ImageBuffer::from_fn(64, 64, |x, y| {
+            let v = if reverse { 255 - (x * 4) as u8 } else { (x * 4) as u8 };
+            Rgb([v.saturating_add(shift), (y * 2) as u8, v])
+        });
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut bytes, ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn hamming_distance() {
+        assert_eq!(hamming(0, 0), 0);
+        assert_eq!(hamming(0b1011, 0b0001), 2);
+        assert_eq!(hamming(u64::MAX, 0), 64);
+    }
+
+    #[test]
+    fn similar_images_hash_close() {
+        let original = dhash(&gradient(0, false)).unwrap();
+        let brighter = dhash(&gradient(10, false)).unwrap();
+        let mirrored = dhash(&gradient(0, true)).unwrap();
+        assert!(hamming(original, brighter) <= 4);
+        assert!(hamming(original, mirrored) > 32);
+    }
+
+    #[test]
+    fn invalid_image() {
+        assert!(dhash(b"not an image").is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use chrono::offset::Utc;
+
+use crate::i18n;
+use crate::models::{ChatConfig, Locale, Policy, Strikes};
+use crate::repository::Repo;
+
+/// What the bot does to the sender of a duplicate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sanction {
+    Warn,
+    Delete,
+    /// Delete and mute for the given minutes
+    Mute(i64),
+    Ban,
+}
+
+impl Sanction {
+    pub fn deletes(&self) -> bool {
+        *self != Sanction::Warn
+    }
+
+    /// Appended to the duplicate notice
+    pub fn notice(&self, locale: Locale, strikes: u32) -> String {
+        match self {
+            Sanction::Warn | Sanction::Delete => String::new(),
+            Sanction::Mute(minutes) => t!(locale, "sanction.mute", strikes = strikes, minutes = minutes),
+            Sanction::Ban => t!(locale, "sanction.ban", strikes = strikes),
+        }
+    }
+}
+
+pub fn sanction_for(config: &ChatConfig, strikes: u32) -> Sanction {
+    match config.policy {
+        Policy::Warn => Sanction::Warn,
+        Policy::Delete => Sanction::Delete,
+        Policy::Mute => Sanction::Mute(config.mute_minutes),
+        Policy::Escalate => {
+            if strikes >= config.ban_after {
+                Sanction::Ban
+            } else if strikes >= config.mute_after {
+                Sanction::Mute(config.mute_minutes)
+            } else {
+                Sanction::Warn
+            }
+        }
+    }
+}
+
+/// Adds a strike to the user, strikes older than the chat window start over
+pub fn record_strike(db: Repo, config: &ChatConfig, user_id: i64) -> u32 {
+    let now = Utc::now().timestamp();
+    let count = match db.get_strikes(config.chat_id, user_id) {
+        Some(strikes) if now - strikes.timestamp <= config.window => strikes.count + 1,
+        _ => 1,
+    };
+    let strikes = Strikes {
+        chat_id: config.chat_id,
+        user_id,
+        count,
+        timestamp: now,
+    };
+    if !db.insert_strikes(strikes) {
+        log::error!("record_strike: could not store strike for {}", user_id);
+    }
+    count
+}
+
+/// Records the duplicate against the sender and picks the chat's sanction
+pub fn evaluate(db: Repo, chat_id: i64, user_id: i64) -> (Sanction, u32) {
+    let config = db.get_config(chat_id);
+    let strikes = record_strike(db, &config, user_id);
+    let sanction = sanction_for(&config, strikes);
+    log::info!(
+        "evaluate: user {} on {} has {} strikes, {:?}",
+        user_id,
+        chat_id,
+        strikes,
+        sanction
+    );
+    (sanction, strikes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, sanction_for, Sanction};
+    use crate::memory_repo::MemoryRepo;
+    use crate::models::{ChatConfig, Policy, Strikes};
+    use crate::repository::Repo;
+    use chrono::offset::Utc;
+    use std::sync::Arc;
+
+    const CHAT_ID: i64 = -1001592783264;
+    const USER_ID: i64 = 1072037897;
+
+    #[test]
+    fn fixed_policies() {
+        let mut config = ChatConfig::new(CHAT_ID);
+        assert_eq!(sanction_for(&config, 5), Sanction::Delete);
+        config.policy = Policy::Warn;
+        assert_eq!(sanction_for(&config, 5), Sanction::Warn);
+        config.policy = Policy::Mute;
+        config.mute_minutes = 10;
+        assert_eq!(sanction_for(&config, 1), Sanction::Mute(10));
+    }
+
+    #[test]
+    fn escalates_across_strikes() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.policy = Policy::Escalate;
+        config.mute_minutes = 30;
+        db.insert_config(config);
+
+        assert_eq!(evaluate(db.clone(), CHAT_ID, USER_ID), (Sanction::Warn, 1));
+        assert_eq!(evaluate(db.clone(), CHAT_ID, USER_ID), (Sanction::Mute(30), 2));
+        assert_eq!(evaluate(db.clone(), CHAT_ID, USER_ID), (Sanction::Ban, 3));
+        assert_eq!(evaluate(db.clone(), CHAT_ID, 208056682), (Sanction::Warn, 1));
+    }
+
+    #[test]
+    fn expired_strikes_start_over() {
+        let db: Repo = Arc::new(MemoryRepo::default());
+        let mut config = ChatConfig::new(CHAT_ID);
+        config.policy = Policy::Escalate;
+        db.insert_config(config.clone());
+        db.insert_strikes(Strikes {
+            chat_id: CHAT_ID,
+            user_id: USER_ID,
+            count: 5,
+            timestamp: Utc::now().timestamp() - config.window - 1,
+        });
+        assert_eq!(evaluate(db.clone(), CHAT_ID, USER_ID), (Sanction::Warn, 1));
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use chrono::offset::Utc;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::i18n;
+use crate::models::Locale;
+
+/// Telegram rejects messages longer than this, counted in UTF-16 code units
+pub const MESSAGE_LIMIT: usize = 4096;
+/// Room kept on every page for the "Page n/m" footer
+const FOOTER_RESERVE: usize = 32;
+/// Replies up to this many messages long are sent back to back
+const MAX_MESSAGES: usize = 3;
+/// Longer replies are browsed with buttons up to this many pages, then sent as a file
+const MAX_PAGES: usize = 25;
+/// Pages stay browsable for an hour
+pub const PAGES_TTL_SECS: i64 = 3600;
+
+const CALLBACK_PREFIX: &str = "page";
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Messages(Vec<String>),
+    Pages(Vec<String>),
+    /// File name and contents
+    Document(String, Vec<u8>),
+}
+
+fn units(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Cuts a single line too long for any message at char boundaries
+fn split_line(line: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf16() > limit {
+            parts.push(part);
+            part = String::new();
+            len = 0;
+        }
+        part.push(c);
+        len += c.len_utf16();
+    }
+    parts.push(part);
+    parts
+}
+
+/// Packs whole lines into chunks of at most `limit` units
+pub fn paginate(lines: &[String], limit: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    for line in lines {
+        for part in split_line(line, limit) {
+            if page.is_empty() {
+                page = part;
+            } else if units(&page) + 1 + units(&part) <= limit {
+                page.push('\n');
+                page.push_str(&part);
+            } else {
+                pages.push(page);
+                page = part;
+            }
+        }
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Picks how to deliver the lines, `json` replaces the plain text when sent as a file
+pub fn render(lines: &[String], name: &str, json: Option<String>) -> Reply {
+    let messages = paginate(lines, MESSAGE_LIMIT);
+    if messages.len() <= MAX_MESSAGES {
+        return Reply::Messages(messages);
+    }
+    let pages = paginate(lines, MESSAGE_LIMIT - FOOTER_RESERVE);
+    if pages.len() <= MAX_PAGES {
+        return Reply::Pages(pages);
+    }
+    match json {
+        Some(json) => Reply::Document(format!("{}.json", name), json.into_bytes()),
+        None => Reply::Document(format!("{}.txt", name), lines.join("\n").into_bytes()),
+    }
+}
+
+/// Page `n` counted from 1, with its footer
+pub fn page_text(locale: Locale, page: &str, n: usize, total: usize) -> String {
+    format!("{}\n\n{}", page, t!(locale, "page.footer", n = n, total = total))
+}
+
+fn callback_data(token: &str, n: usize) -> String {
+    format!("{}:{}:{}", CALLBACK_PREFIX, token, n)
+}
+
+pub fn parse_callback(data: &str) -> Option<(String, usize)> {
+    let parts: Vec<&str> = data.split(':').collect();
+    match parts.as_slice() {
+        [CALLBACK_PREFIX, token, n] => n.parse().ok().map(|n| (token.to_string(), n)),
+        _ => None,
+    }
+}
+
+pub fn keyboard(locale: Locale, token: &str, n: usize, total: usize) -> InlineKeyboardMarkup {
+    let mut buttons = Vec::new();
+    if n > 1 {
+        buttons.push(InlineKeyboardButton::callback(
+            t!(locale, "page.previous"),
+            callback_data(token, n - 1),
+        ));
+    }
+    if n < total {
+        buttons.push(InlineKeyboardButton::callback(
+            t!(locale, "page.next"),
+            callback_data(token, n + 1),
+        ));
+    }
+    InlineKeyboardMarkup::new(vec![buttons])
+}
+
+/// Paged replies kept in memory until they expire, losing them on restart is harmless
+#[derive(Default)]
+pub struct PageCache {
+    entries: Mutex<HashMap<String, (i64, Vec<String>)>>,
+}
+
+impl PageCache {
+    /// Stores the pages and returns the token of their callbacks
+    pub fn insert(&self, pages: Vec<String>, now: i64) -> String {
+        let mut hasher = DefaultHasher::new();
+        (Utc::now().timestamp_nanos(), pages.len(), pages.first()).hash(&mut hasher);
+        let token = format!("{:016x}", hasher.finish());
+        let mut entries = ok!(self.entries.lock());
+        entries.retain(|_, (timestamp, _)| now - *timestamp <= PAGES_TTL_SECS);
+        entries.insert(token.clone(), (now, pages));
+        token
+    }
+
+    /// Page `n` counted from 1 and the number of pages
+    pub fn page(&self, token: &str, n: usize, now: i64) -> Option<(String, usize)> {
+        let entries = ok!(self.entries.lock());
+        match entries.get(token) {
+            Some((timestamp, pages)) if now - timestamp <= PAGES_TTL_SECS && n >= 1 => {
+                pages.get(n - 1).map(|page| (page.clone(), pages.len()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(count: usize, width: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{:0width$}", i, width = width)).collect()
+    }
+
+    #[test]
+    fn pages_keep_whole_lines() {
+        let pages = paginate(&lines(10, 9), 25);
+        assert_eq!(pages.len(), 5);
+        assert_eq!(pages[0], "000000000\n000000001");
+        assert!(pages.iter().all(|page| units(page) <= 25));
+        assert_eq!(pages.join("\n"), lines(10, 9).join("\n"));
+        assert!(paginate(&[], 25).is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let pages = paginate(&[String::from("ñ🦀ñ🦀ñ🦀")], 4);
+        assert_eq!(pages, vec!["ñ🦀ñ", "🦀ñ", "🦀"]);
+        assert!(pages.iter().all(|page| units(page) <= 4));
+    }
+
+    #[test]
+    fn renders_by_size() {
+        let short = lines(10, 20);
+        assert_eq!(render(&short, "users", None), Reply::Messages(vec![short.join("\n")]));
+
+        let medium = lines(1000, 20);
+        match render(&medium, "users", None) {
+            Reply::Pages(pages) => {
+                assert!(pages.len() > MAX_MESSAGES && pages.len() <= MAX_PAGES);
+                assert!(pages.iter().all(|page| units(&page_text(Locale::Es, page, 25, 25)) <= MESSAGE_LIMIT));
+            }
+            reply => panic!("Expected pages, got {:?}", reply),
+        }
+
+        let long = lines(10000, 20);
+        match render(&long, "users", None) {
+            Reply::Document(name, data) => {
+                assert_eq!(name, "users.txt");
+                assert_eq!(data, long.join("\n").into_bytes());
+            }
+            reply => panic!("Expected a document, got {:?}", reply),
+        }
+        match render(&long, "users", Some(String::from("[]"))) {
+            Reply::Document(name, data) => assert_eq!((name.as_str(), data), ("users.json", b"[]".to_vec())),
+            reply => panic!("Expected a document, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn callback_roundtrip() {
+        let data = callback_data("5f1c2e3a9b7d4c60", 12);
+        assert!(data.len() <= 64);
+        assert_eq!(parse_callback(&data), Some((String::from("5f1c2e3a9b7d4c60"), 12)));
+        assert_eq!(parse_callback("ban:confirm:5f1c2e3a9b7d4c60"), None);
+        assert_eq!(parse_callback("page:5f1c2e3a9b7d4c60:next"), None);
+    }
+
+    #[test]
+    fn cache_expires_pages() {
+        let cache = PageCache::default();
+        let token = cache.insert(vec![String::from("a"), String::from("b")], 1000);
+        assert_eq!(cache.page(&token, 2, 1000), Some((String::from("b"), 2)));
+        assert_eq!(cache.page(&token, 0, 1000), None);
+        assert_eq!(cache.page(&token, 3, 1000), None);
+        assert_eq!(cache.page(&token, 1, 1000 + PAGES_TTL_SECS + 1), None);
+
+        // expired entries are dropped on the next insert
+        cache.insert(vec![String::from("c")], 1000 + PAGES_TTL_SECS + 1);
+        assert_eq!(ok!(cache.entries.lock()).len(), 1);
+    }
+}
@@ -1,13 +1,25 @@
+use std::env;
 use std::sync::Arc;
 
+use chrono::offset::Utc;
 use rtdlib::types::UpdateDeleteMessages;
-use teloxide::types::{Chat, User};
+use teloxide::types::{Chat, ChatKind, User};
 
-use super::models::{Group, Mapping, Media, SDO};
+use super::models::{
+    Album, AuditEntry, Ban, BanPlan, ChatConfig, Deletion, Group, Mapping, Media, Offender, PHash,
+    Strikes, SDO,
+};
 use super::models::{User as DBUser};
+use super::memory_repo::MemoryRepo;
+use super::rocksdb::RocksDBRepo;
+use super::sqlite_repo::SQLiteRepo;
+
+pub type Repo = Arc<dyn Repository<Media> + Send + Sync>;
 
 pub trait Repository<T> {
-    fn init() -> Self;
+    fn init() -> Self
+    where
+        Self: Sized;
     fn chat_dbuser_exists(&self, user_id: i64, chat_id: i64) -> bool;
     fn chat_user_exists(&self, user: &User, chat: Arc<Chat>) -> bool;
     fn update_user_timestamp(&self, user: &User, chat: Arc<Chat>) -> bool;
@@ -15,6 +27,9 @@ pub trait Repository<T> {
     fn item_exists(&self, sdo: SDO, is_media: bool) -> Option<T>;
     fn insert_item(&self, sdo: SDO, is_media: bool) -> bool;
     fn insert_duplicate(&self, sdo: SDO) -> bool;
+    /// Media stored for one message, or the duplicates it posted when `is_duplicate`
+    fn message_media(&self, chat_id: i64, msg_id: i32, is_duplicate: bool) -> Vec<Media>;
+    fn delete_media(&self, chat_id: i64, unique_id: &str) -> bool;
     fn delete_item(&self, deleted_messages: UpdateDeleteMessages) -> ();
     fn insert_mapping(&self, api_id: i64, chat_id: i64, unique_id: &str) -> bool;
     fn find_mapping(&self, api_id: i64, chat_id: i64) -> Option<Mapping>;
@@ -30,6 +45,78 @@ pub trait Repository<T> {
     fn inactive_users_before(&self, ndays: i64) -> Vec<DBUser>;
     fn insert_group(&self, group: Group) -> bool;
     fn get_group(&self, supergroup_id: i64) -> Option<Group>;
+    fn get_config(&self, chat_id: i64) -> ChatConfig;
+    fn insert_config(&self, config: ChatConfig) -> bool;
+    fn insert_phash(&self, phash: PHash) -> bool;
+    fn find_similar_phash(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash>;
+    /// Simhashes of text posts, kept apart from the photo hashes
+    fn insert_fingerprint(&self, fingerprint: PHash) -> bool;
+    fn find_similar_fingerprint(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash>;
+    fn find_in_network(&self, unique_id: &str, chat_ids: &[i64]) -> Option<Media>;
+    fn get_strikes(&self, chat_id: i64, user_id: i64) -> Option<Strikes>;
+    fn insert_strikes(&self, strikes: Strikes) -> bool;
+    fn get_offender(&self, chat_id: i64, user_id: i64) -> Option<Offender>;
+    fn insert_offender(&self, offender: Offender) -> bool;
+    fn top_offenders(&self, chat_id: i64, limit: usize) -> Vec<Offender>;
+    fn insert_allowed(&self, chat_id: i64, user_id: i64) -> bool;
+    fn delete_allowed(&self, chat_id: i64, user_id: i64) -> bool;
+    fn is_allowed(&self, chat_id: i64, user_id: i64) -> bool;
+    fn list_allowed(&self, chat_id: i64) -> Vec<i64>;
+    fn insert_ban_plan(&self, plan: BanPlan) -> bool;
+    fn get_ban_plan(&self, token: &str) -> Option<BanPlan>;
+    /// Removes the plan and returns it, only one of several concurrent callers gets it
+    fn take_ban_plan(&self, token: &str) -> Option<BanPlan>;
+    fn insert_ban(&self, ban: Ban) -> bool;
+    fn list_bans(&self, chat_id: i64, limit: usize) -> Vec<Ban>;
+    fn insert_audit(&self, entry: AuditEntry) -> bool;
+    fn list_audit(&self, chat_id: i64, user_id: Option<i64>, limit: usize) -> Vec<AuditEntry>;
+    fn insert_deletion(&self, deletion: Deletion) -> bool;
+    fn delete_deletion(&self, chat_id: i64, msg_id: i32) -> bool;
+    /// Pending deletions of every chat, soonest first
+    fn list_deletions(&self) -> Vec<Deletion>;
+    fn insert_album(&self, album: Album) -> bool;
+    fn get_album(&self, chat_id: i64, media_group_id: &str) -> Option<Album>;
+    /// The album `msg_id` was posted in
+    fn find_album(&self, chat_id: i64, msg_id: i32) -> Option<Album>;
+}
+
+/// Opens the backend named by HIGHLANDER_DB_BACKEND (rocksdb, sqlite or memory), defaults to rocksdb
+pub fn init_from_env() -> Repo {
+    let backend = env::var("HIGHLANDER_DB_BACKEND").unwrap_or_else(|_| String::from("rocksdb"));
+    log::info!("Using {} backend", backend);
+    match backend.as_str() {
+        "sqlite" => Arc::new(SQLiteRepo::init()),
+        "memory" => Arc::new(MemoryRepo::init()),
+        _ => Arc::new(RocksDBRepo::init()),
+    }
+}
+
+pub fn user_to_db(user: &User, chat: Arc<Chat>) -> DBUser {
+    let unknown = String::from("Unknown");
+    let chat_name = match &chat.kind {
+        ChatKind::Public(public) => public.title.as_ref().unwrap_or(&unknown) as &str,
+        ChatKind::Private(_) => unknown.as_str(),
+    };
+
+    let user_name = user.username.as_ref().unwrap_or(&user.first_name);
+    DBUser {
+        user_id: user.id,
+        chat_id: chat.id,
+        user_name: user_name.to_string(),
+        chat_name: chat_name.into(),
+        timestamp: Utc::now().timestamp(),
+    }
+}
+
+pub fn sdo_to_media(sdo: SDO) -> Media {
+    Media {
+        unique_id: sdo.unique_id,
+        chat_id: sdo.chat.id,
+        msg_id: sdo.msg_id,
+        file_type: sdo.file_type,
+        file_id: sdo.file_id.unwrap_or("".into()),
+        timestamp: Utc::now().timestamp(),
+    }
 }
 
 #[cfg(test)]
@@ -243,3 +330,482 @@ mod tests {
     }
 
 }
+
+/// Behaviour every Repository backend must share, run from each backend's tests
+#[cfg(test)]
+pub mod conformance {
+    use std::sync::Arc;
+
+    use chrono::offset::Utc;
+    use chrono::Duration;
+    use rtdlib::types::UpdateDeleteMessages;
+    use teloxide::types::{Chat, User};
+
+    use super::Repository;
+    use crate::models::User as DBUser;
+    use crate::models::{
+        Album, AuditAction, AuditEntry, Ban, BanPlan, ChatConfig, Deletion, Group, Media, Offender,
+        PHash, Policy, Strikes, SDO,
+    };
+
+    const CHAT_1: i64 = -1001192585346;
+    const CHAT_2: i64 = -1001592783264;
+    const USER_1: i64 = 1072037897;
+    const USER_2: i64 = 208056682;
+
+    pub fn chat(chat_id: i64) -> Arc<Chat> {
+        let chat: Chat = serde_json::from_value(serde_json::json!({
+            "id": chat_id,
+            "type": "supergroup",
+            "title": format!("Group {}", chat_id)
+        }))
+        .unwrap();
+        Arc::new(chat)
+    }
+
+    pub fn user(user_id: i64) -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": user_id,
+            "is_bot": false,
+            "first_name": format!("User {}", user_id)
+        }))
+        .unwrap()
+    }
+
+    pub fn sdo(chat_id: i64, msg_id: i32, file_type: &str, unique_id: &str) -> SDO {
+        SDO {
+            chat: chat(chat_id),
+            msg_id,
+            file_type: file_type.into(),
+            unique_id: unique_id.into(),
+            file_id: Some(format!("file_{}", unique_id)),
+        }
+    }
+
+    pub fn run(repo: &dyn Repository<Media>) {
+        users(repo);
+        items(repo);
+        duplicates(repo);
+        mappings(repo);
+        groups(repo);
+        configs(repo);
+        phashes(repo);
+        network(repo);
+        short_chat_ids(repo);
+        strikes(repo);
+        offenders(repo);
+        allowlist(repo);
+        ban_plans(repo);
+        bans(repo);
+        audit(repo);
+        deletions(repo);
+        message_media(repo);
+        albums(repo);
+    }
+
+    fn users(repo: &dyn Repository<Media>) {
+        let user_1 = user(USER_1);
+        assert!(!repo.chat_user_exists(&user_1, chat(CHAT_1)));
+        assert!(repo.insert_user(&user_1, chat(CHAT_1)));
+        assert!(repo.chat_user_exists(&user_1, chat(CHAT_1)));
+        assert!(repo.chat_dbuser_exists(USER_1, CHAT_1));
+        assert!(!repo.chat_dbuser_exists(USER_1, CHAT_2));
+        assert!(repo.update_user_timestamp(&user_1, chat(CHAT_2)));
+
+        let old = DBUser {
+            user_id: USER_2,
+            chat_id: CHAT_1,
+            user_name: String::from("old"),
+            chat_name: String::from("Group"),
+            timestamp: (Utc::now() - Duration::days(30)).timestamp(),
+        };
+        assert!(repo.insert_dbuser(old));
+
+        let mut chat_ids = repo.get_chat_ids();
+        chat_ids.sort();
+        assert_eq!(chat_ids, vec![CHAT_2, CHAT_1]);
+        assert_eq!(repo.list_users(0).len(), 3);
+        assert_eq!(repo.list_users(2).len(), 2);
+        assert_eq!(repo.list_user_groups(CHAT_1, USER_1).len(), 2);
+
+        let counts = repo.get_users_chat_count();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].0.user_id, USER_1);
+        assert_eq!(counts[0].1, 2);
+
+        let inactive = repo.inactive_users_before(10);
+        assert_eq!(inactive.len(), 1);
+        assert_eq!(inactive[0].user_id, USER_2);
+    }
+
+    fn items(repo: &dyn Repository<Media>) {
+        let photo = sdo(CHAT_1, 416, "photo", "AQADiq4xG--XSVd4");
+        assert!(repo.item_exists(photo.clone(), true).is_none());
+        assert!(repo.insert_item(photo.clone(), true));
+        let found = repo.item_exists(photo.clone(), true).unwrap();
+        assert_eq!(found.msg_id, 416);
+        assert_eq!(found.file_type, "photo");
+        assert!(repo
+            .item_exists(sdo(CHAT_2, 416, "photo", "AQADiq4xG--XSVd4"), true)
+            .is_none());
+
+        let url = sdo(CHAT_1, 417, "url", "https://youtu.be/GCI0NMgVfPk");
+        assert!(repo.insert_item(url.clone(), false));
+        assert!(repo.item_exists(url, false).is_some());
+
+        let stored_media = repo.last_media_stored(CHAT_1, 5, false);
+        assert_eq!(stored_media.len(), 1);
+        assert_eq!(stored_media[0].unique_id, "AQADiq4xG--XSVd4");
+        let stored_urls = repo.last_media_stored(CHAT_1, 5, true);
+        assert_eq!(stored_urls.len(), 1);
+        assert_eq!(stored_urls[0].msg_id, 417);
+        assert_eq!(repo.list_media(0).len(), 2);
+    }
+
+    fn duplicates(repo: &dyn Repository<Media>) {
+        assert!(repo.insert_duplicate(sdo(CHAT_1, 420, "photo", "AQADiq4xG--XSVd4")));
+        assert!(repo.insert_duplicate(sdo(CHAT_1, 421, "url", "https://youtu.be/GCI0NMgVfPk")));
+        let dup_media = repo.last_media_duplicated(CHAT_1, 5, false);
+        assert_eq!(dup_media.len(), 1);
+        assert_eq!(dup_media[0].msg_id, 420);
+        assert_eq!(repo.last_media_duplicated(CHAT_1, 5, true).len(), 1);
+        assert_eq!(repo.last_media_duplicated(CHAT_2, 5, false).len(), 0);
+        assert_eq!(repo.list_duplicates(1).len(), 1);
+    }
+
+    fn mappings(repo: &dyn Repository<Media>) {
+        assert!(repo.find_mapping(1048576, CHAT_1).is_none());
+        assert!(repo.insert_mapping(1048576, CHAT_1, "AQADiq4xG--XSVd4"));
+        let mapping = repo.find_mapping(1048576, CHAT_1).unwrap();
+        assert_eq!(mapping.unique_id, "AQADiq4xG--XSVd4");
+
+        let deleted: UpdateDeleteMessages = serde_json::from_value(serde_json::json!({
+            "@type": "updateDeleteMessages",
+            "chat_id": CHAT_1,
+            "message_ids": [1048576],
+            "is_permanent": true,
+            "from_cache": false
+        }))
+        .unwrap();
+        repo.delete_item(deleted);
+        assert!(repo
+            .item_exists(sdo(CHAT_1, 416, "photo", "AQADiq4xG--XSVd4"), true)
+            .is_none());
+    }
+
+    fn groups(repo: &dyn Repository<Media>) {
+        assert!(repo.get_group(1592783264).is_none());
+        let group = Group {
+            supergroup_id: 1592783264,
+            chat_id: CHAT_2,
+            offset: 200,
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_group(group));
+        let group = repo.get_group(1592783264).unwrap();
+        assert_eq!(group.chat_id, CHAT_2);
+        assert_eq!(group.offset, 200);
+    }
+
+    fn configs(repo: &dyn Repository<Media>) {
+        let config = repo.get_config(CHAT_2);
+        assert_eq!(config.window, ChatConfig::new(CHAT_2).window);
+
+        let mut config = ChatConfig::new(CHAT_2);
+        config.set_window_days(1);
+        assert!(repo.insert_config(config));
+        assert_eq!(repo.get_config(CHAT_2).window_days(), 1);
+        assert_eq!(repo.get_config(CHAT_1).window, ChatConfig::new(CHAT_1).window);
+
+        let expired = sdo(CHAT_2, 500, "photo", "AQADiq4xG--XSQr3");
+        assert!(repo.insert_item(expired.clone(), true));
+        assert!(repo.item_exists(expired.clone(), true).is_some());
+        let mut config = repo.get_config(CHAT_2);
+        config.window = -1;
+        assert!(repo.insert_config(config));
+        assert!(repo.item_exists(expired, true).is_none());
+    }
+
+    fn phashes(repo: &dyn Repository<Media>) {
+        let hash: u64 = 0xF0F0_F0F0_0F0F_0F0F;
+        assert!(repo.find_similar_phash(CHAT_1, hash, 4).is_none());
+        let phash = PHash {
+            chat_id: CHAT_1,
+            msg_id: 600,
+            hash,
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_phash(phash));
+        assert_eq!(repo.find_similar_phash(CHAT_1, hash ^ 0b111, 4).unwrap().msg_id, 600);
+        assert!(repo.find_similar_phash(CHAT_1, hash ^ 0b11111, 4).is_none());
+        assert!(repo.find_similar_phash(CHAT_2, hash, 4).is_none());
+        // photo and text hashes never match each other
+        assert!(repo.find_similar_fingerprint(CHAT_1, hash, 4).is_none());
+        let fingerprint = PHash {
+            chat_id: CHAT_1,
+            msg_id: 601,
+            hash: !hash,
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_fingerprint(fingerprint));
+        assert_eq!(repo.find_similar_fingerprint(CHAT_1, !hash ^ 0b11, 4).unwrap().msg_id, 601);
+        assert!(repo.find_similar_fingerprint(CHAT_2, !hash, 4).is_none());
+        assert_eq!(repo.find_similar_phash(CHAT_1, hash, 4).unwrap().msg_id, 600);
+    }
+
+    fn network(repo: &dyn Repository<Media>) {
+        let shared = sdo(CHAT_2, 700, "url", "https://youtube.com/watch?v=GCI0NMgVfPk");
+        assert!(repo.find_in_network(&shared.unique_id, &[CHAT_1, CHAT_2]).is_none());
+        let mut config = repo.get_config(CHAT_2);
+        config.window = ChatConfig::new(CHAT_2).window;
+        assert!(repo.insert_config(config));
+        assert!(repo.insert_item(shared.clone(), false));
+
+        let found = repo.find_in_network(&shared.unique_id, &[CHAT_1, CHAT_2]).unwrap();
+        assert_eq!(found.chat_id, CHAT_2);
+        assert_eq!(found.msg_id, 700);
+        assert!(repo.find_in_network(&shared.unique_id, &[CHAT_1]).is_none());
+        assert!(repo.find_in_network("https://youtube.com/watch?v=other", &[CHAT_2]).is_none());
+        assert!(repo.find_in_network(&shared.unique_id, &[]).is_none());
+    }
+
+    /// Basic groups and private chats have ids shorter than supergroups
+    fn short_chat_ids(repo: &dyn Repository<Media>) {
+        let basic_group = -4242;
+        let private_chat = USER_2;
+        assert!(repo.insert_user(&user(USER_2), chat(basic_group)));
+        assert!(repo.insert_user(&user(USER_2), chat(private_chat)));
+        assert!(repo.chat_dbuser_exists(USER_2, basic_group));
+        assert!(!repo.chat_dbuser_exists(USER_1, basic_group));
+        assert_eq!(repo.list_user_groups(basic_group, USER_2).len(), 3);
+
+        let mut chat_ids = repo.get_chat_ids();
+        chat_ids.sort();
+        assert_eq!(chat_ids, vec![CHAT_2, CHAT_1, basic_group, private_chat]);
+
+        let photo = sdo(basic_group, 12, "photo", "AQADiq4xG--XSVd4");
+        assert!(repo.insert_item(photo.clone(), true));
+        assert_eq!(repo.item_exists(photo, true).unwrap().chat_id, basic_group);
+        assert_eq!(repo.last_media_stored(basic_group, 5, false).len(), 1);
+        assert!(repo.insert_mapping(12, basic_group, "AQADiq4xG--XSVd4"));
+        assert_eq!(repo.find_mapping(12, basic_group).unwrap().chat_id, basic_group);
+    }
+
+    fn strikes(repo: &dyn Repository<Media>) {
+        assert!(repo.get_strikes(CHAT_1, USER_1).is_none());
+        let strikes = Strikes {
+            chat_id: CHAT_1,
+            user_id: USER_1,
+            count: 2,
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_strikes(strikes.clone()));
+        assert_eq!(repo.get_strikes(CHAT_1, USER_1).unwrap().count, 2);
+        assert!(repo.get_strikes(CHAT_2, USER_1).is_none());
+        assert!(repo.insert_strikes(Strikes { count: 3, ..strikes }));
+        assert_eq!(repo.get_strikes(CHAT_1, USER_1).unwrap().count, 3);
+
+        let mut config = repo.get_config(CHAT_1);
+        assert_eq!(config.policy, Policy::Delete);
+        config.policy = Policy::Escalate;
+        config.mute_minutes = 15;
+        assert!(repo.insert_config(config));
+        let config = repo.get_config(CHAT_1);
+        assert_eq!(config.policy, Policy::Escalate);
+        assert_eq!(config.mute_minutes, 15);
+    }
+
+    fn offenders(repo: &dyn Repository<Media>) {
+        assert!(repo.get_offender(CHAT_1, USER_1).is_none());
+        assert!(repo.top_offenders(CHAT_1, 5).is_empty());
+        let offender = |chat_id: i64, user_id: i64, count: u32| Offender {
+            chat_id,
+            user_id,
+            user_name: format!("User {}", user_id),
+            count,
+            last_chat_id: chat_id,
+            last_msg_id: 800 + count as i32,
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_offender(offender(CHAT_1, USER_1, 1)));
+        assert!(repo.insert_offender(offender(CHAT_1, USER_2, 4)));
+        assert!(repo.insert_offender(offender(CHAT_2, USER_1, 9)));
+        assert!(repo.insert_offender(offender(CHAT_1, USER_1, 2)));
+        assert_eq!(repo.get_offender(CHAT_1, USER_1).unwrap().count, 2);
+
+        let top = repo.top_offenders(CHAT_1, 5);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].user_id, top[0].count, top[0].last_msg_id), (USER_2, 4, 804));
+        assert_eq!(top[0].last_chat_id, CHAT_1);
+        assert_eq!((top[1].user_id, top[1].count), (USER_1, 2));
+        assert_eq!(repo.top_offenders(CHAT_1, 1).len(), 1);
+    }
+
+    fn allowlist(repo: &dyn Repository<Media>) {
+        assert!(repo.list_allowed(CHAT_1).is_empty());
+        assert!(!repo.is_allowed(CHAT_1, USER_1));
+        assert!(repo.insert_allowed(CHAT_1, USER_1));
+        assert!(repo.insert_allowed(CHAT_1, USER_2));
+        assert!(repo.insert_allowed(CHAT_1, USER_1));
+        assert!(repo.insert_allowed(CHAT_2, USER_1));
+        assert!(repo.is_allowed(CHAT_1, USER_1));
+        assert!(!repo.is_allowed(CHAT_2, USER_2));
+        let mut allowed = repo.list_allowed(CHAT_1);
+        allowed.sort();
+        assert_eq!(allowed, vec![USER_2, USER_1]);
+        assert_eq!(repo.list_allowed(CHAT_2), vec![USER_1]);
+        assert!(repo.delete_allowed(CHAT_1, USER_1));
+        assert!(!repo.is_allowed(CHAT_1, USER_1));
+        assert!(repo.is_allowed(CHAT_2, USER_1));
+        assert_eq!(repo.list_allowed(CHAT_1), vec![USER_2]);
+        assert!(repo.delete_allowed(CHAT_1, USER_2));
+        assert!(repo.delete_allowed(CHAT_2, USER_1));
+    }
+
+    fn ban_plans(repo: &dyn Repository<Media>) {
+        assert!(repo.get_ban_plan("5f1c2e3a9b7d4c60").is_none());
+        let plan = BanPlan {
+            token: String::from("5f1c2e3a9b7d4c60"),
+            chat_id: CHAT_1,
+            admin_id: USER_1,
+            users: repo.inactive_users_before(10),
+            reason: String::from("inactive for 10 days"),
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.insert_ban_plan(plan));
+        let stored = repo.get_ban_plan("5f1c2e3a9b7d4c60").unwrap();
+        assert_eq!((stored.chat_id, stored.admin_id), (CHAT_1, USER_1));
+        assert_eq!(stored.users.len(), 1);
+        assert_eq!(stored.users[0].user_id, USER_2);
+        assert!(repo.get_ban_plan("5f1c2e3a9b7d4c61").is_none());
+        assert!(repo.take_ban_plan("5f1c2e3a9b7d4c61").is_none());
+        assert_eq!(repo.take_ban_plan("5f1c2e3a9b7d4c60").unwrap().chat_id, CHAT_1);
+        assert!(repo.take_ban_plan("5f1c2e3a9b7d4c60").is_none());
+        assert!(repo.get_ban_plan("5f1c2e3a9b7d4c60").is_none());
+    }
+
+    fn bans(repo: &dyn Repository<Media>) {
+        assert!(repo.list_bans(CHAT_1, 0).is_empty());
+        let now = Utc::now().timestamp();
+        let ban = |user_id: i64, chat_id: i64, error: Option<&str>, timestamp: i64| Ban {
+            user_id,
+            chat_id,
+            user_name: format!("User {}", user_id),
+            reason: String::from("inactive for 10 days"),
+            admin_id: USER_1,
+            error: error.map(String::from),
+            timestamp,
+        };
+        assert!(repo.insert_ban(ban(USER_2, CHAT_1, None, now - 10)));
+        assert!(repo.insert_ban(ban(42, CHAT_1, Some("Bad Request: user not found"), now)));
+        assert!(repo.insert_ban(ban(USER_2, CHAT_2, None, now)));
+
+        let bans = repo.list_bans(CHAT_1, 0);
+        assert_eq!(bans.len(), 2);
+        assert_eq!(bans[0].user_id, 42);
+        assert_eq!(bans[0].error.as_deref(), Some("Bad Request: user not found"));
+        assert_eq!((bans[1].user_id, bans[1].admin_id), (USER_2, USER_1));
+        assert!(bans[1].error.is_none());
+        assert_eq!(repo.list_bans(CHAT_1, 1).len(), 1);
+    }
+
+    fn audit(repo: &dyn Repository<Media>) {
+        assert!(repo.list_audit(CHAT_1, None, 0).is_empty());
+        let now = Utc::now().timestamp();
+        let entry = |action: AuditAction, user_id: i64, msg_id: i32, timestamp: i64| AuditEntry {
+            action,
+            chat_id: CHAT_1,
+            user_id,
+            msg_id,
+            actor_id: 0,
+            rule: String::from("unique_id"),
+            timestamp,
+        };
+        assert!(repo.insert_audit(entry(AuditAction::Duplicate, USER_1, 900, now - 2)));
+        assert!(repo.insert_audit(entry(AuditAction::Delete, USER_1, 900, now - 2)));
+        assert!(repo.insert_audit(entry(AuditAction::Duplicate, USER_2, 901, now - 1)));
+        assert!(repo.insert_audit(AuditEntry {
+            chat_id: CHAT_2,
+            ..entry(AuditAction::Ban, USER_2, 0, now)
+        }));
+
+        let entries = repo.list_audit(CHAT_1, None, 0);
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].action, entries[0].user_id), (AuditAction::Duplicate, USER_2));
+        assert_eq!(repo.list_audit(CHAT_1, None, 2).len(), 2);
+
+        let entries = repo.list_audit(CHAT_1, Some(USER_1), 0);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.user_id == USER_1 && entry.msg_id == 900));
+        assert_eq!(repo.list_audit(CHAT_2, Some(USER_1), 0).len(), 0);
+        assert_eq!(repo.list_audit(CHAT_2, Some(USER_2), 0)[0].action, AuditAction::Ban);
+
+        // a burst of entries in the same clock tick keeps every one
+        for msg_id in 910..960 {
+            assert!(repo.insert_audit(entry(AuditAction::Delete, USER_1, msg_id, now)));
+        }
+        assert_eq!(repo.list_audit(CHAT_1, Some(USER_1), 0).len(), 52);
+        assert_eq!(repo.list_audit(CHAT_1, Some(USER_1), 1)[0].msg_id, 959);
+    }
+
+    fn message_media(repo: &dyn Repository<Media>) {
+        let first = "https://youtu.be/GCI0NMgVfPk";
+        let second = "twitter.com/plaforscience/status/1379526168513277960";
+        assert!(repo.insert_item(sdo(CHAT_2, 970, "url", first), false));
+        assert!(repo.insert_item(sdo(CHAT_2, 970, "url", second), false));
+        assert!(repo.insert_item(sdo(CHAT_2, 971, "url", "https://highlander.rs"), false));
+        assert!(repo.insert_duplicate(sdo(CHAT_2, 970, "url", "https://t.me/highlander")));
+        assert_eq!(repo.message_media(CHAT_2, 970, false).len(), 2);
+        assert_eq!(repo.message_media(CHAT_2, 970, true).len(), 1);
+        assert!(repo.message_media(CHAT_1, 970, false).is_empty());
+
+        assert!(repo.delete_media(CHAT_2, second));
+        let media = repo.message_media(CHAT_2, 970, false);
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].unique_id, first);
+        assert!(repo.item_exists(sdo(CHAT_2, 972, "url", second), false).is_none());
+        assert!(repo.find_in_network(second, &[CHAT_2]).is_none());
+    }
+
+    fn albums(repo: &dyn Repository<Media>) {
+        let album = Album {
+            chat_id: CHAT_1,
+            media_group_id: String::from("13054627816521573"),
+            msg_ids: vec![980, 981, 982],
+            timestamp: Utc::now().timestamp(),
+        };
+        assert!(repo.find_album(CHAT_1, 981).is_none());
+        assert!(repo.get_album(CHAT_1, "13054627816521573").is_none());
+        assert!(repo.insert_album(album.clone()));
+        assert_eq!(repo.get_album(CHAT_1, "13054627816521573"), Some(album.clone()));
+        assert!(repo.get_album(CHAT_2, "13054627816521573").is_none());
+        assert_eq!(repo.find_album(CHAT_1, 981), Some(album.clone()));
+        assert_eq!(repo.find_album(CHAT_1, 982), Some(album));
+        assert!(repo.find_album(CHAT_1, 983).is_none());
+        assert!(repo.find_album(CHAT_2, 981).is_none());
+    }
+
+    fn deletions(repo: &dyn Repository<Media>) {
+        assert!(repo.list_deletions().is_empty());
+        let deletion = |chat_id: i64, msg_id: i32, due: i64| Deletion { chat_id, msg_id, due };
+        assert!(repo.insert_deletion(deletion(CHAT_1, 950, 1633073100)));
+        assert!(repo.insert_deletion(deletion(CHAT_2, 951, 1633072900)));
+        assert!(repo.insert_deletion(deletion(CHAT_1, 952, 1633073000)));
+        assert_eq!(
+            repo.list_deletions(),
+            vec![
+                deletion(CHAT_2, 951, 1633072900),
+                deletion(CHAT_1, 952, 1633073000),
+                deletion(CHAT_1, 950, 1633073100)
+            ]
+        );
+
+        assert!(repo.delete_deletion(CHAT_1, 952));
+        // deleting twice is fine, the scheduler may race a restart
+        assert!(repo.delete_deletion(CHAT_1, 952));
+        assert_eq!(repo.list_deletions().len(), 2);
+        assert!(repo.delete_deletion(CHAT_1, 950));
+        assert!(repo.delete_deletion(CHAT_2, 951));
+        assert!(repo.list_deletions().is_empty());
+    }
+}
@@ -1,96 +1,190 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::env;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use rtdlib::types::UpdateDeleteMessages;
-use teloxide::types::{Chat, ChatKind, User};
+use teloxide::types::{Chat, User};
 
 use bincode;
 use chrono::offset::Utc;
 use chrono::Duration;
 
+use serde::de::DeserializeOwned;
+
 use rocksdb::{
-    ColumnFamilyDescriptor, CompactionDecision, IteratorMode, Options, SliceTransform, DB,
+    ColumnFamilyDescriptor, CompactionDecision, IteratorMode, Options, SliceTransform, WriteBatch,
+    DB,
 };
 
 use itertools::Itertools;
 
 use super::models::User as DBUser;
-use super::models::{Group, Mapping, Media, SDO};
+use super::models::{
+    Album, AuditEntry, Ban, BanPlan, ChatConfig, Deletion, Group, Mapping, Media, Offender,
+    PHash, Strikes, SDO, DEFAULT_WINDOW_SECS,
+};
+use super::keys::{chat_prefix, index_key, index_prefix, Key, CHAT_PREFIX_LEN};
+use super::phash::hamming;
 use super::repository::*;
 
-const FOUR_DAYS_SECS: i64 = 345600;
+type Windows = Arc<RwLock<HashMap<i64, i64>>>;
 
-#[allow(unused_variables)]
-fn media_ttl_filter(level: u32, key: &[u8], value: &[u8]) -> CompactionDecision {
+/// Tells apart audit entries written in the same clock tick
+static AUDIT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn window_for(windows: &Windows, chat_id: i64) -> i64 {
+    match windows.read() {
+        Ok(w) => *w.get(&chat_id).unwrap_or(&DEFAULT_WINDOW_SECS),
+        Err(_) => DEFAULT_WINDOW_SECS,
+    }
+}
+
+fn media_ttl_filter(windows: Windows) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision {
     use self::CompactionDecision::*;
-    let media: Media = bincode::deserialize(value).unwrap();
-    let now = Utc::now().timestamp();
-    if now - media.timestamp > FOUR_DAYS_SECS {
-        Remove
-    } else {
-        Keep
+    move |_level, _key, value| {
+        let now = Utc::now().timestamp();
+        match bincode::deserialize::<Media>(value) {
+            Ok(media) if now - media.timestamp > window_for(&windows, media.chat_id) => Remove,
+            _ => Keep,
+        }
     }
 }
 
-#[allow(unused_variables)]
-fn users_ttl_filter(level: u32, key: &[u8], value: &[u8]) -> CompactionDecision {
+fn users_ttl_filter(windows: Windows) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision {
     use self::CompactionDecision::*;
-    let user: DBUser = bincode::deserialize(value).unwrap();
-    let now = Utc::now().timestamp();
-    if now - user.timestamp > FOUR_DAYS_SECS {
-        Remove
-    } else {
-        Keep
+    move |_level, _key, value| {
+        let now = Utc::now().timestamp();
+        match bincode::deserialize::<DBUser>(value) {
+            Ok(user) if now - user.timestamp > window_for(&windows, user.chat_id) => Remove,
+            _ => Keep,
+        }
     }
 }
 
-#[allow(unused_variables)]
-fn mappings_ttl_filter(level: u32, key: &[u8], value: &[u8]) -> CompactionDecision {
+fn mappings_ttl_filter(windows: Windows) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision {
     use self::CompactionDecision::*;
-    let mapping: Mapping = bincode::deserialize(value).unwrap();
-    let now = Utc::now().timestamp();
-    if now - mapping.timestamp > FOUR_DAYS_SECS {
-        Remove
-    } else {
-        Keep
+    move |_level, _key, value| {
+        let now = Utc::now().timestamp();
+        match bincode::deserialize::<Mapping>(value) {
+            Ok(mapping) if now - mapping.timestamp > window_for(&windows, mapping.chat_id) => Remove,
+            _ => Keep,
+        }
     }
 }
 
-fn key(k: &[u8]) -> Box<[u8]> {
-    k.to_vec().into_boxed_slice()
+fn ban_plans_ttl_filter(_level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
+    use self::CompactionDecision::*;
+    match bincode::deserialize::<BanPlan>(value) {
+        Ok(plan) if !plan.is_expired(Utc::now().timestamp()) => Keep,
+        _ => Remove,
+    }
 }
 
-fn user_to_db(user: &User, chat: Arc<Chat>) -> DBUser {
-    let unknown = String::from("Unknown");
-    let chat_name = match &chat.kind {
-        ChatKind::Public(public) => public.title.as_ref().unwrap_or(&unknown) as &str,
-        ChatKind::Private(_) => unknown.as_str(),
-    };
-
-    let user_name = user.username.as_ref().unwrap_or(&user.first_name);
-    DBUser {
-        user_id: user.id,
-        chat_id: chat.id,
-        user_name: user_name.to_string(),
-        chat_name: chat_name.into(),
-        timestamp: Utc::now().timestamp(),
+fn phashes_ttl_filter(windows: Windows) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision {
+    use self::CompactionDecision::*;
+    move |_level, _key, value| {
+        let now = Utc::now().timestamp();
+        match bincode::deserialize::<PHash>(value) {
+            Ok(phash) if now - phash.timestamp > window_for(&windows, phash.chat_id) => Remove,
+            _ => Keep,
+        }
     }
 }
 
-fn sdo_to_media(sdo: SDO) -> Media {
-    Media {
-        unique_id: sdo.unique_id,
-        chat_id: sdo.chat.id,
-        msg_id: sdo.msg_id,
-        file_type: sdo.file_type,
-        file_id: sdo.file_id.unwrap_or("".into()),
-        timestamp: Utc::now().timestamp(),
+fn albums_ttl_filter(windows: Windows) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision {
+    use self::CompactionDecision::*;
+    move |_level, _key, value| {
+        let now = Utc::now().timestamp();
+        match bincode::deserialize::<Album>(value) {
+            Ok(album) if now - album.timestamp > window_for(&windows, album.chat_id) => Remove,
+            _ => Keep,
+        }
+    }
+}
+
+/// Column families whose keys start with the 8 byte chat id
+const CHAT_CFS: [&str; 14] = [
+    "media",
+    "users",
+    "mappings",
+    "duplicates",
+    "groups",
+    "phashes",
+    "strikes",
+    "offenders",
+    "bans",
+    "audit_log",
+    "deletions",
+    "albums",
+    "fingerprints",
+    "allowlist",
+];
+
+/// Marker in the default column family, absent on databases with string keys
+const KEY_FORMAT: &[u8] = b"key_format";
+const KEY_FORMAT_VERSION: &[u8] = b"2";
+/// Operations per write batch while migrating keys
+const MIGRATION_BATCH: usize = 10000;
+
+/// Marker in the default column family, absent while the allowlist is shared by every chat
+const ALLOWLIST_FORMAT: &[u8] = b"allowlist_format";
+const ALLOWLIST_FORMAT_VERSION: &[u8] = b"2";
+
+const GROUP_SUFFIX: &[u8] = b"group";
+const CONFIG_SUFFIX: &[u8] = b"config";
+
+/// Rebuilds the binary key of a legacy entry from its value
+fn migrated_key(cf: &str, old_key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    match cf {
+        "media" | "duplicates" => bincode::deserialize::<Media>(value)
+            .ok()
+            .map(|media| Key::with_str(media.chat_id, &media.unique_id).encode()),
+        "unique_ids" => bincode::deserialize::<Media>(value)
+            .ok()
+            .map(|media| index_key(&media.unique_id, media.chat_id)),
+        "users" => bincode::deserialize::<DBUser>(value)
+            .ok()
+            .map(|user| Key::with_id(user.chat_id, user.user_id).encode()),
+        "mappings" => bincode::deserialize::<Mapping>(value)
+            .ok()
+            .map(|mapping| Key::with_id(mapping.chat_id, mapping.api_id).encode()),
+        "phashes" => bincode::deserialize::<PHash>(value)
+            .ok()
+            .map(|phash| Key::with_id(phash.chat_id, phash.msg_id as i64).encode()),
+        "groups" if old_key.starts_with(b"config_") => serde_json::from_slice::<ChatConfig>(value)
+            .ok()
+            .map(|config| Key::new(config.chat_id, CONFIG_SUFFIX).encode()),
+        "groups" => bincode::deserialize::<Group>(value)
+            .ok()
+            .map(|group| Key::new(group.supergroup_id, GROUP_SUFFIX).encode()),
+        _ => None,
+    }
+}
+
+/// Entries a key migration could not read are kept, so reads log and skip them
+fn stored<T: DeserializeOwned>(key: &[u8], value: &[u8]) -> Option<T> {
+    match bincode::deserialize(value) {
+        Ok(item) => Some(item),
+        Err(e) => {
+            log::error!(
+                "skipping unreadable {} {}: {}",
+                std::any::type_name::<T>(),
+                String::from_utf8_lossy(key),
+                e
+            );
+            None
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct RocksDBRepo {
     db: Arc<DB>,
+    windows: Windows,
+    /// Held while a ban plan is read and deleted so only one callback claims it
+    plans: Arc<Mutex<()>>,
 }
 
 impl Repository<Media> for RocksDBRepo {
@@ -99,41 +193,7 @@ impl Repository<Media> for RocksDBRepo {
             Ok(path) => path,
             Err(_) => String::from("."),
         };
-
-        let prefix_extractor = SliceTransform::create_fixed_prefix(14); // length of chat_id
-
-        let mut media_opts = Options::default();
-        media_opts.set_compaction_filter("ttl_media", media_ttl_filter);
-        let media_descriptor = ColumnFamilyDescriptor::new("media", media_opts);
-        let mut user_opts = Options::default();
-        user_opts.set_compaction_filter("ttl_user", users_ttl_filter);
-        let users_descriptor = ColumnFamilyDescriptor::new("users", user_opts);
-        let mut mappings_opts = Options::default();
-        mappings_opts.set_compaction_filter("ttl_mappings", mappings_ttl_filter);
-        let mappings_descriptor = ColumnFamilyDescriptor::new("mappings", mappings_opts);
-        let mut duplicates_opts = Options::default();
-        duplicates_opts.set_compaction_filter("ttl_duplicates", media_ttl_filter);
-        let duplicates_descriptor = ColumnFamilyDescriptor::new("duplicates", duplicates_opts);
-        let groups_opts = Options::default();
-        let groups_descriptor = ColumnFamilyDescriptor::new("groups", groups_opts);
-
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.create_missing_column_families(true);
-        opts.set_prefix_extractor(prefix_extractor);
-
-        let cfs = vec![
-            media_descriptor,
-            users_descriptor,
-            mappings_descriptor,
-            duplicates_descriptor,
-            groups_descriptor
-        ];
-
-        match DB::open_cf_descriptors(&opts, &format!("{}/.rocksdb", db_path), cfs) {
-            Err(e) => panic!("{}", e),
-            Ok(db) => RocksDBRepo { db: Arc::new(db) },
-        }
+        RocksDBRepo::open(&format!("{}/.rocksdb", db_path))
     }
 
     fn chat_user_exists(&self, user: &User, chat: Arc<Chat>) -> bool {
@@ -142,36 +202,28 @@ impl Repository<Media> for RocksDBRepo {
 
     fn chat_dbuser_exists(&self, user_id: i64, chat_id: i64) -> bool {
         let users_handle = self.db.cf_handle("users").unwrap();
-        let chat_id = chat_id.to_string();
-        let mut users_it = self.db.prefix_iterator_cf(users_handle, chat_id.as_bytes());
-        match users_it.find(|(k, _)| {
-            let key = String::from_utf8(k.to_vec()).unwrap();
-            let ids: Vec<&str> = key.split("_").collect();
-            if ids.is_empty() {
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.get_cf(users_handle, k.encode()) {
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(e) => {
+                log::error!("chat_dbuser_exists: {}", e);
                 false
-            } else {
-                match ids[1].parse::<i64>() {
-                    Ok(i) => i == user_id && ids[0] == chat_id,
-                    Err(_) => false,
-                }
             }
-        }) {
-            None => false,
-            Some(_) => true,
         }
     }
 
     fn update_user_timestamp(&self, user: &User, chat: Arc<Chat>) -> bool {
         let users_handle = self.db.cf_handle("users").unwrap();
         let dbuser = user_to_db(user, chat.clone());
-        let k = format!("{}_{}", chat.id, user.id);
-        log::info!("Update user key: {}", k);
+        log::info!("Update user key: {}_{}", chat.id, user.id);
+        let k = Key::with_id(chat.id, user.id);
         match bincode::serialize(&dbuser) {
             Err(e) => {
                 log::error!("update_user_timestamp: {}", e);
                 false
             }
-            Ok(v) => match self.db.put_cf(users_handle, key(k.as_bytes()), v) {
+            Ok(v) => match self.db.put_cf(users_handle, k.encode(), v) {
                 Err(e) => {
                     log::error!("update_user_timestamp: {}", e);
                     false
@@ -188,19 +240,15 @@ impl Repository<Media> for RocksDBRepo {
 
     #[allow(unused_variables)]
     fn item_exists(&self, sdo: SDO, is_media: bool) -> Option<Media> {
+        let window = window_for(&self.windows, sdo.chat.id);
         let media_handle = self.db.cf_handle("media").unwrap();
-        let chat_id = sdo.chat.id.to_string();
-        let mut media_it = self.db.prefix_iterator_cf(media_handle, chat_id.as_bytes());
-        match media_it.find(|(k, _)| {
-            let key = String::from_utf8(k.to_vec()).unwrap();
-            let prefix = key.get(..14);
-            let id = key.get(15..);
-            match (prefix, id) {
-                (Some(p), Some(k)) => p == chat_id && k == sdo.unique_id,
-                _ => false,
-            }
-        }) {
-            None => {
+        let k = Key::with_str(sdo.chat.id, &sdo.unique_id);
+        match self.db.get_cf(media_handle, k.encode()) {
+            Err(e) => {
+                log::error!("item_exists: {}", e);
+                None
+            }
+            Ok(None) => {
                 log::info!(
                     "item_exists: key {}_{} not found",
                     sdo.chat.id,
@@ -208,16 +256,22 @@ impl Repository<Media> for RocksDBRepo {
                 );
                 None
             }
-            Some(media_ser) => {
-                let media: Media = bincode::deserialize(&media_ser.1).unwrap();
-                log::info!("item_exists: media {:?} found", media);
-                Some(media)
+            Ok(Some(media_ser)) => {
+                let media: Media = stored(&k.encode(), &media_ser)?;
+                if Utc::now().timestamp() - media.timestamp > window {
+                    log::info!("item_exists: media {:?} found but expired", media);
+                    None
+                } else {
+                    log::info!("item_exists: media {:?} found", media);
+                    Some(media)
+                }
             }
         }
     }
 
     fn insert_item(&self, sdo: SDO, _is_media: bool) -> bool {
         let media_handle = self.db.cf_handle("media").unwrap();
+        let index_handle = self.db.cf_handle("unique_ids").unwrap();
         let chat_id = sdo.chat.id;
         let media = sdo_to_media(sdo);
         match bincode::serialize(&media) {
@@ -226,14 +280,17 @@ impl Repository<Media> for RocksDBRepo {
                 false
             }
             Ok(media_ser) => {
-                let k = format!("{}_{}", chat_id, media.unique_id);
-                match self.db.put_cf(media_handle, key(k.as_bytes()), media_ser) {
+                let k = Key::with_str(chat_id, &media.unique_id);
+                let mut batch = WriteBatch::default();
+                batch.put_cf(media_handle, k.encode(), &media_ser);
+                batch.put_cf(index_handle, index_key(&media.unique_id, chat_id), &media_ser);
+                match self.db.write(batch) {
                     Err(e) => {
                         log::error!("insert_item: {}", e);
                         false
                     }
                     Ok(_) => {
-                        log::info!("insert_item: {}", k);
+                        log::info!("insert_item: {}_{}", chat_id, media.unique_id);
                         true
                     }
                 }
@@ -251,10 +308,8 @@ impl Repository<Media> for RocksDBRepo {
                 false
             }
             Ok(media_ser) => {
-                let k = format!("{}_{}", chat_id, media.unique_id);
-                match self
-                    .db
-                    .put_cf(duplicates_handle, key(k.as_bytes()), media_ser)
+                let k = Key::with_str(chat_id, &media.unique_id);
+                match self.db.put_cf(duplicates_handle, k.encode(), media_ser)
                 {
                     Err(e) => {
                         log::error!("insert_duplicate: {}", e);
@@ -266,8 +321,40 @@ impl Repository<Media> for RocksDBRepo {
         }
     }
 
+    fn message_media(&self, chat_id: i64, msg_id: i32, is_duplicate: bool) -> Vec<Media> {
+        let cf = if is_duplicate { "duplicates" } else { "media" };
+        let handle = self.db.cf_handle(cf).unwrap();
+        let prefix = chat_prefix(chat_id);
+        let media_it = self.db.prefix_iterator_cf(handle, prefix);
+        media_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Media>(&k, &v_ser))
+            .filter(|media| media.msg_id == msg_id)
+            .collect()
+    }
+
+    fn delete_media(&self, chat_id: i64, unique_id: &str) -> bool {
+        let media_handle = self.db.cf_handle("media").unwrap();
+        let index_handle = self.db.cf_handle("unique_ids").unwrap();
+        let k = Key::with_str(chat_id, unique_id);
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(media_handle, k.encode());
+        batch.delete_cf(index_handle, index_key(unique_id, chat_id));
+        match self.db.write(batch) {
+            Err(e) => {
+                log::error!("delete_media: {}", e);
+                false
+            }
+            Ok(_) => {
+                log::info!("delete_media: {}_{}", chat_id, unique_id);
+                true
+            }
+        }
+    }
+
     fn delete_item(&self, deleted_messages: UpdateDeleteMessages) -> () {
         let media_handle = self.db.cf_handle("media").unwrap();
+        let index_handle = self.db.cf_handle("unique_ids").unwrap();
         let chat_id = deleted_messages.chat_id();
         for api_id in deleted_messages.message_ids() {
             match self.find_mapping(*api_id, chat_id) {
@@ -276,8 +363,11 @@ impl Repository<Media> for RocksDBRepo {
                 }
                 Some(mapping) => {
                     let unique_id = mapping.unique_id;
-                    let k = format!("{}_{}", chat_id, unique_id);
-                    match self.db.delete_cf(media_handle, key(k.as_bytes())) {
+                    let k = Key::with_str(chat_id, &unique_id);
+                    let mut batch = WriteBatch::default();
+                    batch.delete_cf(media_handle, k.encode());
+                    batch.delete_cf(index_handle, index_key(&unique_id, chat_id));
+                    match self.db.write(batch) {
                         Err(e) => log::error!("delete_item: {}", e),
                         Ok(_) => log::info!("Deleted {}_{}", chat_id, unique_id),
                     }
@@ -288,25 +378,18 @@ impl Repository<Media> for RocksDBRepo {
 
     fn find_mapping(&self, api_id: i64, chat_id: i64) -> Option<Mapping> {
         let mappings_handle = self.db.cf_handle("mappings").unwrap();
-        let chat_id = chat_id.to_string();
-        let mut mappings_it = self
-            .db
-            .prefix_iterator_cf(mappings_handle, chat_id.as_bytes());
-        match mappings_it.find(|(k, _)| {
-            let key = String::from_utf8(k.to_vec()).unwrap();
-            let ids: Vec<&str> = key.split("_").collect();
-            if ids.is_empty() {
-                false
-            } else {
-                ids[1].parse::<i64>().unwrap_or(0) == api_id && ids[0] == chat_id
+        let k = Key::with_id(chat_id, api_id);
+        match self.db.get_cf(mappings_handle, k.encode()) {
+            Err(e) => {
+                log::error!("find_mapping: {}", e);
+                None
             }
-        }) {
-            None => {
+            Ok(None) => {
                 log::info!("find_mapping: not found {}_{}", chat_id, api_id);
                 None
             }
-            Some(mapping_ser) => {
-                let mapping: Mapping = bincode::deserialize(&mapping_ser.1).unwrap();
+            Ok(Some(mapping_ser)) => {
+                let mapping: Mapping = stored(&k.encode(), &mapping_ser)?;
                 log::info!("find_mapping: found {:?}", mapping);
                 Some(mapping)
             }
@@ -327,10 +410,8 @@ impl Repository<Media> for RocksDBRepo {
                 false
             }
             Ok(mapping_ser) => {
-                let k = format!("{}_{}", chat_id, api_id);
-                match self
-                    .db
-                    .put_cf(mappings_handle, key(k.as_bytes()), mapping_ser)
+                let k = Key::with_id(chat_id, api_id);
+                match self.db.put_cf(mappings_handle, k.encode(), mapping_ser)
                 {
                     Err(e) => {
                         log::error!("insert_mapping: {}", e);
@@ -346,82 +427,23 @@ impl Repository<Media> for RocksDBRepo {
     }
 
     fn last_media_stored(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
-        let media_handle = self.db.cf_handle("media").unwrap();
-        let chat_id_str = chat_id.to_string();
-        let media_it = self
-            .db
-            .prefix_iterator_cf(media_handle, chat_id_str.as_bytes());
-        let mut media_vec = media_it
-            .filter(|(k, _)| {
-                let key = String::from_utf8(k.to_vec()).unwrap();
-                match key.get(..14) {
-                    Some(prefix) => prefix == chat_id_str,
-                    None => false,
-                }
-            })
-            .map(|(_, v_ser)| {
-                let media: Media = bincode::deserialize(&v_ser).unwrap();
-                (media.msg_id, media)
-            })
-            .filter(|tup| (tup.1.file_type == "url") == is_url)
-            .into_group_map()
-            .into_iter()
-            .map(|(_, g)| g.first().unwrap().clone())
-            .collect::<Vec<_>>();
-        media_vec.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-        media_vec.truncate(limit);
-        media_vec
+        self.last_media("media", chat_id, limit, is_url)
     }
 
     fn last_media_duplicated(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
-        let duplicates_handle = self.db.cf_handle("duplicates").unwrap();
-        let chat_id_str = chat_id.to_string();
-        let duplicates_it = self
-            .db
-            .prefix_iterator_cf(duplicates_handle, chat_id_str.as_bytes());
-        let mut duplicates_vec = duplicates_it
-            .filter(|(k, _)| {
-                let key = String::from_utf8(k.to_vec()).unwrap();
-                match key.get(..14) {
-                    Some(prefix) => prefix == chat_id_str,
-                    None => false,
-                }
-            })
-            .map(|(_, v_ser)| {
-                let duplicates: Media = bincode::deserialize(&v_ser).unwrap();
-                (duplicates.msg_id, duplicates)
-            })
-            .filter(|tup| (tup.1.file_type == "url") == is_url)
-            .into_group_map()
-            .into_iter()
-            .map(|(_, g)| g.first().unwrap().clone())
-            .collect::<Vec<_>>();
-        duplicates_vec.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-        duplicates_vec.truncate(limit);
-        duplicates_vec
+        self.last_media("duplicates", chat_id, limit, is_url)
     }
 
+    #[allow(unused_variables)]
     fn list_user_groups(&self, chat_id: i64, user_id: i64) -> Vec<DBUser> {
         let users_handle = self.db.cf_handle("users").unwrap();
-        let chat_id_str = chat_id.to_string();
-        let users_it = self
-            .db
-            .prefix_iterator_cf(users_handle, chat_id_str.as_bytes());
+        let users_it = self.db.iterator_cf(users_handle, IteratorMode::Start);
         let users_vec = users_it
-            .filter(|(k, _)| {
-                let key = String::from_utf8(k.to_vec()).unwrap();
-                match key.get(15..) {
-                    None => false,
-                    Some(id) => match id.parse::<i64>() {
-                        Ok(i) => i == user_id,
-                        Err(_) => false,
-                    },
-                }
-            })
-            .map(|(_, v_ser)| {
-                let user: DBUser = bincode::deserialize(&v_ser).unwrap();
-                user
+            .filter(|(k, _)| match Key::decode(k) {
+                Some(key) => key.suffix_id() == Some(user_id),
+                None => false,
             })
+            .filter_map(|(k, v_ser)| stored::<DBUser>(&k, &v_ser))
             .collect::<Vec<_>>();
         users_vec
     }
@@ -430,38 +452,22 @@ impl Repository<Media> for RocksDBRepo {
         let users_handle = self.db.cf_handle("users").unwrap();
         let users_it = self.db.iterator_cf(users_handle, IteratorMode::Start);
         let users_vec = users_it
-            .dedup_by(|(k1, _), (k2, _)| {
-                let key1 = String::from_utf8(k1.to_vec()).unwrap();
-                let key2 = String::from_utf8(k2.to_vec()).unwrap();
-                match (key1.get(..14), key2.get(..14)) {
-                    (Some(id1), Some(id2)) => id1 == id2,
-                    _ => false,
-                }
-            })
-            .map(|(k, _)| {
-                let key = String::from_utf8(k.to_vec()).unwrap();
-                match key.get(..14) {
-                    None => 0,
-                    Some(id) => match id.parse::<i64>() {
-                        Ok(i) => i,
-                        Err(_) => 0,
-                    },
-                }
-            })
+            .filter_map(|(k, _)| Key::decode(&k).map(|key| key.chat_id))
+            .dedup()
             .collect::<Vec<_>>();
         users_vec
     }
 
     fn insert_dbuser(&self, user: DBUser) -> bool {
         let users_handle = self.db.cf_handle("users").unwrap();
-        let k = format!("{}_{}", user.chat_id, user.user_id);
-        log::info!("Insert DBUser key: {}", k);
+        log::info!("Insert DBUser key: {}_{}", user.chat_id, user.user_id);
+        let k = Key::with_id(user.chat_id, user.user_id);
         match bincode::serialize(&user) {
             Err(e) => {
                 log::error!("insert_dbuser: {}", e);
                 false
             }
-            Ok(v) => match self.db.put_cf(users_handle, key(k.as_bytes()), v) {
+            Ok(v) => match self.db.put_cf(users_handle, k.encode(), v) {
                 Err(e) => {
                     log::error!("insert_dbuser: {}", e);
                     false
@@ -475,10 +481,7 @@ impl Repository<Media> for RocksDBRepo {
         let media_handle = self.db.cf_handle("media").unwrap();
         let media_it = self.db.iterator_cf(media_handle, IteratorMode::Start);
         let mut media_vec = media_it
-            .map(|(_, v_ser)| {
-                let media: Media = bincode::deserialize(&v_ser).unwrap();
-                media
-            })
+            .filter_map(|(k, v_ser)| stored::<Media>(&k, &v_ser))
             .collect::<Vec<_>>();
         if limit > 0 {
             media_vec.truncate(limit);
@@ -490,10 +493,7 @@ impl Repository<Media> for RocksDBRepo {
         let users_handle = self.db.cf_handle("users").unwrap();
         let users_it = self.db.iterator_cf(users_handle, IteratorMode::Start);
         let mut users_vec = users_it
-            .map(|(_, v_ser)| {
-                let user: DBUser = bincode::deserialize(&v_ser).unwrap();
-                user
-            })
+            .filter_map(|(k, v_ser)| stored::<DBUser>(&k, &v_ser))
             .collect::<Vec<_>>();
         if limit > 0 {
             users_vec.truncate(limit);
@@ -505,10 +505,7 @@ impl Repository<Media> for RocksDBRepo {
         let media_handle = self.db.cf_handle("duplicates").unwrap();
         let media_it = self.db.iterator_cf(media_handle, IteratorMode::Start);
         let mut media_vec = media_it
-            .map(|(_, v_ser)| {
-                let media: Media = bincode::deserialize(&v_ser).unwrap();
-                media
-            })
+            .filter_map(|(k, v_ser)| stored::<Media>(&k, &v_ser))
             .collect::<Vec<_>>();
         if limit > 0 {
             media_vec.truncate(limit);
@@ -520,10 +517,8 @@ impl Repository<Media> for RocksDBRepo {
         let users_handle = self.db.cf_handle("users").unwrap();
         let users_it = self.db.iterator_cf(users_handle, IteratorMode::Start);
         let users_vec = users_it
-            .map(|(_, v_ser)| {
-                let user: DBUser = bincode::deserialize(&v_ser).unwrap();
-                (user.user_id, user)
-            })
+            .filter_map(|(k, v_ser)| stored::<DBUser>(&k, &v_ser))
+            .map(|user| (user.user_id, user))
             .into_group_map()
             .into_iter()
             .map(|(_, g)| {
@@ -540,10 +535,7 @@ impl Repository<Media> for RocksDBRepo {
         let users_handle = self.db.cf_handle("users").unwrap();
         let users_it = self.db.iterator_cf(users_handle, IteratorMode::Start);
         let users_vec = users_it
-            .map(|(_, v_ser)| {
-                let user: DBUser = bincode::deserialize(&v_ser).unwrap();
-                user
-            })
+            .filter_map(|(k, v_ser)| stored::<DBUser>(&k, &v_ser))
             .filter(|user| {
                 let offset_day = Utc::now() - Duration::days(ndays);
                 user.timestamp < offset_day.timestamp()
@@ -554,14 +546,14 @@ impl Repository<Media> for RocksDBRepo {
 
     fn insert_group(&self, group: Group) -> bool {
         let groups_handle = self.db.cf_handle("groups").unwrap();
-        let k = format!("{}", group.supergroup_id);
-        log::info!("Insert Group key: {}", k);
+        log::info!("Insert Group key: {}", group.supergroup_id);
+        let k = Key::new(group.supergroup_id, GROUP_SUFFIX);
         match bincode::serialize(&group) {
             Err(e) => {
                 log::error!("insert_dbgroup: {}", e);
                 false
             }
-            Ok(v) => match self.db.put_cf(groups_handle, key(k.as_bytes()), v) {
+            Ok(v) => match self.db.put_cf(groups_handle, k.encode(), v) {
                 Err(e) => {
                     log::error!("insert_group: {}", e);
                     false
@@ -573,29 +565,814 @@ impl Repository<Media> for RocksDBRepo {
 
     fn get_group(&self, supergroup_id: i64) -> Option<Group> {
         let groups_handle = self.db.cf_handle("groups").unwrap();
-        let mut groups_it = self.db.iterator_cf(groups_handle, IteratorMode::Start);
-        match groups_it.find(|(k, _)| {
-            let key = String::from_utf8(k.to_vec()).unwrap();
-            match key.parse::<i64>() {
-                Ok(id) => id == supergroup_id,
-                Err(_) => false
-            }
-
-        }) {
-            Some(group_ser) => {
-                let group: Group = bincode::deserialize(&group_ser.1).unwrap();
-                Some(group)
-            },
-            None => {
+        let k = Key::new(supergroup_id, GROUP_SUFFIX);
+        match self.db.get_cf(groups_handle, k.encode()) {
+            Ok(Some(group_ser)) => stored::<Group>(&k.encode(), &group_ser),
+            Ok(None) => {
                 log::error!("get_group: {} not found", supergroup_id);
                 None
             }
+            Err(e) => {
+                log::error!("get_group: {}", e);
+                None
+            }
+        }
+    }
+
+    fn get_config(&self, chat_id: i64) -> ChatConfig {
+        let groups_handle = self.db.cf_handle("groups").unwrap();
+        let k = Key::new(chat_id, CONFIG_SUFFIX);
+        match self.db.get_cf(groups_handle, k.encode()) {
+            Ok(Some(config_ser)) => match serde_json::from_slice::<ChatConfig>(&config_ser) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::error!("get_config: {}", e);
+                    ChatConfig::new(chat_id)
+                }
+            },
+            Ok(None) => ChatConfig::new(chat_id),
+            Err(e) => {
+                log::error!("get_config: {}", e);
+                ChatConfig::new(chat_id)
+            }
+        }
+    }
+
+    fn insert_config(&self, config: ChatConfig) -> bool {
+        let groups_handle = self.db.cf_handle("groups").unwrap();
+        log::info!("Insert Config key: {}", config.chat_id);
+        let k = Key::new(config.chat_id, CONFIG_SUFFIX);
+        match serde_json::to_vec(&config) {
+            Err(e) => {
+                log::error!("insert_config: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(groups_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_config: {}", e);
+                    false
+                }
+                Ok(_) => {
+                    if let Ok(mut windows) = self.windows.write() {
+                        windows.insert(config.chat_id, config.window);
+                    }
+                    true
+                }
+            },
+        }
+    }
+
+    fn insert_phash(&self, phash: PHash) -> bool {
+        self.put_hash("phashes", "insert_phash", phash)
+    }
+
+    fn find_similar_phash(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        self.similar_hash("phashes", chat_id, hash, max_distance)
+    }
+
+    fn insert_fingerprint(&self, fingerprint: PHash) -> bool {
+        self.put_hash("fingerprints", "insert_fingerprint", fingerprint)
+    }
+
+    fn find_similar_fingerprint(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        self.similar_hash("fingerprints", chat_id, hash, max_distance)
+    }
+
+    fn find_in_network(&self, unique_id: &str, chat_ids: &[i64]) -> Option<Media> {
+        let index_handle = self.db.cf_handle("unique_ids").unwrap();
+        let prefix = index_prefix(unique_id);
+        let now = Utc::now().timestamp();
+        let index_it = self.db.prefix_iterator_cf(index_handle, &prefix);
+        index_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Media>(&k, &v_ser))
+            .filter(|media| chat_ids.contains(&media.chat_id))
+            .filter(|media| now - media.timestamp <= window_for(&self.windows, media.chat_id))
+            .min_by_key(|media| media.timestamp)
+    }
+
+    fn get_strikes(&self, chat_id: i64, user_id: i64) -> Option<Strikes> {
+        let strikes_handle = self.db.cf_handle("strikes").unwrap();
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.get_cf(strikes_handle, k.encode()) {
+            Ok(Some(strikes_ser)) => stored::<Strikes>(&k.encode(), &strikes_ser),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("get_strikes: {}", e);
+                None
+            }
+        }
+    }
+
+    fn insert_strikes(&self, strikes: Strikes) -> bool {
+        let strikes_handle = self.db.cf_handle("strikes").unwrap();
+        let k = Key::with_id(strikes.chat_id, strikes.user_id);
+        match bincode::serialize(&strikes) {
+            Err(e) => {
+                log::error!("insert_strikes: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(strikes_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_strikes: {}", e);
+                    false
+                }
+                Ok(_) => {
+                    log::info!("insert_strikes: {:?}", strikes);
+                    true
+                }
+            },
+        }
+    }
+
+    fn get_offender(&self, chat_id: i64, user_id: i64) -> Option<Offender> {
+        let offenders_handle = self.db.cf_handle("offenders").unwrap();
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.get_cf(offenders_handle, k.encode()) {
+            Ok(Some(offender_ser)) => stored::<Offender>(&k.encode(), &offender_ser),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("get_offender: {}", e);
+                None
+            }
+        }
+    }
+
+    fn insert_offender(&self, offender: Offender) -> bool {
+        let offenders_handle = self.db.cf_handle("offenders").unwrap();
+        let k = Key::with_id(offender.chat_id, offender.user_id);
+        match bincode::serialize(&offender) {
+            Err(e) => {
+                log::error!("insert_offender: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(offenders_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_offender: {}", e);
+                    false
+                }
+                Ok(_) => true,
+            },
+        }
+    }
+
+    fn top_offenders(&self, chat_id: i64, limit: usize) -> Vec<Offender> {
+        let offenders_handle = self.db.cf_handle("offenders").unwrap();
+        let prefix = chat_prefix(chat_id);
+        let offenders_it = self.db.prefix_iterator_cf(offenders_handle, prefix);
+        let mut offenders_vec = offenders_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Offender>(&k, &v_ser))
+            .collect::<Vec<_>>();
+        offenders_vec.sort_by(|a, b| b.count.cmp(&a.count).then(b.timestamp.cmp(&a.timestamp)));
+        if limit > 0 {
+            offenders_vec.truncate(limit);
+        }
+        offenders_vec
+    }
+
+    fn insert_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let allowlist_handle = self.db.cf_handle("allowlist").unwrap();
+        let timestamp = Utc::now().timestamp();
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.put_cf(allowlist_handle, k.encode(), timestamp.to_be_bytes()) {
+            Err(e) => {
+                log::error!("insert_allowed: {}", e);
+                false
+            }
+            Ok(_) => {
+                log::info!("insert_allowed: {} on chat {}", user_id, chat_id);
+                true
+            }
+        }
+    }
+
+    fn delete_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let allowlist_handle = self.db.cf_handle("allowlist").unwrap();
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.delete_cf(allowlist_handle, k.encode()) {
+            Err(e) => {
+                log::error!("delete_allowed: {}", e);
+                false
+            }
+            Ok(_) => {
+                log::info!("delete_allowed: {} on chat {}", user_id, chat_id);
+                true
+            }
+        }
+    }
+
+    fn is_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let allowlist_handle = self.db.cf_handle("allowlist").unwrap();
+        let k = Key::with_id(chat_id, user_id);
+        match self.db.get_cf(allowlist_handle, k.encode()) {
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(e) => {
+                log::error!("is_allowed: {}", e);
+                false
+            }
         }
     }
+
+    fn list_allowed(&self, chat_id: i64) -> Vec<i64> {
+        let allowlist_handle = self.db.cf_handle("allowlist").unwrap();
+        let prefix = chat_prefix(chat_id);
+        let allowlist_it = self.db.prefix_iterator_cf(allowlist_handle, prefix);
+        allowlist_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, _)| Key::decode(&k)?.suffix_id())
+            .collect()
+    }
+
+    fn insert_ban_plan(&self, plan: BanPlan) -> bool {
+        let ban_plans_handle = self.db.cf_handle("ban_plans").unwrap();
+        match bincode::serialize(&plan) {
+            Err(e) => {
+                log::error!("insert_ban_plan: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(ban_plans_handle, plan.token.as_bytes(), v) {
+                Err(e) => {
+                    log::error!("insert_ban_plan: {}", e);
+                    false
+                }
+                Ok(_) => {
+                    log::info!("insert_ban_plan: {} with {} users", plan.token, plan.users.len());
+                    true
+                }
+            },
+        }
+    }
+
+    fn get_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        let ban_plans_handle = self.db.cf_handle("ban_plans").unwrap();
+        match self.db.get_cf(ban_plans_handle, token.as_bytes()) {
+            Ok(Some(plan_ser)) => stored::<BanPlan>(token.as_bytes(), &plan_ser),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("get_ban_plan: {}", e);
+                None
+            }
+        }
+    }
+
+    fn take_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        let _claim = match self.plans.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let plan = self.get_ban_plan(token)?;
+        let ban_plans_handle = self.db.cf_handle("ban_plans").unwrap();
+        match self.db.delete_cf(ban_plans_handle, token.as_bytes()) {
+            Err(e) => {
+                log::error!("take_ban_plan: {}", e);
+                None
+            }
+            Ok(_) => Some(plan),
+        }
+    }
+
+    fn insert_ban(&self, ban: Ban) -> bool {
+        let bans_handle = self.db.cf_handle("bans").unwrap();
+        let mut suffix = ban.timestamp.to_be_bytes().to_vec();
+        suffix.extend_from_slice(&ban.user_id.to_be_bytes());
+        let k = Key::new(ban.chat_id, &suffix);
+        match bincode::serialize(&ban) {
+            Err(e) => {
+                log::error!("insert_ban: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(bans_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_ban: {}", e);
+                    false
+                }
+                Ok(_) => {
+                    log::info!("insert_ban: {:?}", ban);
+                    true
+                }
+            },
+        }
+    }
+
+    fn list_bans(&self, chat_id: i64, limit: usize) -> Vec<Ban> {
+        let bans_handle = self.db.cf_handle("bans").unwrap();
+        let prefix = chat_prefix(chat_id);
+        let bans_it = self.db.prefix_iterator_cf(bans_handle, prefix);
+        let mut bans_vec = bans_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Ban>(&k, &v_ser))
+            .collect::<Vec<_>>();
+        bans_vec.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        if limit > 0 {
+            bans_vec.truncate(limit);
+        }
+        bans_vec
+    }
+
+    fn insert_audit(&self, entry: AuditEntry) -> bool {
+        let audit_handle = self.db.cf_handle("audit_log").unwrap();
+        // nanoseconds keep entries in insertion order, the sequence number keeps entries of the
+        // same tick apart
+        let mut suffix = Utc::now().timestamp_nanos().to_be_bytes().to_vec();
+        suffix.extend_from_slice(&AUDIT_SEQ.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        let k = Key::new(entry.chat_id, &suffix);
+        match bincode::serialize(&entry) {
+            Err(e) => {
+                log::error!("insert_audit: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(audit_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_audit: {}", e);
+                    false
+                }
+                Ok(_) => true,
+            },
+        }
+    }
+
+    fn list_audit(&self, chat_id: i64, user_id: Option<i64>, limit: usize) -> Vec<AuditEntry> {
+        let audit_handle = self.db.cf_handle("audit_log").unwrap();
+        let prefix = chat_prefix(chat_id);
+        let audit_it = self.db.prefix_iterator_cf(audit_handle, prefix);
+        let mut audit_vec = audit_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<AuditEntry>(&k, &v_ser))
+            .filter(|entry| user_id.map_or(true, |id| entry.user_id == id))
+            .collect::<Vec<_>>();
+        audit_vec.reverse();
+        if limit > 0 {
+            audit_vec.truncate(limit);
+        }
+        audit_vec
+    }
+
+    fn insert_deletion(&self, deletion: Deletion) -> bool {
+        let deletions_handle = self.db.cf_handle("deletions").unwrap();
+        let k = Key::with_id(deletion.chat_id, deletion.msg_id as i64);
+        match bincode::serialize(&deletion) {
+            Err(e) => {
+                log::error!("insert_deletion: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(deletions_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_deletion: {}", e);
+                    false
+                }
+                Ok(_) => true,
+            },
+        }
+    }
+
+    fn delete_deletion(&self, chat_id: i64, msg_id: i32) -> bool {
+        let deletions_handle = self.db.cf_handle("deletions").unwrap();
+        let k = Key::with_id(chat_id, msg_id as i64);
+        match self.db.delete_cf(deletions_handle, k.encode()) {
+            Err(e) => {
+                log::error!("delete_deletion: {}", e);
+                false
+            }
+            Ok(_) => true,
+        }
+    }
+
+    fn list_deletions(&self) -> Vec<Deletion> {
+        let deletions_handle = self.db.cf_handle("deletions").unwrap();
+        let deletions_it = self.db.iterator_cf(deletions_handle, IteratorMode::Start);
+        deletions_it
+            .filter_map(|(k, v_ser)| stored::<Deletion>(&k, &v_ser))
+            .sorted_by_key(|deletion| deletion.due)
+            .collect()
+    }
+
+    fn insert_album(&self, album: Album) -> bool {
+        let albums_handle = self.db.cf_handle("albums").unwrap();
+        let k = Key::with_str(album.chat_id, &album.media_group_id);
+        match bincode::serialize(&album) {
+            Err(e) => {
+                log::error!("insert_album: {}", e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(albums_handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("insert_album: {}", e);
+                    false
+                }
+                Ok(_) => true,
+            },
+        }
+    }
+
+    fn get_album(&self, chat_id: i64, media_group_id: &str) -> Option<Album> {
+        let albums_handle = self.db.cf_handle("albums").unwrap();
+        let k = Key::with_str(chat_id, media_group_id);
+        match self.db.get_cf(albums_handle, k.encode()) {
+            Ok(Some(album_ser)) => stored::<Album>(&k.encode(), &album_ser),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("get_album: {}", e);
+                None
+            }
+        }
+    }
+
+    fn find_album(&self, chat_id: i64, msg_id: i32) -> Option<Album> {
+        let albums_handle = self.db.cf_handle("albums").unwrap();
+        let prefix = chat_prefix(chat_id);
+        let albums_it = self.db.prefix_iterator_cf(albums_handle, prefix);
+        albums_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Album>(&k, &v_ser))
+            .find(|album| album.msg_ids.contains(&msg_id))
+    }
+}
+
+impl RocksDBRepo {
+    fn put_hash(&self, cf: &str, label: &str, phash: PHash) -> bool {
+        let handle = self.db.cf_handle(cf).unwrap();
+        let k = Key::with_id(phash.chat_id, phash.msg_id as i64);
+        match bincode::serialize(&phash) {
+            Err(e) => {
+                log::error!("{}: {}", label, e);
+                false
+            }
+            Ok(v) => match self.db.put_cf(handle, k.encode(), v) {
+                Err(e) => {
+                    log::error!("{}: {}", label, e);
+                    false
+                }
+                Ok(_) => {
+                    log::info!(
+                        "{}: {}_{} {:016x}",
+                        label,
+                        phash.chat_id,
+                        phash.msg_id,
+                        phash.hash
+                    );
+                    true
+                }
+            },
+        }
+    }
+
+    /// Closest hash of the chat within the window and `max_distance` bits
+    fn similar_hash(&self, cf: &str, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        let handle = self.db.cf_handle(cf).unwrap();
+        let window = window_for(&self.windows, chat_id);
+        let now = Utc::now().timestamp();
+        let prefix = chat_prefix(chat_id);
+        let hashes_it = self.db.prefix_iterator_cf(handle, prefix);
+        hashes_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<PHash>(&k, &v_ser))
+            .filter(|phash| now - phash.timestamp <= window)
+            .map(|phash| (hamming(phash.hash, hash), phash))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, phash)| phash)
+    }
+
+    pub fn open(path: &str) -> Self {
+        let windows: Windows = Arc::new(RwLock::new(HashMap::new()));
+
+        let mut media_opts = Options::default();
+        media_opts.set_compaction_filter("ttl_media", media_ttl_filter(windows.clone()));
+        let mut user_opts = Options::default();
+        user_opts.set_compaction_filter("ttl_user", users_ttl_filter(windows.clone()));
+        let mut mappings_opts = Options::default();
+        mappings_opts.set_compaction_filter("ttl_mappings", mappings_ttl_filter(windows.clone()));
+        let mut duplicates_opts = Options::default();
+        duplicates_opts.set_compaction_filter("ttl_duplicates", media_ttl_filter(windows.clone()));
+        let groups_opts = Options::default();
+        let mut phashes_opts = Options::default();
+        phashes_opts.set_compaction_filter("ttl_phashes", phashes_ttl_filter(windows.clone()));
+        let strikes_opts = Options::default();
+        let offenders_opts = Options::default();
+        let bans_opts = Options::default();
+        let audit_opts = Options::default();
+        let deletions_opts = Options::default();
+        let mut albums_opts = Options::default();
+        albums_opts.set_compaction_filter("ttl_albums", albums_ttl_filter(windows.clone()));
+        let mut fingerprints_opts = Options::default();
+        fingerprints_opts.set_compaction_filter("ttl_fingerprints", phashes_ttl_filter(windows.clone()));
+        let allowlist_opts = Options::default();
+        let mut ban_plans_opts = Options::default();
+        ban_plans_opts.set_compaction_filter("ttl_ban_plans", ban_plans_ttl_filter);
+        let ban_plans_descriptor = ColumnFamilyDescriptor::new("ban_plans", ban_plans_opts);
+        let mut index_opts = Options::default();
+        index_opts.set_compaction_filter("ttl_unique_ids", media_ttl_filter(windows.clone()));
+        let index_descriptor = ColumnFamilyDescriptor::new("unique_ids", index_opts);
+
+        let chat_opts = vec![
+            media_opts,
+            user_opts,
+            mappings_opts,
+            duplicates_opts,
+            groups_opts,
+            phashes_opts,
+            strikes_opts,
+            offenders_opts,
+            bans_opts,
+            audit_opts,
+            deletions_opts,
+            albums_opts,
+            fingerprints_opts,
+            allowlist_opts,
+        ];
+        let mut cfs = CHAT_CFS
+            .iter()
+            .zip(chat_opts)
+            .map(|(name, mut cf_opts)| {
+                cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(CHAT_PREFIX_LEN));
+                ColumnFamilyDescriptor::new(*name, cf_opts)
+            })
+            .collect::<Vec<_>>();
+        cfs.push(index_descriptor);
+        cfs.push(ban_plans_descriptor);
+
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let repo = match DB::open_cf_descriptors(&opts, path, cfs) {
+            Err(e) => panic!("{}", e),
+            Ok(db) => RocksDBRepo {
+                db: Arc::new(db),
+                windows,
+                plans: Arc::new(Mutex::new(())),
+            },
+        };
+        repo.migrate_keys();
+        repo.migrate_allowlist();
+        repo.load_windows();
+        repo
+    }
+
+    /// Rewrites the `{chat_id}_{suffix}` string keys of older databases, a batch at a time so
+    /// large databases don't build one huge write. Unreadable entries are logged and kept
+    fn migrate_keys(&self) {
+        match self.db.get(KEY_FORMAT) {
+            Ok(Some(version)) if version.as_slice() == KEY_FORMAT_VERSION => return,
+            Err(e) => panic!("migrate_keys: {}", e),
+            _ => (),
+        }
+        let write = |batch: WriteBatch| {
+            if let Err(e) = self.db.write(batch) {
+                panic!("migrate_keys: {}", e);
+            }
+        };
+        let mut migrated = 0;
+        let mut kept = 0;
+        for cf in CHAT_CFS.iter().chain(["unique_ids"].iter()) {
+            let handle = self.db.cf_handle(cf).unwrap();
+            let mut batch = WriteBatch::default();
+            for (old_key, value) in self.db.iterator_cf(handle, IteratorMode::Start) {
+                // already rewritten by an earlier run that did not finish
+                if CHAT_CFS.contains(cf) && Key::decode(&old_key).is_some() {
+                    continue;
+                }
+                match migrated_key(cf, &old_key, &value) {
+                    Some(new_key) => {
+                        batch.delete_cf(handle, &old_key);
+                        batch.put_cf(handle, new_key, &value);
+                        migrated += 1;
+                    }
+                    None => {
+                        log::error!(
+                            "migrate_keys: keeping unreadable {} entry {}",
+                            cf,
+                            String::from_utf8_lossy(&old_key)
+                        );
+                        kept += 1;
+                    }
+                }
+                if batch.len() >= MIGRATION_BATCH {
+                    write(std::mem::take(&mut batch));
+                }
+            }
+            write(batch);
+        }
+        if let Err(e) = self.db.put(KEY_FORMAT, KEY_FORMAT_VERSION) {
+            panic!("migrate_keys: {}", e);
+        }
+        log::info!("migrate_keys: {} keys rewritten, {} unreadable kept", migrated, kept);
+    }
+
+    /// Copies the allowlist shared by every chat to each known chat
+    fn migrate_allowlist(&self) {
+        match self.db.get(ALLOWLIST_FORMAT) {
+            Ok(Some(version)) if version.as_slice() == ALLOWLIST_FORMAT_VERSION => return,
+            Err(e) => panic!("migrate_allowlist: {}", e),
+            _ => (),
+        }
+        let users_handle = self.db.cf_handle("users").unwrap();
+        let chat_ids = self
+            .db
+            .iterator_cf(users_handle, IteratorMode::Start)
+            .filter_map(|(k, _)| Key::decode(&k).map(|key| key.chat_id))
+            .unique()
+            .collect::<Vec<_>>();
+        let allowlist_handle = self.db.cf_handle("allowlist").unwrap();
+        let mut batch = WriteBatch::default();
+        let mut user_ids = Vec::new();
+        for (k, _) in self.db.iterator_cf(allowlist_handle, IteratorMode::Start) {
+            let shared: Option<[u8; 8]> = k.as_ref().try_into().ok();
+            if let Some(id) = shared {
+                batch.delete_cf(allowlist_handle, &k);
+                user_ids.push(i64::from_be_bytes(id));
+            }
+        }
+        let timestamp = Utc::now().timestamp();
+        for chat_id in chat_ids.iter() {
+            for user_id in user_ids.iter() {
+                let k = Key::with_id(*chat_id, *user_id);
+                batch.put_cf(allowlist_handle, k.encode(), timestamp.to_be_bytes());
+            }
+        }
+        batch.put(ALLOWLIST_FORMAT, ALLOWLIST_FORMAT_VERSION);
+        match self.db.write(batch) {
+            Err(e) => panic!("migrate_allowlist: {}", e),
+            Ok(_) => log::info!("migrate_allowlist: copied to {} chats", chat_ids.len()),
+        }
+    }
+
+    fn last_media(&self, cf: &str, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        let handle = self.db.cf_handle(cf).unwrap();
+        let prefix = chat_prefix(chat_id);
+        let media_it = self.db.prefix_iterator_cf(handle, prefix);
+        let mut media_vec = media_it
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter_map(|(k, v_ser)| stored::<Media>(&k, &v_ser))
+            .map(|media| (media.msg_id, media))
+            .filter(|tup| (tup.1.file_type == "url") == is_url)
+            .into_group_map()
+            .into_iter()
+            .map(|(_, g)| g.first().unwrap().clone())
+            .collect::<Vec<_>>();
+        media_vec.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        media_vec.truncate(limit);
+        media_vec
+    }
+
+    fn load_windows(&self) {
+        let groups_handle = self.db.cf_handle("groups").unwrap();
+        let groups_it = self.db.iterator_cf(groups_handle, IteratorMode::Start);
+        let mut windows = self.windows.write().unwrap();
+        groups_it
+            .filter(|(k, _)| match Key::decode(k) {
+                Some(key) => key.suffix == CONFIG_SUFFIX,
+                None => false,
+            })
+            .filter_map(|(_, v)| serde_json::from_slice::<ChatConfig>(&v).ok())
+            .for_each(|config| {
+                windows.insert(config.chat_id, config.window);
+            });
+        log::info!("load_windows: {} chats configured", windows.len());
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{window_for, RocksDBRepo, Windows, ALLOWLIST_FORMAT};
+    use crate::models::User as DBUser;
+    use crate::models::{ChatConfig, Group, Mapping, Media, DEFAULT_WINDOW_SECS};
+    use crate::repository::{conformance, Repository};
+    use chrono::offset::Utc;
+    use rocksdb::{Options, DB};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    #[test]
+    fn test_conformance() {
+        let path = std::env::temp_dir().join("highlander_conformance.rocksdb");
+        let path = path.to_str().unwrap();
+        let _ = DB::destroy(&Options::default(), path);
+        {
+            let repo = RocksDBRepo::open(path);
+            conformance::run(&repo);
+        }
+        let _ = DB::destroy(&Options::default(), path);
+    }
+
+    #[test]
+    fn test_migrate_string_keys() {
+        let path = std::env::temp_dir().join("highlander_migration.rocksdb");
+        let path = path.to_str().unwrap();
+        let _ = DB::destroy(&Options::default(), path);
+        let now = Utc::now().timestamp();
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            opts.create_missing_column_families(true);
+            let cfs = ["media", "users", "mappings", "duplicates", "groups", "phashes", "unique_ids"];
+            let db = DB::open_cf(&opts, path, cfs.iter()).unwrap();
+            let media = Media {
+                unique_id: String::from("AQADiq4xG--XSVd4"),
+                chat_id: -4242,
+                msg_id: 416,
+                file_type: String::from("photo"),
+                file_id: String::from("file_AQADiq4xG--XSVd4"),
+                timestamp: now,
+            };
+            let user = DBUser {
+                user_id: 1072037897,
+                chat_id: -1001592783264,
+                user_name: String::from("User"),
+                chat_name: String::from("Group"),
+                timestamp: now,
+            };
+            let mapping = Mapping {
+                unique_id: String::from("AQADiq4xG--XSVd4"),
+                chat_id: -4242,
+                api_id: 436207616,
+                timestamp: now,
+            };
+            let group = Group {
+                supergroup_id: 1592783264,
+                chat_id: -1001592783264,
+                offset: 200,
+                timestamp: now,
+            };
+            let mut config = ChatConfig::new(-4242);
+            config.set_window_days(1);
+
+            let put = |cf: &str, k: &str, v: Vec<u8>| {
+                db.put_cf(db.cf_handle(cf).unwrap(), k.as_bytes(), v).unwrap();
+            };
+            put("media", "-4242_AQADiq4xG--XSVd4", bincode::serialize(&media).unwrap());
+            put("unique_ids", "AQADiq4xG--XSVd4\0-4242", bincode::serialize(&media).unwrap());
+            put("users", "-1001592783264_1072037897", bincode::serialize(&user).unwrap());
+            put("mappings", "-4242_436207616", bincode::serialize(&mapping).unwrap());
+            put("groups", "1592783264", bincode::serialize(&group).unwrap());
+            put("groups", "config_-4242", serde_json::to_vec(&config).unwrap());
+            put("users", "garbage", b"not bincode".to_vec());
+        }
+        for _ in 0..2 {
+            let repo = RocksDBRepo::open(path);
+            let photo = conformance::sdo(-4242, 416, "photo", "AQADiq4xG--XSVd4");
+            assert_eq!(repo.item_exists(photo, true).unwrap().msg_id, 416);
+            assert!(repo.find_in_network("AQADiq4xG--XSVd4", &[-4242]).is_some());
+            assert!(repo.chat_dbuser_exists(1072037897, -1001592783264));
+            assert_eq!(repo.get_chat_ids(), vec![-1001592783264]);
+            assert_eq!(repo.list_users(0).len(), 1);
+            assert_eq!(repo.find_mapping(436207616, -4242).unwrap().unique_id, "AQADiq4xG--XSVd4");
+            assert_eq!(repo.get_group(1592783264).unwrap().offset, 200);
+            assert_eq!(repo.get_config(-4242).window_days(), 1);
+            assert_eq!(window_for(&repo.windows, -4242), 86400);
+            let users_handle = repo.db.cf_handle("users").unwrap();
+            assert!(repo.db.get_cf(users_handle, b"garbage").unwrap().is_some());
+        }
+        let _ = DB::destroy(&Options::default(), path);
+    }
+
+    #[test]
+    fn test_migrate_shared_allowlist() {
+        let path = std::env::temp_dir().join("highlander_allowlist.rocksdb");
+        let path = path.to_str().unwrap();
+        let _ = DB::destroy(&Options::default(), path);
+        {
+            let repo = RocksDBRepo::open(path);
+            for chat_id in [-4242, -1001592783264].iter() {
+                repo.insert_user(&conformance::user(42), conformance::chat(*chat_id));
+            }
+            let allowlist_handle = repo.db.cf_handle("allowlist").unwrap();
+            let shared = 1072037897i64.to_be_bytes();
+            repo.db.put_cf(allowlist_handle, shared, 0i64.to_be_bytes()).unwrap();
+            repo.db.delete(ALLOWLIST_FORMAT).unwrap();
+        }
+        for _ in 0..2 {
+            let repo = RocksDBRepo::open(path);
+            for chat_id in [-4242, -1001592783264].iter() {
+                assert_eq!(repo.list_allowed(*chat_id), vec![1072037897]);
+            }
+            assert!(repo.list_allowed(-1001192585346).is_empty());
+        }
+        let _ = DB::destroy(&Options::default(), path);
+    }
+
+    #[test]
+    fn test_ttl_filters_keep_unreadable_entries() {
+        let windows: Windows = Arc::new(RwLock::new(HashMap::new()));
+        let keep = |decision| matches!(decision, CompactionDecision::Keep);
+        assert!(keep(media_ttl_filter(windows.clone())(0, b"garbage", b"not bincode")));
+        assert!(keep(users_ttl_filter(windows.clone())(0, b"garbage", b"not bincode")));
+        assert!(keep(mappings_ttl_filter(windows.clone())(0, b"garbage", b"not bincode")));
+        assert!(keep(phashes_ttl_filter(windows.clone())(0, b"garbage", b"not bincode")));
+        assert!(keep(albums_ttl_filter(windows)(0, b"garbage", b"not bincode")));
+    }
+
+    #[test]
+    fn test_window_for() {
+        let windows: Windows = Arc::new(RwLock::new(HashMap::new()));
+        assert_eq!(window_for(&windows, -1001592783264), DEFAULT_WINDOW_SECS);
+        windows.write().unwrap().insert(-1001592783264, 86400);
+        assert_eq!(window_for(&windows, -1001592783264), 86400);
+        assert_eq!(window_for(&windows, -1001192585346), DEFAULT_WINDOW_SECS);
+    }
+
     #[test]
     fn test_split_str() {
         let key = "-1001445478423_1072037897";
@@ -1,255 +1,934 @@
-use sqlite::{Connection, Error, Value};
+use sqlite::{Connection, Value};
 
 use std::env;
 use std::sync::Arc;
 
 use rtdlib::types::UpdateDeleteMessages;
-use teloxide::types::{Chat, ChatKind, User};
+use teloxide::types::{Chat, User};
 
-use super::models::{SDO, Status};
+use chrono::offset::Utc;
+use chrono::Duration;
+
+use super::models::User as DBUser;
+use super::models::{
+    Album, AuditEntry, Ban, BanPlan, ChatConfig, Deletion, Group, Mapping, Media, Offender,
+    PHash, Strikes, SDO, BAN_PLAN_TTL_SECS,
+};
+use super::phash::hamming;
 use super::repository::*;
 
-const INSERT: &str = "INSERT INTO mappings (api_id, chat_id, unique_id) VALUES (?, ?, ?)";
+const SCHEMA: &str = include_str!("../schema.sql");
 
-fn cursor_to_bool(cursor: Result<Option<&[Value]>, Error>, label: &str) -> bool {
-    match cursor {
-        Err(e) => {
-            log::error!("{}: {}", label, e);
-            false
-        }
-        Ok(row) => match row {
-            None => false,
-            Some(_) => true,
-        },
+const MEDIA_COLUMNS: &str = "unique_id, chat_id, msg_id, file_type, file_id, timestamp";
+const USER_COLUMNS: &str = "user_id, chat_id, user_name, chat_name, timestamp";
+const OFFENDER_COLUMNS: &str = "chat_id, user_id, user_name, count, last_chat_id, last_msg_id, timestamp";
+const BAN_COLUMNS: &str = "user_id, chat_id, user_name, reason, admin_id, error, timestamp";
+
+/// `user_version` from which the allowlist is keyed by chat
+const ALLOWLIST_VERSION: i64 = 1;
+
+fn integer(value: &Value) -> i64 {
+    value.as_integer().unwrap_or(0)
+}
+
+fn string(value: &Value) -> String {
+    value.as_string().unwrap_or("").to_string()
+}
+
+fn row_to_media(row: &[Value]) -> Media {
+    Media {
+        unique_id: string(&row[0]),
+        chat_id: integer(&row[1]),
+        msg_id: integer(&row[2]) as i32,
+        file_type: string(&row[3]),
+        file_id: string(&row[4]),
+        timestamp: integer(&row[5]),
     }
 }
 
+fn row_to_user(row: &[Value]) -> DBUser {
+    DBUser {
+        user_id: integer(&row[0]),
+        chat_id: integer(&row[1]),
+        user_name: string(&row[2]),
+        chat_name: string(&row[3]),
+        timestamp: integer(&row[4]),
+    }
+}
+
+fn row_to_offender(row: &[Value]) -> Offender {
+    Offender {
+        chat_id: integer(&row[0]),
+        user_id: integer(&row[1]),
+        user_name: string(&row[2]),
+        count: integer(&row[3]) as u32,
+        last_chat_id: integer(&row[4]),
+        last_msg_id: integer(&row[5]) as i32,
+        timestamp: integer(&row[6]),
+    }
+}
+
+fn row_to_ban(row: &[Value]) -> Ban {
+    Ban {
+        user_id: integer(&row[0]),
+        chat_id: integer(&row[1]),
+        user_name: string(&row[2]),
+        reason: string(&row[3]),
+        admin_id: integer(&row[4]),
+        error: row[5].as_string().map(String::from),
+        timestamp: integer(&row[6]),
+    }
+}
+
+fn media_to_values(media: &Media) -> Vec<Value> {
+    vec![
+        Value::String(media.unique_id.clone()),
+        Value::Integer(media.chat_id),
+        Value::Integer(media.msg_id.into()),
+        Value::String(media.file_type.clone()),
+        Value::String(media.file_id.clone()),
+        Value::Integer(media.timestamp),
+    ]
+}
+
+fn user_to_values(user: &DBUser) -> Vec<Value> {
+    vec![
+        Value::Integer(user.user_id),
+        Value::Integer(user.chat_id),
+        Value::String(user.user_name.clone()),
+        Value::String(user.chat_name.clone()),
+        Value::Integer(user.timestamp),
+    ]
+}
+
+/// Sqlite uses a negative limit for "no limit", the repository uses 0
+fn sql_limit(limit: usize) -> i64 {
+    if limit > 0 {
+        limit as i64
+    } else {
+        -1
+    }
+}
+
+/// Opens a connection per operation so the repository can be shared across threads
 #[derive(Clone)]
 pub struct SQLiteRepo {
-    connection: Arc<Connection>,
+    path: Arc<String>,
 }
 
-impl Repository for SQLiteRepo {
-    fn init() -> Self {
-        let db_path = match env::var("HIGHLANDER_DB_PATH") {
-            Ok(path) => path,
-            Err(_) => String::from("."),
+impl SQLiteRepo {
+    pub fn open(path: &str) -> Self {
+        let repo = SQLiteRepo {
+            path: Arc::new(path.to_string()),
         };
+        ok!(repo.connection().execute(SCHEMA));
+        repo.migrate_allowlist();
+        repo
+    }
 
-        let connection: Connection = ok!(sqlite::open(format!("{}/attachments.db", db_path)));
-        SQLiteRepo {
-            connection: Arc::new(connection)
+    /// Moves the allowlist shared by every chat to one per chat
+    fn migrate_allowlist(&self) {
+        let version = self.rows("migrate_allowlist", "PRAGMA user_version", &[]);
+        if version.first().map_or(0, |row| integer(&row[0])) >= ALLOWLIST_VERSION {
+            return;
         }
+        let columns = self.rows("migrate_allowlist", "PRAGMA table_info(allowlist)", &[]);
+        let shared = !columns.iter().any(|row| string(&row[1]) == "chat_id");
+        if shared {
+            ok!(self
+                .connection()
+                .execute("ALTER TABLE allowlist RENAME TO allowlist_shared;"));
+            ok!(self.connection().execute(SCHEMA));
+            let copy = "INSERT OR IGNORE INTO allowlist (chat_id, user_id, timestamp) \
+                SELECT chats.chat_id, allowlist_shared.user_id, allowlist_shared.timestamp \
+                FROM (SELECT DISTINCT chat_id FROM users) AS chats, allowlist_shared";
+            self.execute("migrate_allowlist", copy, &[]);
+            ok!(self.connection().execute("DROP TABLE allowlist_shared;"));
+        }
+        let set_version = format!("PRAGMA user_version = {};", ALLOWLIST_VERSION);
+        ok!(self.connection().execute(set_version));
+        log::info!("migrate_allowlist: allowlist is now per chat");
     }
 
-    fn chat_user_exists(&self, user: &User, chat: Arc<Chat>) -> bool {
-        let select = "SELECT user_id, chat_id, user_name, chat_name FROM users WHERE user_id = ? AND chat_id = ?";
-        match self.connection.prepare(select) {
+    fn connection(&self) -> Connection {
+        ok!(sqlite::open(self.path.as_str()))
+    }
+
+    fn execute(&self, label: &str, query: &str, values: &[Value]) -> bool {
+        let connection = self.connection();
+        match connection.prepare(query) {
             Err(e) => {
-                log::error!("Store user: {}", e);
+                log::error!("{}: {}", label, e);
                 false
-            },
-            Ok(mut select_stmt) => {
-                ok!(select_stmt.bind(1, user.id));
-                ok!(select_stmt.bind(2, chat.id));
-                let mut select_cursor = select_stmt.cursor();
-                cursor_to_bool(select_cursor.next(), "chat_user_exists")
+            }
+            Ok(stmt) => {
+                let mut cursor = stmt.cursor();
+                match cursor.bind(values).and_then(|_| cursor.next().map(|_| ())) {
+                    Err(e) => {
+                        log::error!("{}: {}", label, e);
+                        false
+                    }
+                    Ok(_) => true,
+                }
             }
         }
     }
 
-    fn update_user_timestamp(&self, user: &User, chat: Arc<Chat>) -> bool {
-        let update = "UPDATE users SET timestamp=CURRENT_TIMESTAMP WHERE user_id=? AND chat_id=?";
-        let mut update_stmt = ok!(self.connection.prepare(update));
-        ok!(update_stmt.bind(1, user.id));
-        ok!(update_stmt.bind(2, chat.id));
-        let mut update_cursor = update_stmt.cursor();
-        match update_cursor.next() {
-            Err(e) => {
-                log::error!("update_user_timestamp: {}", e);
-                false
+    fn rows(&self, label: &str, query: &str, values: &[Value]) -> Vec<Vec<Value>> {
+        let connection = self.connection();
+        let mut rows = Vec::new();
+        match connection.prepare(query) {
+            Err(e) => log::error!("{}: {}", label, e),
+            Ok(stmt) => {
+                let mut cursor = stmt.cursor();
+                if let Err(e) = cursor.bind(values) {
+                    log::error!("{}: {}", label, e);
+                    return rows;
+                }
+                loop {
+                    match cursor.next() {
+                        Ok(Some(row)) => rows.push(row.to_vec()),
+                        Ok(None) => break,
+                        Err(e) => {
+                            log::error!("{}: {}", label, e);
+                            break;
+                        }
+                    }
+                }
             }
-            Ok(row) => match row {
-                None => false,
-                Some(_) => true,
-            },
         }
+        rows
     }
 
-    fn insert_user(&self, user: &User, chat: Arc<Chat>) -> bool {
-        log::info!("insert_user...");
-        match &chat.kind {
-            ChatKind::Public(public) => {
-                let unknown = String::from("Unknown");
-                let chat_name = public.title.as_ref().unwrap_or(&unknown);
-                let insert = "INSERT INTO users (user_id, chat_id, user_name, chat_name) VALUES (?, ?, ?, ?)";
-                let mut insert_stmt = ok!(self.connection.prepare(insert));
-                ok!(insert_stmt.bind(1, user.id));
-                ok!(insert_stmt.bind(2, chat.id));
-                ok!(insert_stmt.bind(
-                    3,
-                    user.username
-                        .as_ref()
-                        .unwrap_or(&user.first_name.to_string()) as &str
-                ));
-                ok!(insert_stmt.bind(4, &chat_name as &str));
-
-                let mut cursor = insert_stmt.cursor();
-                cursor_to_bool(cursor.next(), "insert_user")
-            }
-            ChatKind::Private(_) => false, //private.username.as_ref().unwrap_or(&unknown)
-        }
-    }
-
-    fn item_exists(&self, sdo: SDO, is_media: bool) -> Vec<Value> {
-        let select = if is_media {
-            "SELECT chat_id, msg_id, unique_id FROM media WHERE chat_id = ? AND unique_id = ?"
-        } else {
-            "SELECT chat_id, msg_id, unique_id FROM {} WHERE chat_id = ? AND unique_id = ?"
+    fn insert_media(&self, table: &str, media: &Media) -> bool {
+        let insert = format!(
+            "INSERT OR REPLACE INTO {} ({}) VALUES (?, ?, ?, ?, ?, ?)",
+            table, MEDIA_COLUMNS
+        );
+        self.execute(table, &insert, &media_to_values(media))
+    }
+
+    fn last_media(&self, table: &str, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        let select = format!(
+            "SELECT {} FROM {} WHERE chat_id = ? AND (file_type = 'url') = ? GROUP BY msg_id ORDER BY timestamp DESC LIMIT ?",
+            MEDIA_COLUMNS, table
+        );
+        let values = [
+            Value::Integer(chat_id),
+            Value::Integer(is_url as i64),
+            Value::Integer(sql_limit(limit)),
+        ];
+        self.rows(table, &select, &values)
+            .iter()
+            .map(|row| row_to_media(row))
+            .collect()
+    }
+
+    fn insert_hash(&self, table: &str, label: &str, phash: PHash) -> bool {
+        let insert = format!(
+            "INSERT OR REPLACE INTO {} (chat_id, msg_id, hash, timestamp) VALUES (?, ?, ?, ?)",
+            table
+        );
+        let values = [
+            Value::Integer(phash.chat_id),
+            Value::Integer(phash.msg_id.into()),
+            Value::Integer(phash.hash as i64),
+            Value::Integer(phash.timestamp),
+        ];
+        self.execute(label, &insert, &values)
+    }
+
+    /// Closest hash of the chat within the window and `max_distance` bits
+    fn find_similar_hash(&self, table: &str, label: &str, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        let oldest = Utc::now().timestamp() - self.get_config(chat_id).window;
+        let select = format!(
+            "SELECT chat_id, msg_id, hash, timestamp FROM {} WHERE chat_id = ? AND timestamp >= ?",
+            table
+        );
+        let values = [Value::Integer(chat_id), Value::Integer(oldest)];
+        self.rows(label, &select, &values)
+            .iter()
+            .map(|row| PHash {
+                chat_id: integer(&row[0]),
+                msg_id: integer(&row[1]) as i32,
+                hash: integer(&row[2]) as u64,
+                timestamp: integer(&row[3]),
+            })
+            .map(|phash| (hamming(phash.hash, hash), phash))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, phash)| phash)
+    }
+
+    /// Mimics the rocksdb ttl compaction filter for the given chat
+    fn purge_expired(&self, chat_id: i64) {
+        let oldest = Utc::now().timestamp() - self.get_config(chat_id).window;
+        let values = [Value::Integer(chat_id), Value::Integer(oldest)];
+        for table in &["media", "duplicates", "mappings", "phashes", "fingerprints"] {
+            let delete = format!("DELETE FROM {} WHERE chat_id = ? AND timestamp < ?", table);
+            self.execute("purge_expired", &delete, &values);
+        }
+    }
+}
+
+impl Repository<Media> for SQLiteRepo {
+    fn init() -> Self {
+        let db_path = match env::var("HIGHLANDER_DB_PATH") {
+            Ok(path) => path,
+            Err(_) => String::from("."),
         };
+        SQLiteRepo::open(&format!("{}/attachments.db", db_path))
+    }
 
-        let empty_vec = Vec::new();
+    fn chat_user_exists(&self, user: &User, chat: Arc<Chat>) -> bool {
+        self.chat_dbuser_exists(user.id, chat.id)
+    }
 
-        match self.connection.prepare(select) {
-            Err(e) => {
-                log::error!("Handle message: {}", e);
-                empty_vec
-            }
-            Ok(mut select_stmt) => {
-                ok!(select_stmt.bind(1, sdo.chat.id));
-                ok!(select_stmt.bind(2, sdo.unique_id.as_str()));
-                let mut select_cursor = select_stmt.cursor();
-                match select_cursor.next() {
-                    Err(e) => {
-                        log::error!("media_exists: {}", e);
-                        empty_vec
+    fn chat_dbuser_exists(&self, user_id: i64, chat_id: i64) -> bool {
+        let select = "SELECT user_id FROM users WHERE user_id = ? AND chat_id = ?";
+        let values = [Value::Integer(user_id), Value::Integer(chat_id)];
+        !self.rows("chat_dbuser_exists", select, &values).is_empty()
+    }
+
+    fn update_user_timestamp(&self, user: &User, chat: Arc<Chat>) -> bool {
+        self.insert_dbuser(user_to_db(user, chat))
+    }
+
+    fn insert_user(&self, user: &User, chat: Arc<Chat>) -> bool {
+        log::info!("insert_user: {} on chat {}", user.id, chat.id);
+        self.update_user_timestamp(user, chat)
+    }
+
+    #[allow(unused_variables)]
+    fn item_exists(&self, sdo: SDO, is_media: bool) -> Option<Media> {
+        let window = self.get_config(sdo.chat.id).window;
+        let select = format!(
+            "SELECT {} FROM media WHERE chat_id = ? AND unique_id = ?",
+            MEDIA_COLUMNS
+        );
+        let values = [
+            Value::Integer(sdo.chat.id),
+            Value::String(sdo.unique_id.clone()),
+        ];
+        match self.rows("item_exists", &select, &values).first() {
+            None => {
+                log::info!(
+                    "item_exists: key {}_{} not found",
+                    sdo.chat.id,
+                    sdo.unique_id
+                );
+                None
+            }
+            Some(row) => {
+                let media = row_to_media(row);
+                if Utc::now().timestamp() - media.timestamp > window {
+                    log::info!("item_exists: media {:?} found but expired", media);
+                    None
+                } else {
+                    log::info!("item_exists: media {:?} found", media);
+                    Some(media)
+                }
+            }
+        }
+    }
+
+    fn insert_item(&self, sdo: SDO, _is_media: bool) -> bool {
+        let chat_id = sdo.chat.id;
+        self.purge_expired(chat_id);
+        let media = sdo_to_media(sdo);
+        self.insert_media("media", &media)
+    }
+
+    fn insert_duplicate(&self, sdo: SDO) -> bool {
+        let media = sdo_to_media(sdo);
+        self.insert_media("duplicates", &media)
+    }
+
+    fn message_media(&self, chat_id: i64, msg_id: i32, is_duplicate: bool) -> Vec<Media> {
+        let table = if is_duplicate { "duplicates" } else { "media" };
+        let select = format!(
+            "SELECT {} FROM {} WHERE chat_id = ? AND msg_id = ?",
+            MEDIA_COLUMNS, table
+        );
+        let values = [Value::Integer(chat_id), Value::Integer(msg_id as i64)];
+        self.rows("message_media", &select, &values)
+            .iter()
+            .map(|row| row_to_media(row))
+            .collect()
+    }
+
+    fn delete_media(&self, chat_id: i64, unique_id: &str) -> bool {
+        let delete = "DELETE FROM media WHERE chat_id = ? AND unique_id = ?";
+        let values = [Value::Integer(chat_id), Value::String(unique_id.into())];
+        self.execute("delete_media", delete, &values)
+    }
+
+    fn delete_item(&self, deleted_messages: UpdateDeleteMessages) -> () {
+        let chat_id = deleted_messages.chat_id();
+        let delete = "DELETE FROM media WHERE chat_id = ? AND unique_id = ?";
+        for api_id in deleted_messages.message_ids() {
+            match self.find_mapping(*api_id, chat_id) {
+                None => {
+                    log::error!("Mapping {}_{} not found", chat_id, api_id);
+                }
+                Some(mapping) => {
+                    let values = [
+                        Value::Integer(chat_id),
+                        Value::String(mapping.unique_id.clone()),
+                    ];
+                    if self.execute("delete_item", delete, &values) {
+                        log::info!("Deleted {}_{}", chat_id, mapping.unique_id);
                     }
-                    Ok(row) => match row {
-                        None => empty_vec,
-                        Some(r) => {
-                            let new_vec = r.to_vec();
-                            new_vec.to_owned()
-                        }
-                    },
                 }
             }
         }
     }
 
-    fn insert_item(&self, sdo: SDO, is_media: bool) -> bool {
-        let insert = if is_media {
-            "INSERT INTO media (chat_id, msg_id, file_type, unique_id, file_id) VALUES (?, ?, ?, ?, ?)"
-        } else {
-            "INSERT INTO urls (chat_id, msg_id, unique_id) VALUES (?, ?, ?)"
-        };
+    fn insert_mapping(&self, api_id: i64, chat_id: i64, unique_id: &str) -> bool {
+        let insert = "INSERT OR REPLACE INTO mappings (api_id, chat_id, unique_id, timestamp) VALUES (?, ?, ?, ?)";
+        let values = [
+            Value::Integer(api_id),
+            Value::Integer(chat_id),
+            Value::String(unique_id.into()),
+            Value::Integer(Utc::now().timestamp()),
+        ];
+        self.execute("insert_mapping", insert, &values)
+    }
 
-        let mut insert_stmt = ok!(self.connection.prepare(insert));
-        if is_media {
-            ok!(insert_stmt.bind(1, sdo.chat.id));
-            ok!(insert_stmt.bind(2, f64::from(sdo.msg_id)));
-            ok!(insert_stmt.bind(3, sdo.file_type.as_str()));
-            ok!(insert_stmt.bind(4, sdo.unique_id.as_str()));
-            ok!(insert_stmt.bind(5, ok!(sdo.file_id).as_str()));
-        } else {
-            ok!(insert_stmt.bind(1, sdo.chat.id));
-            ok!(insert_stmt.bind(2, f64::from(sdo.msg_id)));
-            ok!(insert_stmt.bind(3, sdo.unique_id.as_str()));
-        };
-        let mut cursor = insert_stmt.cursor();
-        match cursor.next() {
+    fn find_mapping(&self, api_id: i64, chat_id: i64) -> Option<Mapping> {
+        let select = "SELECT unique_id, chat_id, api_id, timestamp FROM mappings WHERE api_id = ? AND chat_id = ?";
+        let values = [Value::Integer(api_id), Value::Integer(chat_id)];
+        match self.rows("find_mapping", select, &values).first() {
+            None => {
+                log::info!("find_mapping: not found {}_{}", chat_id, api_id);
+                None
+            }
+            Some(row) => Some(Mapping {
+                unique_id: string(&row[0]),
+                chat_id: integer(&row[1]),
+                api_id: integer(&row[2]),
+                timestamp: integer(&row[3]),
+            }),
+        }
+    }
+
+    fn last_media_stored(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        self.last_media("media", chat_id, limit, is_url)
+    }
+
+    fn last_media_duplicated(&self, chat_id: i64, limit: usize, is_url: bool) -> Vec<Media> {
+        self.last_media("duplicates", chat_id, limit, is_url)
+    }
+
+    #[allow(unused_variables)]
+    fn list_user_groups(&self, chat_id: i64, user_id: i64) -> Vec<DBUser> {
+        let select = format!("SELECT {} FROM users WHERE user_id = ?", USER_COLUMNS);
+        self.rows("list_user_groups", &select, &[Value::Integer(user_id)])
+            .iter()
+            .map(|row| row_to_user(row))
+            .collect()
+    }
+
+    fn get_chat_ids(&self) -> Vec<i64> {
+        let select = "SELECT DISTINCT chat_id FROM users ORDER BY chat_id";
+        self.rows("get_chat_ids", select, &[])
+            .iter()
+            .map(|row| integer(&row[0]))
+            .collect()
+    }
+
+    fn insert_dbuser(&self, user: DBUser) -> bool {
+        let insert = format!(
+            "INSERT OR REPLACE INTO users ({}) VALUES (?, ?, ?, ?, ?)",
+            USER_COLUMNS
+        );
+        self.execute("insert_dbuser", &insert, &user_to_values(&user))
+    }
+
+    fn list_media(&self, limit: usize) -> Vec<Media> {
+        let select = format!("SELECT {} FROM media LIMIT ?", MEDIA_COLUMNS);
+        self.rows("list_media", &select, &[Value::Integer(sql_limit(limit))])
+            .iter()
+            .map(|row| row_to_media(row))
+            .collect()
+    }
+
+    fn list_users(&self, limit: usize) -> Vec<DBUser> {
+        let select = format!("SELECT {} FROM users LIMIT ?", USER_COLUMNS);
+        self.rows("list_users", &select, &[Value::Integer(sql_limit(limit))])
+            .iter()
+            .map(|row| row_to_user(row))
+            .collect()
+    }
+
+    fn list_duplicates(&self, limit: usize) -> Vec<Media> {
+        let select = format!("SELECT {} FROM duplicates LIMIT ?", MEDIA_COLUMNS);
+        self.rows("list_duplicates", &select, &[Value::Integer(sql_limit(limit))])
+            .iter()
+            .map(|row| row_to_media(row))
+            .collect()
+    }
+
+    fn get_users_chat_count(&self) -> Vec<(DBUser, usize)> {
+        let select = format!(
+            "SELECT {}, COUNT(*) FROM users GROUP BY user_id HAVING COUNT(*) > 1",
+            USER_COLUMNS
+        );
+        self.rows("get_users_chat_count", &select, &[])
+            .iter()
+            .map(|row| (row_to_user(row), integer(&row[5]) as usize))
+            .collect()
+    }
+
+    fn inactive_users_before(&self, ndays: i64) -> Vec<DBUser> {
+        let offset_day = Utc::now() - Duration::days(ndays);
+        let select = format!("SELECT {} FROM users WHERE timestamp < ?", USER_COLUMNS);
+        let values = [Value::Integer(offset_day.timestamp())];
+        self.rows("inactive_users_before", &select, &values)
+            .iter()
+            .map(|row| row_to_user(row))
+            .collect()
+    }
+
+    fn insert_group(&self, group: Group) -> bool {
+        log::info!("Insert Group key: {}", group.supergroup_id);
+        let insert = "INSERT OR REPLACE INTO chat_groups (supergroup_id, chat_id, member_offset, timestamp) VALUES (?, ?, ?, ?)";
+        let values = [
+            Value::Integer(group.supergroup_id),
+            Value::Integer(group.chat_id),
+            Value::Integer(group.offset),
+            Value::Integer(group.timestamp),
+        ];
+        self.execute("insert_group", insert, &values)
+    }
+
+    fn get_group(&self, supergroup_id: i64) -> Option<Group> {
+        let select = "SELECT supergroup_id, chat_id, member_offset, timestamp FROM chat_groups WHERE supergroup_id = ?";
+        match self
+            .rows("get_group", select, &[Value::Integer(supergroup_id)])
+            .first()
+        {
+            Some(row) => Some(Group {
+                supergroup_id: integer(&row[0]),
+                chat_id: integer(&row[1]),
+                offset: integer(&row[2]),
+                timestamp: integer(&row[3]),
+            }),
+            None => {
+                log::error!("get_group: {} not found", supergroup_id);
+                None
+            }
+        }
+    }
+
+    fn get_config(&self, chat_id: i64) -> ChatConfig {
+        let select = "SELECT config FROM configs WHERE chat_id = ?";
+        match self
+            .rows("get_config", select, &[Value::Integer(chat_id)])
+            .first()
+        {
+            Some(row) => match serde_json::from_str::<ChatConfig>(&string(&row[0])) {
+                Ok(config) => config,
+                Err(e) => {
+                    log::error!("get_config: {}", e);
+                    ChatConfig::new(chat_id)
+                }
+            },
+            None => ChatConfig::new(chat_id),
+        }
+    }
+
+    fn insert_config(&self, config: ChatConfig) -> bool {
+        match serde_json::to_string(&config) {
             Err(e) => {
-                log::error!("insert_item: is_media {} {}", is_media, e);
+                log::error!("insert_config: {}", e);
                 false
             }
-            Ok(_) => {
-                log::info!(
-                    "Stored {} - {} - {}",
-                    sdo.chat.id,
-                    sdo.msg_id,
-                    sdo.unique_id
-                );
-                true
+            Ok(v) => {
+                let insert = "INSERT OR REPLACE INTO configs (chat_id, config) VALUES (?, ?)";
+                let values = [Value::Integer(config.chat_id), Value::String(v)];
+                self.execute("insert_config", insert, &values)
             }
         }
     }
 
-    fn insert_duplicate(&self, sdo: SDO) -> bool {
-        log::info!(
-            "Duplicate: {} - {} - {}",
-            sdo.chat.id,
-            sdo.unique_id,
-            acc.text
+    fn insert_phash(&self, phash: PHash) -> bool {
+        self.insert_hash("phashes", "insert_phash", phash)
+    }
+
+    fn find_similar_phash(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        self.find_similar_hash("phashes", "find_similar_phash", chat_id, hash, max_distance)
+    }
+
+    fn insert_fingerprint(&self, fingerprint: PHash) -> bool {
+        self.insert_hash("fingerprints", "insert_fingerprint", fingerprint)
+    }
+
+    fn find_similar_fingerprint(&self, chat_id: i64, hash: u64, max_distance: u32) -> Option<PHash> {
+        self.find_similar_hash("fingerprints", "find_similar_fingerprint", chat_id, hash, max_distance)
+    }
+
+    fn find_in_network(&self, unique_id: &str, chat_ids: &[i64]) -> Option<Media> {
+        if chat_ids.is_empty() {
+            return None;
+        }
+        let now = Utc::now().timestamp();
+        let placeholders = vec!["?"; chat_ids.len()].join(", ");
+        let select = format!(
+            "SELECT {} FROM media WHERE unique_id = ? AND chat_id IN ({}) ORDER BY timestamp",
+            MEDIA_COLUMNS, placeholders
+        );
+        let mut values = vec![Value::String(unique_id.into())];
+        values.extend(chat_ids.iter().map(|chat_id| Value::Integer(*chat_id)));
+        self.rows("find_in_network", &select, &values)
+            .iter()
+            .map(|row| row_to_media(row))
+            .find(|media| now - media.timestamp <= self.get_config(media.chat_id).window)
+    }
+
+    fn get_strikes(&self, chat_id: i64, user_id: i64) -> Option<Strikes> {
+        let select = "SELECT chat_id, user_id, count, timestamp FROM strikes WHERE chat_id = ? AND user_id = ?";
+        let values = [Value::Integer(chat_id), Value::Integer(user_id)];
+        self.rows("get_strikes", select, &values)
+            .first()
+            .map(|row| Strikes {
+                chat_id: integer(&row[0]),
+                user_id: integer(&row[1]),
+                count: integer(&row[2]) as u32,
+                timestamp: integer(&row[3]),
+            })
+    }
+
+    fn insert_strikes(&self, strikes: Strikes) -> bool {
+        let insert = "INSERT OR REPLACE INTO strikes (chat_id, user_id, count, timestamp) VALUES (?, ?, ?, ?)";
+        let values = [
+            Value::Integer(strikes.chat_id),
+            Value::Integer(strikes.user_id),
+            Value::Integer(strikes.count.into()),
+            Value::Integer(strikes.timestamp),
+        ];
+        self.execute("insert_strikes", insert, &values)
+    }
+
+    fn get_offender(&self, chat_id: i64, user_id: i64) -> Option<Offender> {
+        let select = format!("SELECT {} FROM offenders WHERE chat_id = ? AND user_id = ?", OFFENDER_COLUMNS);
+        let values = [Value::Integer(chat_id), Value::Integer(user_id)];
+        self.rows("get_offender", &select, &values)
+            .first()
+            .map(|row| row_to_offender(row))
+    }
+
+    fn insert_offender(&self, offender: Offender) -> bool {
+        let insert = format!("INSERT OR REPLACE INTO offenders ({}) VALUES (?, ?, ?, ?, ?, ?, ?)", OFFENDER_COLUMNS);
+        let values = [
+            Value::Integer(offender.chat_id),
+            Value::Integer(offender.user_id),
+            Value::String(offender.user_name),
+            Value::Integer(offender.count.into()),
+            Value::Integer(offender.last_chat_id),
+            Value::Integer(offender.last_msg_id.into()),
+            Value::Integer(offender.timestamp),
+        ];
+        self.execute("insert_offender", &insert, &values)
+    }
+
+    fn top_offenders(&self, chat_id: i64, limit: usize) -> Vec<Offender> {
+        let select = format!(
+            "SELECT {} FROM offenders WHERE chat_id = ? ORDER BY count DESC, timestamp DESC LIMIT ?",
+            OFFENDER_COLUMNS
         );
-        let insert = "INSERT INTO duplicates (chat_id, unique_id, file_type, file_id, msg_id) VALUES (?, ?, ?, ?, ?)";
-        let mut insert_stmt = ok!(self.connection.prepare(insert));
-        ok!(insert_stmt.bind(1, sdo.chat.id));
-        ok!(insert_stmt.bind(2, sdo.unique_id.as_str()));
-        ok!(insert_stmt.bind(3, sdo.file_type.as_str()));
-        ok!(insert_stmt.bind(4, sdo.file_id.unwrap_or(String::from("")).as_str()));
-        ok!(insert_stmt.bind(5, f64::from(sdo.msg_id)));
-
-        let mut cursor = insert_stmt.cursor();
-        match cursor.next() {
-            Ok(_) => true,
+        let values = [Value::Integer(chat_id), Value::Integer(sql_limit(limit))];
+        self.rows("top_offenders", &select, &values)
+            .iter()
+            .map(|row| row_to_offender(row))
+            .collect()
+    }
+
+    fn insert_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let insert =
+            "INSERT OR REPLACE INTO allowlist (chat_id, user_id, timestamp) VALUES (?, ?, ?)";
+        let values = [
+            Value::Integer(chat_id),
+            Value::Integer(user_id),
+            Value::Integer(Utc::now().timestamp()),
+        ];
+        self.execute("insert_allowed", insert, &values)
+    }
+
+    fn delete_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let delete = "DELETE FROM allowlist WHERE chat_id = ? AND user_id = ?";
+        let values = [Value::Integer(chat_id), Value::Integer(user_id)];
+        self.execute("delete_allowed", delete, &values)
+    }
+
+    fn is_allowed(&self, chat_id: i64, user_id: i64) -> bool {
+        let select = "SELECT user_id FROM allowlist WHERE chat_id = ? AND user_id = ?";
+        let values = [Value::Integer(chat_id), Value::Integer(user_id)];
+        !self.rows("is_allowed", select, &values).is_empty()
+    }
+
+    fn list_allowed(&self, chat_id: i64) -> Vec<i64> {
+        let select = "SELECT user_id FROM allowlist WHERE chat_id = ? ORDER BY user_id";
+        self.rows("list_allowed", select, &[Value::Integer(chat_id)])
+            .iter()
+            .map(|row| integer(&row[0]))
+            .collect()
+    }
+
+    fn insert_ban_plan(&self, plan: BanPlan) -> bool {
+        let oldest = Utc::now().timestamp() - BAN_PLAN_TTL_SECS;
+        let delete = "DELETE FROM ban_plans WHERE timestamp < ?";
+        self.execute("insert_ban_plan", delete, &[Value::Integer(oldest)]);
+        match serde_json::to_string(&plan) {
             Err(e) => {
-                log::error!("insert_duplicate: {}", e);
+                log::error!("insert_ban_plan: {}", e);
                 false
             }
+            Ok(v) => {
+                let insert = "INSERT OR REPLACE INTO ban_plans (token, plan, timestamp) VALUES (?, ?, ?)";
+                let values = [
+                    Value::String(plan.token),
+                    Value::String(v),
+                    Value::Integer(plan.timestamp),
+                ];
+                self.execute("insert_ban_plan", insert, &values)
+            }
         }
     }
 
-    fn delete_item(&self, deleted_messages: UpdateDeleteMessages) -> () {
-        let chat_id = deleted_messages.chat_id();
-        let delete_media = "DELETE FROM media WHERE unique_id = (SELECT unique_id FROM mappings WHERE api_id = ? and chat_id = ?)";
-        let delete_urls = "DELETE FROM urls WHERE unique_id = (SELECT unique_id FROM mappings WHERE api_id = ? and chat_id = ?)";
+    fn get_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        let select = "SELECT plan FROM ban_plans WHERE token = ?";
+        let rows = self.rows("get_ban_plan", select, &[Value::String(token.into())]);
+        let row = rows.first()?;
+        match serde_json::from_str::<BanPlan>(&string(&row[0])) {
+            Ok(plan) => Some(plan),
+            Err(e) => {
+                log::error!("get_ban_plan: {}", e);
+                None
+            }
+        }
+    }
 
-        for msg_id in deleted_messages.message_ids() {
-            let mut delete_media_stmt = ok!(self.connection.prepare(delete_media));
-            ok!(delete_media_stmt.bind(1, *msg_id));
-            ok!(delete_media_stmt.bind(2, chat_id));
-            let mut delete_media_cursor = delete_media_stmt.cursor();
-            match delete_media_cursor.next() {
-                Err(e) => log::error!("{}", e),
-                Ok(r) => match r {
-                    None => (),
-                    Some(_) => log::info!("Delete media message {} on chat {}", msg_id, chat_id),
-                },
+    fn take_ban_plan(&self, token: &str) -> Option<BanPlan> {
+        let plan = self.get_ban_plan(token)?;
+        // the delete is atomic, only the connection that removed the row claims the plan
+        let connection = self.connection();
+        let deleted = connection
+            .prepare("DELETE FROM ban_plans WHERE token = ?")
+            .and_then(|stmt| {
+                let mut cursor = stmt.cursor();
+                cursor.bind(&[Value::String(token.into())])?;
+                cursor.next().map(|_| ())
+            })
+            .and_then(|_| connection.prepare("SELECT changes()"))
+            .and_then(|stmt| {
+                let mut cursor = stmt.cursor();
+                let changes = cursor.next()?.map_or(0, |row| integer(&row[0]));
+                Ok(changes)
+            });
+        match deleted {
+            Ok(1) => Some(plan),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("take_ban_plan: {}", e);
+                None
             }
+        }
+    }
+
+    fn insert_ban(&self, ban: Ban) -> bool {
+        let insert = format!("INSERT INTO bans ({}) VALUES (?, ?, ?, ?, ?, ?, ?)", BAN_COLUMNS);
+        let error = match ban.error {
+            Some(e) => Value::String(e),
+            None => Value::Null,
+        };
+        let values = [
+            Value::Integer(ban.user_id),
+            Value::Integer(ban.chat_id),
+            Value::String(ban.user_name),
+            Value::String(ban.reason),
+            Value::Integer(ban.admin_id),
+            error,
+            Value::Integer(ban.timestamp),
+        ];
+        self.execute("insert_ban", &insert, &values)
+    }
 
-            let mut delete_urls_stmt = ok!(self.connection.prepare(delete_urls));
-            ok!(delete_urls_stmt.bind(1, *msg_id));
-            ok!(delete_urls_stmt.bind(2, chat_id));
-            let mut delete_urls_cursor = delete_urls_stmt.cursor();
-            match delete_urls_cursor.next() {
-                Err(e) => log::error!("{}", e),
-                Ok(r) => match r {
-                    None => (),
-                    Some(_) => log::info!("Delete url message {} on chat {}", msg_id, chat_id),
-                },
+    fn list_bans(&self, chat_id: i64, limit: usize) -> Vec<Ban> {
+        let select = format!(
+            "SELECT {} FROM bans WHERE chat_id = ? ORDER BY timestamp DESC LIMIT ?",
+            BAN_COLUMNS
+        );
+        let values = [Value::Integer(chat_id), Value::Integer(sql_limit(limit))];
+        self.rows("list_bans", &select, &values)
+            .iter()
+            .map(|row| row_to_ban(row))
+            .collect()
+    }
+
+    fn insert_audit(&self, entry: AuditEntry) -> bool {
+        match serde_json::to_string(&entry) {
+            Err(e) => {
+                log::error!("insert_audit: {}", e);
+                false
             }
+            Ok(v) => {
+                let insert = "INSERT INTO audit_log (chat_id, user_id, entry, timestamp) VALUES (?, ?, ?, ?)";
+                let values = [
+                    Value::Integer(entry.chat_id),
+                    Value::Integer(entry.user_id),
+                    Value::String(v),
+                    Value::Integer(entry.timestamp),
+                ];
+                self.execute("insert_audit", insert, &values)
+            }
+        }
+    }
+
+    fn list_audit(&self, chat_id: i64, user_id: Option<i64>, limit: usize) -> Vec<AuditEntry> {
+        let mut select = String::from("SELECT entry FROM audit_log WHERE chat_id = ?");
+        let mut values = vec![Value::Integer(chat_id)];
+        if let Some(id) = user_id {
+            select.push_str(" AND user_id = ?");
+            values.push(Value::Integer(id));
         }
+        select.push_str(" ORDER BY timestamp DESC, rowid DESC LIMIT ?");
+        values.push(Value::Integer(sql_limit(limit)));
+        self.rows("list_audit", &select, &values)
+            .iter()
+            .filter_map(|row| match serde_json::from_str::<AuditEntry>(&string(&row[0])) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::error!("list_audit: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn insert_deletion(&self, deletion: Deletion) -> bool {
+        let insert = "INSERT OR REPLACE INTO deletions (chat_id, msg_id, due) VALUES (?, ?, ?)";
+        let values = [
+            Value::Integer(deletion.chat_id),
+            Value::Integer(deletion.msg_id as i64),
+            Value::Integer(deletion.due),
+        ];
+        self.execute("insert_deletion", insert, &values)
     }
 
-    fn insert_mapping(&self, id: i64, chat_id: i64, unique_id: &str) -> bool {
-        let mut insert_stmt = ok!(self.connection.prepare(INSERT));
+    fn delete_deletion(&self, chat_id: i64, msg_id: i32) -> bool {
+        let delete = "DELETE FROM deletions WHERE chat_id = ? AND msg_id = ?";
+        let values = [Value::Integer(chat_id), Value::Integer(msg_id as i64)];
+        self.execute("delete_deletion", delete, &values)
+    }
 
-        ok!(insert_stmt.bind(1, id));
-        ok!(insert_stmt.bind(2, chat_id));
-        ok!(insert_stmt.bind(3, unique_id));
+    fn list_deletions(&self) -> Vec<Deletion> {
+        let select = "SELECT chat_id, msg_id, due FROM deletions ORDER BY due";
+        self.rows("list_deletions", select, &[])
+            .iter()
+            .map(|row| Deletion {
+                chat_id: integer(&row[0]),
+                msg_id: integer(&row[1]) as i32,
+                due: integer(&row[2]),
+            })
+            .collect()
+    }
 
-        let mut cursor = insert_stmt.cursor();
-        match cursor.next() {
+    fn insert_album(&self, album: Album) -> bool {
+        let oldest = Utc::now().timestamp() - self.get_config(album.chat_id).window;
+        let delete = "DELETE FROM albums WHERE chat_id = ? AND timestamp < ?";
+        self.execute("insert_album", delete, &[Value::Integer(album.chat_id), Value::Integer(oldest)]);
+        match serde_json::to_string(&album) {
             Err(e) => {
-                log::error!("{}", e);
+                log::error!("insert_album: {}", e);
                 false
             }
-            Ok(r) => match r {
-                None => false,
-                Some(e) => {
-                    log::error!("{:?}", e);
-                    true
+            Ok(v) => {
+                let insert = "INSERT OR REPLACE INTO albums (chat_id, media_group_id, album, timestamp) VALUES (?, ?, ?, ?)";
+                let values = [
+                    Value::Integer(album.chat_id),
+                    Value::String(album.media_group_id),
+                    Value::String(v),
+                    Value::Integer(album.timestamp),
+                ];
+                self.execute("insert_album", insert, &values)
+            }
+        }
+    }
+
+    fn get_album(&self, chat_id: i64, media_group_id: &str) -> Option<Album> {
+        let select = "SELECT album FROM albums WHERE chat_id = ? AND media_group_id = ?";
+        let values = [Value::Integer(chat_id), Value::String(media_group_id.into())];
+        let rows = self.rows("get_album", select, &values);
+        let row = rows.first()?;
+        match serde_json::from_str::<Album>(&string(&row[0])) {
+            Ok(album) => Some(album),
+            Err(e) => {
+                log::error!("get_album: {}", e);
+                None
+            }
+        }
+    }
+
+    fn find_album(&self, chat_id: i64, msg_id: i32) -> Option<Album> {
+        let select = "SELECT album FROM albums WHERE chat_id = ?";
+        self.rows("find_album", select, &[Value::Integer(chat_id)])
+            .iter()
+            .filter_map(|row| match serde_json::from_str::<Album>(&string(&row[0])) {
+                Ok(album) => Some(album),
+                Err(e) => {
+                    log::error!("find_album: {}", e);
+                    None
                 }
-            },
+            })
+            .find(|album| album.msg_ids.contains(&msg_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SQLiteRepo;
+    use crate::repository::{conformance, Repository};
+
+    #[test]
+    fn test_conformance() {
+        let path = std::env::temp_dir().join("highlander_conformance.db");
+        let _ = std::fs::remove_file(&path);
+        {
+            let repo = SQLiteRepo::open(path.to_str().unwrap());
+            conformance::run(&repo);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_migrate_shared_allowlist() {
+        let path = std::env::temp_dir().join("highlander_allowlist.db");
+        let _ = std::fs::remove_file(&path);
+        {
+            let connection = sqlite::open(path.to_str().unwrap()).unwrap();
+            connection
+                .execute(
+                    "create table users(user_id sqlite3_int64, chat_id sqlite3_int64, \
+                     user_name varchar(250), chat_name varchar(250), timestamp sqlite3_int64 not null); \
+                     create table allowlist(user_id sqlite3_int64, timestamp sqlite3_int64 not null); \
+                     insert into users values (42, -4242, 'User', 'Group', 0); \
+                     insert into users values (42, -1001592783264, 'User', 'Group', 0); \
+                     insert into allowlist values (1072037897, 0);",
+                )
+                .unwrap();
+        }
+        {
+            let repo = SQLiteRepo::open(path.to_str().unwrap());
+            for chat_id in [-4242, -1001592783264].iter() {
+                assert_eq!(repo.list_allowed(*chat_id), vec![1072037897]);
+            }
+            assert!(repo.list_allowed(-1001192585346).is_empty());
+            assert!(repo.delete_allowed(-4242, 1072037897));
         }
+        let repo = SQLiteRepo::open(path.to_str().unwrap());
+        assert!(!repo.is_allowed(-4242, 1072037897));
+        drop(repo);
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -0,0 +1,132 @@
+use rtdlib::types::{TextEntity, TextEntityType};
+use teloxide::types::{Message, MessageEntity, MessageEntityKind};
+
+use crate::canonical::canonicalize;
+use crate::duplicates::extract_last250;
+
+/// Unique id an url is stored under, the same for the bot and the TDLib listener
+pub fn url_id(url: &str) -> String {
+    extract_last250(&canonicalize(url)).into()
+}
+
+/// Telegram links bare domains too, they are read as http
+fn with_scheme(url: String) -> String {
+    if url.contains("://") {
+        url
+    } else {
+        format!("http://{}", url)
+    }
+}
+
+/// Entity offsets and lengths count UTF-16 code units
+fn span(text: &str, offset: usize, length: usize) -> Option<String> {
+    let units = text.encode_utf16().collect::<Vec<_>>();
+    let slice = units.get(offset..offset + length)?;
+    String::from_utf16(slice).ok()
+}
+
+/// Urls of Bot API entities, written out or behind a hyperlink
+pub fn entity_urls(text: &str, entities: &[MessageEntity]) -> Vec<String> {
+    entities
+        .iter()
+        .filter_map(|entity| match &entity.kind {
+            MessageEntityKind::Url => span(text, entity.offset, entity.length).map(with_scheme),
+            MessageEntityKind::TextLink { url } => Some(url.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Urls of TDLib entities, written out or behind a hyperlink
+pub fn td_entity_urls(text: &str, entities: &[TextEntity]) -> Vec<String> {
+    entities
+        .iter()
+        .filter_map(|entity| match entity.type_() {
+            TextEntityType::Url(_) => {
+                span(text, entity.offset() as usize, entity.length() as usize).map(with_scheme)
+            }
+            TextEntityType::TextUrl(text_url) => Some(text_url.url().clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Urls of the text or caption of a message
+pub fn message_urls(message: &Message) -> Vec<String> {
+    let text = message.text().or_else(|| message.caption());
+    let entities = message.entities().or_else(|| message.caption_entities());
+    match (text, entities) {
+        (Some(text), Some(entities)) => entity_urls(text, entities),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entity_urls, message_urls, td_entity_urls, url_id};
+    use rtdlib::types::TextEntity;
+    use serde_json::json;
+    use teloxide::types::{Message, MessageEntity};
+
+    fn entities(value: serde_json::Value) -> Vec<MessageEntity> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn urls_and_hidden_links() {
+        let text = "mira twitter.com/plaforscience y esto";
+        let found = entity_urls(
+            text,
+            &entities(json!([
+                { "type": "url", "offset": 5, "length": 25 },
+                { "type": "bold", "offset": 0, "length": 4 },
+                { "type": "text_link", "offset": 33, "length": 4, "url": "https://highlander.rs/docs" }
+            ])),
+        );
+        assert_eq!(found, vec!["http://twitter.com/plaforscience", "https://highlander.rs/docs"]);
+    }
+
+    #[test]
+    fn offsets_count_utf16_units() {
+        // the emoji takes two units, the accented letter one
+        let text = "🎉 canción https://highlander.rs ok";
+        let found = entity_urls(text, &entities(json!([{ "type": "url", "offset": 11, "length": 21 }])));
+        assert_eq!(found, vec!["https://highlander.rs"]);
+        assert!(entity_urls(text, &entities(json!([{ "type": "url", "offset": 30, "length": 21 }]))).is_empty());
+    }
+
+    #[test]
+    fn tdlib_entities_match_bot_api() {
+        let text = "🎉 https://www.youtube.com/watch?v=GCI0NMgVfPk&feature=share";
+        let bot = entity_urls(text, &entities(json!([{ "type": "url", "offset": 3, "length": 57 }])));
+        let td: Vec<TextEntity> = serde_json::from_value(json!([
+            { "@type": "textEntity", "offset": 3, "length": 57, "type": { "@type": "textEntityTypeUrl" } },
+            {
+                "@type": "textEntity",
+                "offset": 0,
+                "length": 2,
+                "type": { "@type": "textEntityTypeTextUrl", "url": "https://highlander.rs" }
+            }
+        ]))
+        .unwrap();
+        let td = td_entity_urls(text, &td);
+        assert_eq!(td.len(), 2);
+        assert_eq!(url_id(&td[0]), url_id(&bot[0]));
+        assert_eq!(td[1], "https://highlander.rs");
+    }
+
+    #[test]
+    fn captions() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 10,
+            "date": 1633072800,
+            "chat": { "id": -1001592783264i64, "type": "supergroup", "title": "Highlander" },
+            "from": { "id": 1072037897, "is_bot": false, "first_name": "Connor" },
+            "photo": [{ "file_id": "file_a", "file_unique_id": "a", "width": 90, "height": 90 }],
+            "caption": "fuente",
+            "caption_entities": [{ "type": "text_link", "offset": 0, "length": 6, "url": "https://highlander.rs" }]
+        }))
+        .unwrap();
+        assert_eq!(message_urls(&message), vec!["https://highlander.rs"]);
+    }
+}